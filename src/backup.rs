@@ -0,0 +1,132 @@
+//! Backups of the database stored in the drive, to restore it on another machine
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::Utc;
+use crate::db::DB;
+use crate::drives::Drive;
+use crate::error::{Error, Result};
+use crate::types::{BackupChunkMeta, BackupMeta, Chunk};
+
+/// Number of backups kept in the drive, the older ones are deleted
+const BACKUPS_KEPT: usize = 3;
+
+/// Temporary file holding the copy of the database
+fn backup_path() -> PathBuf {
+    std::env::temp_dir().join(format!("multi-drive-backup-{}.db", std::process::id()))
+}
+
+/// Backups of the drive, newest first
+async fn backups(drive: &dyn Drive) -> Result<Vec<(String, BackupMeta)>> {
+    let mut backups: Vec<(String, BackupMeta)> = drive.pinned().await?
+        .into_iter()
+        .filter_map(|o| Some((o.locator, serde_json::from_str(&o.meta).ok()?)))
+        .collect();
+    backups.sort_by_key(|(_, meta)| std::cmp::Reverse(meta.backup));
+    Ok(backups)
+}
+
+/// Send a copy of the database to the drive, in chunks referenced by a pinned object
+pub async fn backup(db: &DB, drive: &dyn Drive) -> Result<()> {
+    let path = backup_path();
+    if tokio::fs::try_exists(&path).await? {
+        tokio::fs::remove_file(&path).await?;
+    }
+    db.backup(path.clone()).await?;
+    let content = tokio::fs::read(&path).await?;
+    tokio::fs::remove_file(&path).await?;
+
+    let created = Utc::now();
+    let mut chunks = Vec::new();
+    for (index, blob) in content.chunks(drive.max_blob_size()).enumerate() {
+        let meta = serde_json::to_string(&BackupChunkMeta { backup: created, index })?;
+        let locator = drive.put(&format!("backup.{}", index), &meta, Some(blob.to_vec())).await?;
+        chunks.push(Chunk { locator, size: blob.len() as u64, compressed: false, hash: None });
+    }
+    let meta = serde_json::to_string(&BackupMeta { backup: created, chunks })?;
+    let locator = drive.put("backup", &meta, None).await?;
+    drive.pin(&locator).await?;
+
+    // The number of pinned objects is limited
+    let mut old = Vec::new();
+    for (locator, meta) in backups(drive).await?.into_iter().skip(BACKUPS_KEPT) {
+        old.extend(meta.chunks.into_iter().map(|c| c.locator));
+        old.push(locator);
+    }
+    drive.delete_many(&old).await
+}
+
+/// Replace the database by the newest backup of the drive, return when it was made
+pub async fn restore(db: &DB, drive: &dyn Drive) -> Result<chrono::DateTime<Utc>> {
+    let (_, meta) = backups(drive).await?.into_iter().next().ok_or(Error::NotFound)?;
+    let mut content = Vec::new();
+    for chunk in &meta.chunks {
+        let (_, blob) = drive.get(&chunk.locator).await?;
+        content.extend(blob.ok_or(Error::BlobNotFound)?);
+    }
+    let path = backup_path();
+    tokio::fs::write(&path, content).await?;
+    db.restore(path.clone()).await?;
+    tokio::fs::remove_file(&path).await?;
+    Ok(meta.backup)
+}
+
+/// Back up the database every `interval` if it changed
+pub fn spawn(db: Arc<DB>, drive: Arc<dyn Drive>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut last_changes = None;
+        loop {
+            tokio::time::sleep(interval).await;
+            let changes = match db.total_changes().await {
+                Ok(changes) => changes,
+                Err(e) => {
+                    eprintln!("Failed to back up the database: {}", e);
+                    continue
+                }
+            };
+            if last_changes == Some(changes) {
+                continue
+            }
+            match backup(&db, drive.as_ref()).await {
+                Ok(()) => last_changes = Some(changes),
+                Err(e) => eprintln!("Failed to back up the database: {}", e)
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drives::discord::{mock, DiscordClient};
+    use crate::types::Metadata;
+
+    // A single test, the backups of a process share their temporary file
+    #[tokio::test(flavor = "multi_thread")]
+    async fn backup_and_restore() {
+        let url = mock::start().await.unwrap();
+        let drive = DiscordClient::new("token".to_string(), "channel".to_string(), url);
+        let db = DB::new(None).await;
+        assert!(matches!(restore(&db, &drive).await, Err(Error::NotFound)));
+
+        db.insert_dir_entry(None, "/".to_string(), Metadata { len: 0, modified: None, is_dir: true }).await.unwrap();
+        let root = db.get_dir_entry_by_path("/".to_string()).await.unwrap().unwrap().id;
+        for i in 0..=BACKUPS_KEPT {
+            db.insert_dir_entry(Some(root), format!("/{}", i), Metadata { len: 0, modified: None, is_dir: true }).await.unwrap();
+            backup(&db, &drive).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // The oldest backup and its chunks were deleted
+        let kept = backups(&drive).await.unwrap();
+        assert_eq!(kept.len(), BACKUPS_KEPT);
+        let (objects, _) = drive.list(None).await.unwrap();
+        assert_eq!(objects.len(), kept.iter().map(|(_, meta)| 1 + meta.chunks.len()).sum::<usize>());
+
+        let restored = DB::new(None).await;
+        assert_eq!(restore(&restored, &drive).await.unwrap(), kept[0].1.backup);
+        for i in 0..=BACKUPS_KEPT {
+            assert!(restored.get_dir_entry_by_path(format!("/{}", i)).await.unwrap().is_some());
+        }
+    }
+}
@@ -0,0 +1,224 @@
+//! Local copies of the files and chunks of the drive
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use chrono::Utc;
+use tokio::sync::Mutex;
+use crate::db::DB;
+use crate::error::Result;
+
+/// Number of entries read at once when looking for files to evict
+const EVICT_BATCH: usize = 100;
+
+/// Directory holding the cached files, bounded to `max_size` bytes.
+///
+/// Every file is tracked in the `cache_entries` table. When the cache is full the least recently used files are evicted,
+/// except the dirty ones, which are not sent to the drive yet, and the ones currently opened, which are pinned.
+#[derive(Debug)]
+pub struct Cache {
+    dir: PathBuf,
+    max_size: u64,
+    db: Arc<DB>,
+    /// Number of handles using each file
+    pinned: std::sync::Mutex<HashMap<String, usize>>,
+    /// Held while evicting
+    evicting: Mutex<()>
+}
+impl Cache {
+    pub fn new(dir: PathBuf, max_size: u64, db: Arc<DB>) -> Self {
+        Self {
+            dir,
+            max_size,
+            db,
+            pinned: std::sync::Mutex::new(HashMap::new()),
+            evicting: Mutex::new(())
+        }
+    }
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+    /// Record an access to a file after it was downloaded, opened or sent, and evict others if the cache is full
+    pub async fn touch(&self, name: &str, entry_id: usize) -> Result<()> {
+        let size = tokio::fs::metadata(self.path(name)).await?.len();
+        self.db.touch_cache_entry(name.to_string(), entry_id, size, Utc::now().timestamp_millis()).await?;
+        self.evict().await
+    }
+    /// Mark a file as written but not sent, it is not evicted until it is marked clean
+    pub async fn set_dirty(&self, name: &str, dirty: bool) -> Result<()> {
+        self.db.set_cache_entry_dirty(name.to_string(), dirty).await
+    }
+    /// Prevent a file from being evicted while it is used
+    pub fn pin(&self, name: &str) {
+        *self.pinned.lock().unwrap().entry(name.to_string()).or_default() += 1;
+    }
+    pub fn unpin(&self, name: &str) {
+        let mut pinned = self.pinned.lock().unwrap();
+        if let Some(count) = pinned.get_mut(name) {
+            *count -= 1;
+            if *count == 0 {
+                pinned.remove(name);
+            }
+        }
+    }
+    fn is_pinned(&self, name: &str) -> bool {
+        self.pinned.lock().unwrap().contains_key(name)
+    }
+    /// Remove a file from the cache
+    pub async fn remove(&self, name: &str) -> Result<()> {
+        let path = self.path(name);
+        if tokio::fs::try_exists(&path).await? {
+            tokio::fs::remove_file(&path).await?;
+        }
+        self.db.remove_cache_entry(name.to_string()).await
+    }
+    /// Remove the least recently used clean files until the cache fits in `max_size`
+    pub async fn evict(&self) -> Result<()> {
+        // Another eviction is running
+        let Ok(_evicting) = self.evicting.try_lock() else {
+            return Ok(())
+        };
+        let mut size = self.db.get_cache_size().await?;
+        if size <= self.max_size {
+            return Ok(())
+        }
+        let mut skipped = 0;
+        loop {
+            let entries = self.db.get_evictable_cache_entries(skipped + EVICT_BATCH).await?;
+            let candidates = entries.len();
+            for entry in entries.into_iter().skip(skipped) {
+                if size <= self.max_size {
+                    return Ok(())
+                }
+                if self.is_pinned(&entry.name) {
+                    skipped += 1;
+                    continue
+                }
+                self.remove(&entry.name).await?;
+                size = size.saturating_sub(entry.size);
+            }
+            // Only dirty or pinned files are left
+            if size <= self.max_size || candidates <= skipped {
+                return Ok(())
+            }
+        }
+    }
+    /// Remove all the files, after the ids of the entries changed
+    pub async fn clear(&self) -> Result<()> {
+        let mut dir = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(file) = dir.next_entry().await? {
+            tokio::fs::remove_file(file.path()).await?;
+        }
+        for entry in self.db.get_cache_entries().await? {
+            self.db.remove_cache_entry(entry.name).await?;
+        }
+        Ok(())
+    }
+    /// Synchronize the table with the directory, after the cache was used by an older version or modified by hand
+    pub async fn scan(&self) -> Result<()> {
+        let mut tracked: HashMap<String, bool> = self.db.get_cache_entries().await?
+            .into_iter()
+            .map(|e| (e.name, e.dirty))
+            .collect();
+        let mut dir = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(file) = dir.next_entry().await? {
+            let name = file.file_name().to_string_lossy().into_owned();
+            if tracked.remove(&name).is_some() {
+                continue
+            }
+            // Interrupted download
+            if name.ends_with(".part") {
+                tokio::fs::remove_file(file.path()).await?;
+                continue
+            }
+            let entry_id = name.split('.').next().and_then(|id| id.parse().ok());
+            match entry_id {
+                Some(entry_id) => {
+                    let size = file.metadata().await?.len();
+                    // Never accessed since tracked, evicted first
+                    self.db.touch_cache_entry(name, entry_id, size, 0).await?;
+                },
+                None => eprintln!("Unknown file in the cache: {}", name)
+            }
+        }
+        // The files were removed
+        for name in tracked.into_keys() {
+            self.db.remove_cache_entry(name).await?;
+        }
+        self.evict().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A cache of `max_size` bytes in a new directory
+    async fn cache(name: &str, max_size: u64) -> Cache {
+        let dir = std::env::temp_dir().join(format!("multi-drive-cache-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        Cache::new(dir, max_size, Arc::new(DB::new(None).await))
+    }
+
+    /// Write a file of 100 bytes in the cache and record the access
+    async fn add(cache: &Cache, name: &str) {
+        std::fs::write(cache.path(name), [0; 100]).unwrap();
+        cache.touch(name, 1).await.unwrap();
+        // Distinct access times
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+    }
+
+    fn exists(cache: &Cache, name: &str) -> bool {
+        cache.path(name).exists()
+    }
+
+    #[tokio::test]
+    async fn evict_least_recently_used() {
+        let cache = cache("lru", 250).await;
+        add(&cache, "1").await;
+        add(&cache, "2").await;
+        add(&cache, "1.0").await;
+        assert!(!exists(&cache, "1") && exists(&cache, "2") && exists(&cache, "1.0"));
+        // Accessed again, so more recent than 1.0
+        cache.touch("2", 1).await.unwrap();
+        add(&cache, "3").await;
+        assert!(exists(&cache, "2") && !exists(&cache, "1.0") && exists(&cache, "3"));
+        assert_eq!(cache.db.get_cache_size().await.unwrap(), 200);
+    }
+
+    #[tokio::test]
+    async fn keep_dirty_and_pinned() {
+        let cache = cache("kept", 250).await;
+        add(&cache, "1").await;
+        add(&cache, "2").await;
+        cache.set_dirty("1", true).await.unwrap();
+        cache.pin("2");
+        add(&cache, "3").await;
+        // Over the size rather than losing a file not sent yet or in use
+        assert!(exists(&cache, "1") && exists(&cache, "2"));
+        assert!(!exists(&cache, "3"));
+
+        cache.unpin("2");
+        add(&cache, "3").await;
+        assert!(exists(&cache, "1") && !exists(&cache, "2") && exists(&cache, "3"));
+        cache.set_dirty("1", false).await.unwrap();
+        add(&cache, "4").await;
+        assert!(!exists(&cache, "1") && exists(&cache, "3") && exists(&cache, "4"));
+    }
+
+    #[tokio::test]
+    async fn scan() {
+        let cache = cache("scan", 1000).await;
+        add(&cache, "1").await;
+        add(&cache, "2").await;
+        std::fs::remove_file(cache.path("1")).unwrap();
+        std::fs::write(cache.path("3.0"), [0; 10]).unwrap();
+        std::fs::write(cache.path("4.part"), [0; 10]).unwrap();
+        cache.scan().await.unwrap();
+        let mut names: Vec<String> = cache.db.get_cache_entries().await.unwrap().into_iter().map(|e| e.name).collect();
+        names.sort();
+        assert_eq!(names, vec!["2", "3.0"]);
+        assert!(!exists(&cache, "4.part"));
+        assert_eq!(cache.db.get_cache_size().await.unwrap(), 110);
+    }
+}
@@ -0,0 +1,107 @@
+//! Compression of the content of the files before it is sent to the drive
+
+use std::path::Path;
+use crate::error::Result;
+
+/// Extensions of the formats that are already compressed
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "aac", "apk", "avi", "avif", "br", "bz2", "docx", "epub", "flac", "gif", "gz", "heic", "jar", "jpeg", "jpg",
+    "lz", "lz4", "lzma", "m4a", "mkv", "mov", "mp3", "mp4", "odt", "ogg", "opus", "pdf", "png", "rar", "tgz", "webm",
+    "webp", "xlsx", "xz", "zip", "zst"
+];
+/// Number of bytes from the start of the content used to estimate its entropy
+const SAMPLE_SIZE: usize = 64 * 1024;
+/// Entropy in bits per byte above which the content is considered incompressible
+const MAX_ENTROPY: f64 = 7.5;
+/// Level used when no other is configured
+pub const DEFAULT_LEVEL: i32 = zstd::DEFAULT_COMPRESSION_LEVEL;
+
+/// Whether the file at `path` is worth compressing, judging by its extension and the entropy of the start of its content
+pub fn is_compressible(path: &str, content: &[u8]) -> bool {
+    let extension = Path::new(path).extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    if extension.is_some_and(|e| COMPRESSED_EXTENSIONS.contains(&e.as_str())) {
+        return false
+    }
+    entropy(&content[..content.len().min(SAMPLE_SIZE)]) <= MAX_ENTROPY
+}
+
+/// Shannon entropy of the data, in bits per byte
+fn entropy(data: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    for byte in data {
+        counts[*byte as usize] += 1;
+    }
+    counts.iter()
+        .filter(|c| **c > 0)
+        .map(|c| {
+            let p = *c as f64 / data.len() as f64;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Compress a blob with zstd, return it as is if it doesn't get smaller and whether it was compressed
+pub fn compress(blob: Vec<u8>, level: i32) -> Result<(Vec<u8>, bool)> {
+    let compressed = zstd::bulk::compress(&blob, level)?;
+    match compressed.len() < blob.len() {
+        true => Ok((compressed, true)),
+        false => Ok((blob, false))
+    }
+}
+
+/// Compress a blob with zstd even if it doesn't get smaller, to store it again like its compressed copies
+pub fn recompress(blob: &[u8], level: i32) -> Result<Vec<u8>> {
+    Ok(zstd::bulk::compress(blob, level)?)
+}
+
+/// Decompress a blob of `size` bytes once decompressed
+pub fn decompress(blob: &[u8], size: usize) -> Result<Vec<u8>> {
+    Ok(zstd::bulk::decompress(blob, size)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes that look random
+    fn noise(len: usize) -> Vec<u8> {
+        let mut seed = 1u64;
+        (0..len).map(|_| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn skip_compressed_formats() {
+        let text = b"hello world ".repeat(1000);
+        assert!(is_compressible("/notes.txt", &text));
+        assert!(is_compressible("/no-extension", &text));
+        assert!(!is_compressible("/photo.jpg", &text));
+        assert!(!is_compressible("/archive.ZIP", &text));
+    }
+
+    #[test]
+    fn skip_high_entropy() {
+        assert!(entropy(&[7; 100]) == 0.0);
+        assert!(entropy(&noise(SAMPLE_SIZE)) > MAX_ENTROPY);
+        assert!(!is_compressible("/data.bin", &noise(100_000)));
+        // Only the start is sampled
+        let mut content = b"a".repeat(SAMPLE_SIZE);
+        content.extend(noise(100_000));
+        assert!(is_compressible("/data.bin", &content));
+    }
+
+    #[test]
+    fn roundtrip() {
+        let text = b"hello world ".repeat(1000);
+        let (compressed, is_compressed) = compress(text.clone(), DEFAULT_LEVEL).unwrap();
+        assert!(is_compressed && compressed.len() < text.len());
+        assert_eq!(decompress(&compressed, text.len()).unwrap(), text);
+        // Kept as is when it doesn't get smaller
+        let random = noise(10_000);
+        assert_eq!(compress(random.clone(), DEFAULT_LEVEL).unwrap(), (random, false));
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::env;
+use crate::error::Result;
+
+/// Options that are set by their name alone, they never take the next argument as their value
+const FLAGS: &[&str] = &["DISCORD_MOCK", "DRY_RUN", "FORCE", "MIRROR_DIRS", "OBFUSCATE", "REPAIR", "VERSIONING"];
+
+/// Settings of the server.
+///
+/// Options are read from the command line (`--discord-api-url <value>`, `--discord-api-url=<value>`, `--flag` or `--flag=<value>`)
+/// and fall back to the environment, which is loaded from `config.env` (`DISCORD_API_URL=<value>`).
+#[derive(Debug, Default)]
+pub struct Config {
+    /// Command line arguments that are not options
+    pub args: Vec<String>,
+    options: HashMap<String, String>
+}
+impl Config {
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Self {
+        let mut config = Self::default();
+        let mut args = args.into_iter().peekable();
+        while let Some(arg) = args.next() {
+            match arg.strip_prefix("--") {
+                Some(option) => {
+                    let (name, value) = match option.split_once('=') {
+                        Some((name, value)) => (name.replace('-', "_").to_uppercase(), Some(value.to_string())),
+                        None => (option.replace('-', "_").to_uppercase(), None)
+                    };
+                    let value = match value {
+                        Some(value) => value,
+                        None if FLAGS.contains(&name.as_str()) => "true".to_string(),
+                        None => args.next_if(|a| !a.starts_with("--")).unwrap_or_else(|| "true".to_string())
+                    };
+                    config.options.insert(name, value);
+                },
+                None => config.args.push(arg)
+            }
+        }
+        config
+    }
+    /// Read the command line of the process, skipping the name of the executable
+    pub fn from_env() -> Self {
+        Self::from_args(env::args().skip(1))
+    }
+    /// Value of an option, `name` is the name of its environment variable
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.options.get(name).cloned().or_else(|| env::var(name).ok())
+    }
+    /// Value of a required option
+    pub fn var(&self, name: &str) -> Result<String> {
+        match self.options.get(name) {
+            Some(value) => Ok(value.clone()),
+            None => Ok(env::var(name)?)
+        }
+    }
+    /// Values of a required option holding a comma-separated list, at least one
+    pub fn list(&self, name: &str) -> Result<Vec<String>> {
+        let values: Vec<String> = self.var(name)?.split(',')
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect();
+        if values.is_empty() {
+            return Err(env::VarError::NotPresent.into())
+        }
+        Ok(values)
+    }
+    pub fn flag(&self, name: &str) -> bool {
+        matches!(self.get(name).as_deref(), Some("true" | "1" | "yes"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Config {
+        Config::from_args(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn flags_do_not_take_the_command() {
+        let config = parse(&["--repair", "scrub"]);
+        assert!(config.flag("REPAIR"));
+        assert_eq!(config.args, vec!["scrub"]);
+        let config = parse(&["--dry-run", "gc", "--discord-mock"]);
+        assert!(config.flag("DRY_RUN") && config.flag("DISCORD_MOCK"));
+        assert_eq!(config.args, vec!["gc"]);
+        let config = parse(&["--force", "rebuild-index"]);
+        assert!(config.flag("FORCE"));
+        assert_eq!(config.args, vec!["rebuild-index"]);
+    }
+
+    #[test]
+    fn options_take_a_value() {
+        let config = parse(&["--gc-grace-period", "60", "gc", "--cache-dir=/tmp/cache", "--repair=false"]);
+        assert_eq!(config.get("GC_GRACE_PERIOD").as_deref(), Some("60"));
+        assert_eq!(config.get("CACHE_DIR").as_deref(), Some("/tmp/cache"));
+        assert!(!config.flag("REPAIR"));
+        assert_eq!(config.args, vec!["gc"]);
+    }
+}
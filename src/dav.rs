@@ -0,0 +1,1348 @@
+mod snapshots;
+mod trash;
+mod versions;
+
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use bytes::Buf;
+use fastcdc::v2020::AsyncStreamCDC;
+use futures::{FutureExt, StreamExt};
+use futures::stream::FuturesUnordered;
+use tokio::sync::Mutex;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::{DavMetaData, DavFileSystem, FsError, FsResult, DavFile, DavDirEntry};
+use chrono::{DateTime, Utc};
+use crate::cache::Cache;
+use crate::compression;
+use crate::config::Config;
+use crate::db::DB;
+use crate::drives::{replicas, Drive, REPLICA_SEPARATOR};
+use crate::error::{Result, Error};
+use crate::types::{File, Metadata, DirEntry, Chunk, BlobMeta, EntryMeta};
+
+/// Maximum number of objects deleted at once by `DriveFs::purge`
+const PURGE_BATCH: usize = 100;
+/// Time to wait before purging, so that the deletions of a whole tree are batched together
+const PURGE_DELAY: Duration = Duration::from_secs(2);
+/// Time between the checks for the entries kept in the trash for longer than `Options::trash`
+const TRASH_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Bounds of the size of the chunks cut in the content, the maximum is also limited by `Drive::max_blob_size`
+const MIN_CHUNK_SIZE: u32 = 256 * 1024;
+const AVG_CHUNK_SIZE: u32 = 1024 * 1024;
+const MAX_CHUNK_SIZE: u32 = fastcdc::v2020::MAXIMUM_MAX;
+/// Name of the objects of the blobs, the same for all so that it reveals nothing about their content
+pub const BLOB_NAME: &str = "chunk";
+
+/// Decode a `DavPath` to the form used in the database
+fn decode_path(path: &DavPath) -> FsResult<String> {
+    let path = path.as_url_string();
+    let path = percent_encoding::percent_decode_str(&path).decode_utf8().map_err(|_|FsError::Forbidden)?;
+    match path.trim_end_matches('/') {
+        "" => Ok("/".to_string()),
+        p => Ok(p.to_string())
+    }
+}
+
+/// Whether a path is in one of the views, `/.versions`, `/.snapshots` or `/.trash`, where nothing can be created
+fn is_view(path: &str) -> bool {
+    versions::strip(path).is_some() || snapshots::strip(path).is_some() || trash::strip(path).is_some()
+}
+
+/// Past content of an entry, read instead of its current one
+#[derive(Debug, Clone, Copy)]
+enum Frozen {
+    /// Id of a version, see `versions`
+    Version(usize),
+    /// Id of a snapshot, see `snapshots`
+    Snapshot(usize),
+    /// Id of a removed entry, see `trash`
+    Trash(usize)
+}
+
+#[derive(Debug)]
+pub struct DriveFile {
+    pub inner: File,
+    pub fs: DriveFs,
+    /// The cache was written since the last upload
+    dirty: bool,
+    /// The content must be discarded when loading instead of being downloaded
+    truncate: bool,
+    /// The cached file is pinned by this handle
+    pinned: bool,
+    /// The file is read-only and shows a past content
+    frozen: Option<Frozen>
+}
+impl DriveFile {
+    pub fn new(inner: File, fs: DriveFs) -> Self {
+        Self {
+            inner,
+            fs,
+            dirty: false,
+            truncate: false,
+            pinned: false,
+            frozen: None
+        }
+    }
+    pub fn drive(&self) -> &Arc<dyn Drive> {
+        &self.fs.drive
+    }
+    pub fn db(&self) -> &Arc<DB> {
+        &self.fs.db
+    }
+    pub fn boxed(self) -> Box<Self> {
+        Box::new(self)
+    }
+    /// Name of the file in the cache
+    pub fn name(&self) -> String {
+        match self.frozen {
+            Some(Frozen::Version(version)) => format!("{}.v{}", self.inner.id(), version),
+            Some(Frozen::Snapshot(snapshot)) => format!("{}.s{}", self.inner.id(), snapshot),
+            Some(Frozen::Trash(trash)) => format!("{}.t{}", self.inner.id(), trash),
+            None => self.inner.id().to_string()
+        }
+    }
+    /// Name of a chunk of the file in the cache
+    pub fn chunk_name(&self, index: usize) -> String {
+        format!("{}.{}", self.name(), index)
+    }
+    /// Path of the file in the cache
+    pub fn path(&self) -> PathBuf {
+        self.fs.cache.path(&self.name())
+    }
+    /// Remove the file and its chunks from the cache
+    pub async fn remove_cached(&self, chunks: usize) -> Result<()> {
+        let names = std::iter::once(self.name()).chain((0..chunks).map(|i| self.chunk_name(i)));
+        for name in names {
+            self.fs.cache.remove(&name).await?;
+        }
+        Ok(())
+    }
+    /// Chunks of the content of the file on the drive, in order
+    pub async fn chunks(&self) -> Result<Vec<Chunk>> {
+        match self.frozen {
+            Some(Frozen::Version(version)) => self.db().get_version_chunks(version).await,
+            Some(Frozen::Snapshot(snapshot)) => self.db().get_snapshot_chunks(snapshot, *self.inner.id()).await,
+            Some(Frozen::Trash(trash)) => self.db().get_trash_chunks(trash).await,
+            None => self.db().get_chunks_by_entry_id(*self.inner.id()).await
+        }
+    }
+    /// Whether the file was already sent to the drive.
+    /// Copies share the chunks of the original from the start, their own object is sent afterwards.
+    pub async fn is_sent(&self) -> Result<bool> {
+        Ok(self.inner.locator().is_some() || !self.chunks().await?.is_empty())
+    }
+    /// Download the content of a chunk and check it against its hash.
+    /// The copies of a replicated chunk are tried in turn until one is intact
+    pub async fn download(&self, chunk: &Chunk) -> Result<Vec<u8>> {
+        let mut error = Error::NotFound;
+        for replica in replicas(&chunk.locator) {
+            match self.download_copy(chunk, replica).await {
+                Ok(content) => return Ok(content),
+                Err(e) => error = e
+            }
+        }
+        Err(error)
+    }
+    /// Download and check one of the copies of a chunk
+    pub async fn download_copy(&self, chunk: &Chunk, locator: &str) -> Result<Vec<u8>> {
+        let (_, blob) = self.drive().get(locator).await?;
+        let blob = blob.ok_or(Error::BlobNotFound)?;
+        chunk.content(blob)?.ok_or_else(|| Error::Corrupted(format!("chunk {} of {}", locator, self.inner.dir_entry.path)))
+    }
+    /// Download a chunk in the cache if needed and open it
+    pub async fn load_chunk(&self, index: usize, chunk: &Chunk) -> Result<tokio::fs::File> {
+        let name = self.chunk_name(index);
+        let path = self.fs.cache.path(&name);
+        if !tokio::fs::try_exists(&path).await? {
+            let part = self.fs.cache.path(&format!("{}.part", name));
+            tokio::fs::write(&part, self.download(chunk).await?).await?;
+            tokio::fs::rename(&part, &path).await?;
+        }
+        // Opened before being touched, an eviction can't remove it under our feet
+        let content = tokio::fs::File::open(&path).await?;
+        self.fs.cache.touch(&name, *self.inner.id()).await?;
+        Ok(content)
+    }
+    /// Download the file in the cache if needed and open it
+    pub async fn load(&mut self) -> Result<()> {
+        if self.inner.cached.is_some() {
+            return Ok(())
+        }
+        let path = self.path();
+        if !self.pinned && !self.inner.metadata().is_dir() {
+            self.fs.cache.pin(&self.name());
+            self.pinned = true;
+        }
+
+        if self.truncate {
+            tokio::fs::File::create(&path).await?;
+            let metadata = self.inner.metadata_mut();
+            metadata.len = 0;
+            metadata.modified = Some(Utc::now());
+            self.truncate = false;
+            self.fs.cache.touch(&self.name(), *self.inner.id()).await?;
+            self.fs.cache.set_dirty(&self.name(), true).await?;
+        } else if !tokio::fs::try_exists(&path).await? {
+            if !self.is_sent().await? {
+                if self.inner.metadata().is_dir() {
+                    self.send_create().await?;
+                } else {
+                    // Sent on flush, with what was written until then
+                    tokio::fs::File::create(&path).await?;
+                }
+            } else {
+                self.refresh(true).await?;
+
+                if !self.inner.metadata().is_dir() {
+                    // Download in a temporary file so that an interrupted download is not mistaken for a complete one
+                    let part = path.with_extension("part");
+                    let mut content = tokio::fs::File::create(&part).await?;
+                    let mut hasher = blake3::Hasher::new();
+                    // Up to `Drive::concurrency` chunks are downloaded at the same time, and written in order
+                    let file = &*self;
+                    let downloads = self.chunks().await?.into_iter().map(|chunk| async move {
+                        file.download(&chunk).await
+                    });
+                    let mut blobs = futures::stream::iter(downloads).buffered(self.drive().concurrency());
+                    while let Some(blob) = blobs.next().await {
+                        let blob = blob?;
+                        hasher.update(&blob);
+                        content.write_all(&blob).await?;
+                    }
+                    content.flush().await?;
+                    if self.inner.dir_entry.checksum.as_ref().is_some_and(|c| hasher.finalize().to_hex().as_str() != c) {
+                        tokio::fs::remove_file(&part).await?;
+                        return Err(Error::Corrupted(self.inner.dir_entry.path.clone()))
+                    }
+                    tokio::fs::rename(&part, &path).await?;
+                }
+            }
+        }
+
+        if !self.inner.metadata().is_dir() {
+            let mut cached = tokio::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path).await?;
+            cached.seek(SeekFrom::Start(self.inner.cursor_pos)).await?;
+            self.inner.cached = Some(cached);
+            self.fs.cache.touch(&self.name(), *self.inner.id()).await?;
+        }
+
+        Ok(())
+    }
+    /// Update the entry if its object was changed by another server sharing the drive.
+    ///
+    /// Unless `force` is set, the object is checked at most once per `Options::freshness_ttl`.
+    /// The local changes that are not sent yet win over the remote ones, they overwrite them when sent.
+    /// If the object was removed, the entry and the ones under it are removed locally and `Error::NotFound` is returned.
+    pub async fn refresh(&mut self, force: bool) -> Result<()> {
+        let Some(locator) = self.inner.locator().cloned() else {
+            return Ok(())
+        };
+        if self.dirty || !(force || self.fs.should_check(*self.inner.id())) {
+            return Ok(())
+        }
+        if self.db().get_cache_entry(self.name()).await?.is_some_and(|e| e.dirty) {
+            return Ok(())
+        }
+        let (meta, version) = match self.drive().get_meta(&locator).await {
+            Ok(object) => object,
+            // Removed by another server
+            Err(Error::NotFound) => {
+                self.forget_removed().await?;
+                return Err(Error::NotFound)
+            },
+            Err(e) => return Err(e)
+        };
+        self.fs.mark_checked(*self.inner.id());
+        if self.inner.dir_entry.version.as_ref() == Some(&version) {
+            return Ok(())
+        }
+        let entry_meta: EntryMeta = serde_json::from_str(&meta)?;
+        // Moved to the trash by another server, which keeps its objects
+        if entry_meta.trashed.is_some() {
+            self.forget_removed().await?;
+            return Err(Error::NotFound)
+        }
+
+        if self.inner.metadata().is_dir() != entry_meta.metadata.is_dir() {
+            eprintln!("Distant file is_dir value is different from local file is_dir value");
+            return Err(Error::BadContent)
+        }
+
+        let id = *self.inner.id();
+        *self.inner.metadata_mut() = entry_meta.metadata.clone();
+        self.db().set_dir_entry_metadata(id, entry_meta.metadata).await?;
+        match entry_meta.chunks {
+            Some(chunks) => {
+                // The cached content is outdated
+                let old_chunks = self.chunks().await?;
+                self.remove_cached(old_chunks.len()).await?;
+                self.inner.dir_entry.checksum = entry_meta.checksum.clone();
+                self.db().set_dir_entry_objects(id, locator, version.clone(), chunks, entry_meta.checksum).await?;
+            },
+            None => self.db().set_dir_entry_version(id, version.clone()).await?
+        }
+        self.inner.dir_entry.version = Some(version);
+        Ok(())
+    }
+    /// Forget the entry and its descendants after another server removed them, without deleting their objects
+    async fn forget_removed(&self) -> Result<()> {
+        let chunks = self.chunks().await?;
+        self.db().forget_dir_entry(self.inner.dir_entry.path.clone()).await?;
+        self.remove_cached(chunks.len()).await
+    }
+    /// Send the local file to the drive.
+    ///
+    /// The metadata is stored in the object of the entry and the content in chunks of at most `Drive::max_blob_size` bytes, each in its own object.
+    /// The chunks are cut with FastCDC so that identical regions give identical chunks, and a chunk whose content was already sent,
+    /// by this file or by any other, references the existing object instead of being sent again.
+    /// The chunks are compressed if `Options::compression` is set and the file looks compressible.
+    /// Chunks are never edited in place because other files can share them: new ones are sent and the old ones are deleted once they are not referenced anymore,
+    /// or kept with the version they belong to when `Options::versioning` is set.
+    async fn upload(&mut self) -> Result<()> {
+        // Blobs sent before and reused, referenced until the entry references them
+        let mut acquired = Vec::new();
+        let result = self.upload_content(&mut acquired).await;
+        self.db().release_blobs(acquired).await?;
+        result
+    }
+    /// Send the content and the object of the entry, `acquired` collects the hashes of the blobs reused
+    async fn upload_content(&mut self, acquired: &mut Vec<String>) -> Result<()> {
+        let old_chunks = self.chunks().await?;
+        let mut chunks = Vec::new();
+
+        if !self.inner.metadata().is_dir() {
+            if !tokio::fs::try_exists(self.path()).await? {
+                return Err(Error::NotFound)
+            }
+            let max_size = MAX_CHUNK_SIZE.min(self.drive().max_blob_size() as u32);
+            let content = tokio::fs::File::open(self.path()).await?;
+            let mut chunker = AsyncStreamCDC::new(content, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, max_size);
+            let mut stream = Box::pin(chunker.as_stream());
+            let mut level = self.fs.options.compression;
+            // Chunks of the file, known or sent by this upload
+            let mut sent: HashMap<String, Chunk> = HashMap::new();
+            // Hash of each chunk of the file, in order
+            let mut hashes = Vec::new();
+            let mut seen = HashSet::new();
+            // Up to `Drive::concurrency` chunks are sent at the same time
+            let drive = self.drive().clone();
+            let mut uploads = FuturesUnordered::new();
+            let mut hasher = blake3::Hasher::new();
+            while let Some(data) = stream.next().await {
+                let blob = data?.data;
+                hasher.update(&blob);
+                if hashes.is_empty() {
+                    level = level.filter(|_| compression::is_compressible(&self.inner.dir_entry.path, &blob));
+                }
+                let hash = blake3::hash(&blob).to_hex().to_string();
+                hashes.push(hash.clone());
+                if !seen.insert(hash.clone()) {
+                    continue
+                }
+                if let Some(chunk) = self.db().acquire_blob(hash.clone()).await? {
+                    acquired.push(hash.clone());
+                    sent.insert(hash, chunk);
+                    continue
+                }
+                let size = blob.len() as u64;
+                let (blob, compressed) = match level {
+                    Some(level) => compression::compress(blob, level)?,
+                    None => (blob, false)
+                };
+                let blob_meta = serde_json::to_string(&BlobMeta { hash: hash.clone() })?;
+                let drive = &drive;
+                uploads.push(async move {
+                    let locator = drive.put(BLOB_NAME, &blob_meta, Some(blob)).await?;
+                    Ok::<_, Error>((hash.clone(), Chunk { locator, size, compressed, hash: Some(hash) }))
+                });
+                while uploads.len() >= drive.concurrency() {
+                    if let Some(uploaded) = uploads.next().await {
+                        let (hash, chunk) = uploaded?;
+                        sent.insert(hash, chunk);
+                    }
+                }
+            }
+            while let Some(uploaded) = uploads.next().await {
+                let (hash, chunk) = uploaded?;
+                sent.insert(hash, chunk);
+            }
+            chunks = hashes.iter().map(|h| sent[h].clone()).collect();
+            self.inner.dir_entry.checksum = Some(hasher.finalize().to_hex().to_string());
+        }
+
+        // The cached chunks are outdated
+        for index in 0..old_chunks.len() {
+            self.fs.cache.remove(&self.chunk_name(index)).await?;
+        }
+
+        self.send_meta(chunks.clone()).await?;
+        self.record_version(chunks).await?;
+        self.fs.spawn_purge();
+
+        Ok(())
+    }
+    /// Record the content just sent as the newest version of the file, if `Options::versioning` is set
+    async fn record_version(&self, chunks: Vec<Chunk>) -> Result<()> {
+        if !self.fs.options.versioning || self.inner.metadata().is_dir() {
+            return Ok(())
+        }
+        let expiry = self.fs.options.versions_max_age
+            .and_then(|age| chrono::Duration::from_std(age).ok())
+            .and_then(|age| Utc::now().checked_sub_signed(age));
+        self.db().add_file_version(
+            *self.inner.id(),
+            self.inner.dir_entry.path.clone(),
+            self.inner.metadata().clone(),
+            chunks,
+            self.inner.dir_entry.checksum.clone(),
+            self.fs.options.keep_versions,
+            expiry
+        ).await
+    }
+    /// Send the object of the entry without sending the content again.
+    ///
+    /// It holds the path of the entry to rebuild the index, and the chunks so that the other servers sharing the drive can read the new content.
+    async fn send_meta(&mut self, chunks: Vec<Chunk>) -> Result<()> {
+        let id = *self.inner.id();
+        let meta = serde_json::to_string(&EntryMeta {
+            metadata: self.inner.metadata().clone(),
+            path: Some(self.inner.dir_entry.path.clone()),
+            chunks: Some(chunks.clone()),
+            checksum: self.inner.dir_entry.checksum.clone(),
+            trashed: None
+        })?;
+        // Entries sent before the metadata had its own object can use theirs as a chunk
+        let (locator, version) = match self.inner.locator() {
+            Some(locator) if !self.db().is_chunk(locator.clone()).await? => {
+                let version = self.drive().update(locator, &id.to_string(), &meta, None).await?;
+                (locator.clone(), version)
+            },
+            _ => (self.drive().put(&id.to_string(), &meta, None).await?, String::new())
+        };
+        self.fs.mark_checked(id);
+
+        self.inner.dir_entry.locator = Some(locator.clone());
+        self.inner.dir_entry.version = Some(version.clone());
+        self.db().set_dir_entry_objects(id, locator, version, chunks, self.inner.dir_entry.checksum.clone()).await
+    }
+    /// Mark the object of the entry as moved to the trash at `deleted`, so that neither the other servers nor `rebuild-index` keep it in the tree.
+    /// The object is sent again without the mark when the entry is restored
+    pub async fn send_trashed(&mut self, deleted: DateTime<Utc>) -> Result<()> {
+        let Some(locator) = self.inner.locator().cloned() else {
+            return Ok(())
+        };
+        // Entries sent before the metadata had its own object have no object of their own to mark
+        if self.db().is_chunk(locator.clone()).await? {
+            return Ok(())
+        }
+        let id = *self.inner.id();
+        let meta = serde_json::to_string(&EntryMeta {
+            metadata: self.inner.metadata().clone(),
+            path: Some(self.inner.dir_entry.path.clone()),
+            chunks: Some(self.chunks().await?),
+            checksum: self.inner.dir_entry.checksum.clone(),
+            trashed: Some(deleted)
+        })?;
+        let version = self.drive().update(&locator, &id.to_string(), &meta, None).await?;
+        self.db().set_dir_entry_version(id, version).await
+    }
+    /// Send the object of the entry again, after it was lost
+    pub async fn resend_meta(&mut self) -> Result<()> {
+        self.inner.dir_entry.locator = None;
+        let chunks = self.chunks().await?;
+        self.send_meta(chunks).await
+    }
+    /// Content of a chunk from its cached copy, if it matches the hash of the chunk. `offset` is the position of the chunk in the file
+    async fn cached_chunk(&self, index: usize, offset: u64, chunk: &Chunk) -> Result<Option<Vec<u8>>> {
+        let Some(hash) = &chunk.hash else {
+            return Ok(None)
+        };
+        // The chunk can be cached on its own or in the whole file
+        for (path, start) in [(self.fs.cache.path(&self.chunk_name(index)), 0), (self.path(), offset)] {
+            let Ok(mut cached) = tokio::fs::File::open(&path).await else {
+                continue
+            };
+            let mut content = Vec::with_capacity(chunk.size as usize);
+            cached.seek(SeekFrom::Start(start)).await?;
+            cached.take(chunk.size).read_to_end(&mut content).await?;
+            if blake3::hash(&content).to_hex().as_str() == hash {
+                return Ok(Some(content))
+            }
+        }
+        Ok(None)
+    }
+    /// Store again the `corrupted` and `lost` copies of a chunk, from `content` read from an intact copy or from the cached copy
+    /// if it matches the hash of the chunk. Return whether there was an intact content to repair them from.
+    ///
+    /// `offset` is the position of the chunk in the file. The corrupted copies are overwritten and the intact ones are left as they are.
+    /// The lost copies are replaced by new objects, then all the files sharing the chunk use its new locator
+    /// and their objects are sent again by `DriveFs::send_outdated`.
+    pub async fn repair_copies(&self, index: usize, offset: u64, chunk: &Chunk, corrupted: &[&str], lost: &[&str], content: Option<Vec<u8>>) -> Result<bool> {
+        let Some(hash) = &chunk.hash else {
+            return Ok(false)
+        };
+        let content = match content {
+            Some(content) => content,
+            None => match self.cached_chunk(index, offset, chunk).await? {
+                Some(content) => content,
+                None => return Ok(false)
+            }
+        };
+        // The copies share how the chunk is stored
+        let blob = match chunk.compressed {
+            true => compression::recompress(&content, compression::DEFAULT_LEVEL)?,
+            false => content
+        };
+        let blob_meta = serde_json::to_string(&BlobMeta { hash: hash.clone() })?;
+        for copy in corrupted {
+            self.drive().update(copy, BLOB_NAME, &blob_meta, Some(blob.clone())).await?;
+        }
+        if lost.is_empty() {
+            return Ok(true)
+        }
+        // Copied from the remaining copies, which are intact now
+        let locator = match self.drive().replicate(&chunk.locator, BLOB_NAME, &|meta, blob| chunk.is_intact(meta, blob)).await? {
+            Some(locator) => locator,
+            // The drive doesn't replicate the objects
+            None => {
+                let new = self.drive().put(BLOB_NAME, &blob_meta, Some(blob)).await?;
+                replicas(&chunk.locator)
+                    .filter(|c| !lost.contains(c))
+                    .map(String::from)
+                    .chain(std::iter::once(new))
+                    .collect::<Vec<_>>()
+                    .join(&REPLICA_SEPARATOR.to_string())
+            }
+        };
+        self.db().replace_chunk_object(chunk.locator.clone(), locator, chunk.compressed).await?;
+        Ok(true)
+    }
+    /// Send the file to the drive for the first time
+    pub async fn send_create(&mut self) -> Result<()> {
+        self.upload().await
+    }
+    /// Edit the file on the drive, return an error if the file was not sent
+    pub async fn send_edit(&mut self) -> Result<()> {
+        if !self.is_sent().await? {
+            return Err(Error::LocatorIsNone)
+        }
+        self.upload().await
+    }
+    // Create or edit distant file
+    pub async fn send(&mut self) -> Result<()> {
+        match self.is_sent().await? {
+            true => self.send_edit().await,
+            false => self.send_create().await
+        }
+    }
+    /// Write in the cache, the file is sent to the drive on flush
+    pub async fn write_content(&mut self, mut buf: impl Buf) -> Result<()> {
+        self.load().await?;
+        let cached = self.inner.cached.as_mut().ok_or(Error::FileContentIsNone)?;
+        while buf.has_remaining() {
+            let chunk = buf.chunk();
+            let n = cached.write(chunk).await?;
+            buf.advance(n);
+        }
+        let pos = cached.stream_position().await?;
+        let metadata = self.inner.metadata_mut();
+        metadata.len = metadata.len.max(pos);
+        metadata.modified = Some(Utc::now());
+        if !self.dirty {
+            self.fs.cache.set_dirty(&self.name(), true).await?;
+            self.dirty = true;
+        }
+        Ok(())
+    }
+    /// Read from the cached chunks covering the requested range, without loading the whole file
+    async fn read_chunks(&mut self, count: usize) -> Result<bytes::Bytes> {
+        let start = self.inner.cursor_pos;
+        let end = start + count as u64;
+        let mut buf = Vec::with_capacity(count);
+        let mut offset = 0;
+        for (index, chunk) in self.chunks().await?.iter().enumerate() {
+            if offset >= end {
+                break
+            }
+            if offset + chunk.size > start {
+                let from = start.max(offset) - offset;
+                let to = end.min(offset + chunk.size) - offset;
+                let mut content = self.load_chunk(index, chunk).await?;
+                content.seek(SeekFrom::Start(from)).await?;
+                content.take(to - from).read_to_end(&mut buf).await?;
+            }
+            offset += chunk.size;
+        }
+        self.inner.cursor_pos += buf.len() as u64;
+        Ok(buf.into())
+    }
+    pub async fn read_content(&mut self, count: usize) -> Result<bytes::Bytes> {
+        if self.inner.cached.is_none() && !self.truncate && !tokio::fs::try_exists(self.path()).await? && !self.chunks().await?.is_empty() {
+            return self.read_chunks(count).await
+        }
+        self.load().await?;
+        let cached = self.inner.cached.as_mut().ok_or(Error::FileContentIsNone)?;
+        let mut buf = vec![0; count];
+        let mut n = 0;
+        while n < count {
+            match cached.read(&mut buf[n..]).await? {
+                0 => break,
+                r => n += r
+            }
+        }
+        buf.truncate(n);
+        Ok(buf.into())
+    }
+    pub async fn seek_content(&mut self, pos: SeekFrom) -> Result<u64> {
+        if let Some(cached) = self.inner.cached.as_mut() {
+            return Ok(cached.seek(pos).await?)
+        }
+        // The content is not loaded, only move the cursor
+        let new_pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(o) => self.inner.metadata().len.checked_add_signed(o),
+            SeekFrom::Current(o) => self.inner.cursor_pos.checked_add_signed(o)
+        };
+        self.inner.cursor_pos = new_pos.ok_or(std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position"))?;
+        Ok(self.inner.cursor_pos)
+    }
+    /// Flush the cache and send the file to the drive if it was modified
+    pub async fn flush_content(&mut self) -> Result<()> {
+        if self.dirty {
+            // Apply a truncation even if nothing was written
+            self.load().await?;
+        }
+        if let Some(c) = &mut self.inner.cached {
+            c.flush().await?;
+        }
+        if self.dirty {
+            self.db().set_dir_entry_metadata(*self.inner.id(), self.inner.metadata().clone()).await?;
+            self.send().await?;
+            // Update the size before the file can be evicted
+            self.fs.cache.touch(&self.name(), *self.inner.id()).await?;
+            self.fs.cache.set_dirty(&self.name(), false).await?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DriveFile {
+    /// The WebDAV handler flushes the files it writes, so this only happens when a request is interrupted.
+    /// What was written is still sent so that the drive does not diverge from the cache.
+    /// It is also unpinned from the cache.
+    fn drop(&mut self) {
+        if self.pinned {
+            self.fs.cache.unpin(&self.name());
+        }
+        if !self.dirty {
+            return
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            eprintln!("Failed to send {}: no runtime to send it", self.inner.dir_entry.path);
+            return
+        };
+        let mut file = DriveFile::new(File {
+            dir_entry: self.inner.dir_entry.clone(),
+            cached: None,
+            cursor_pos: 0
+        }, self.fs.clone());
+        file.dirty = true;
+        file.truncate = self.truncate;
+        self.dirty = false;
+        runtime.spawn(async move {
+            if let Err(e) = file.flush_content().await {
+                eprintln!("Failed to send {}: {}", file.inner.dir_entry.path, e);
+            }
+        });
+    }
+}
+
+impl DavFile for DriveFile {
+    fn metadata<'a>(&'a mut self) -> webdav_handler::fs::FsFuture<Box<dyn DavMetaData>> {
+        async {
+            Ok(self.inner.metadata().clone().boxed() as Box<(dyn DavMetaData + 'static)>)
+        }.boxed()
+    }
+    fn write_bytes<'a>(&'a mut self, buf: bytes::Bytes) -> webdav_handler::fs::FsFuture<()> {
+        async move {
+            Ok(self.write_content(buf).await?)
+        }.boxed()
+    }
+    fn write_buf<'a>(&'a mut self, buf: Box<dyn bytes::Buf + Send>) -> webdav_handler::fs::FsFuture<()> {
+        async move {
+            Ok(self.write_content(buf).await?)
+        }.boxed()
+    }
+    fn read_bytes<'a>(&'a mut self, count: usize) -> webdav_handler::fs::FsFuture<bytes::Bytes> {
+        async move {
+            Ok(self.read_content(count).await?)
+        }.boxed()
+    }
+    fn seek<'a>(&'a mut self, pos: SeekFrom) -> webdav_handler::fs::FsFuture<u64> {
+        async move {
+            Ok(self.seek_content(pos).await?)
+        }.boxed()
+    }
+    fn flush<'a>(&'a mut self) -> webdav_handler::fs::FsFuture<()> {
+        async move {
+            Ok(self.flush_content().await?)
+        }.boxed()
+    }
+}
+
+/// Behaviour of a `DriveFs`
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Also store the directories in the drive, as objects without blob
+    pub mirror_dirs: bool,
+    /// Time during which an opened file is not checked again for changes made by other servers
+    pub freshness_ttl: Duration,
+    /// Zstd level used to compress the content of the files, `None` to send it as is
+    pub compression: Option<i32>,
+    /// Keep the previous contents of the files, readable under `/.versions`
+    pub versioning: bool,
+    /// Number of versions kept for each file, all if `None`
+    pub keep_versions: Option<usize>,
+    /// Time during which the versions are kept, forever if `None`. The current content of a file is always kept
+    pub versions_max_age: Option<Duration>,
+    /// Time during which the removed entries are kept in `/.trash`, they are deleted right away if `None`
+    pub trash: Option<Duration>
+}
+impl Options {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        Ok(Self {
+            mirror_dirs: config.flag("MIRROR_DIRS"),
+            freshness_ttl: Duration::from_secs(match config.get("FRESHNESS_TTL") {
+                Some(ttl) => ttl.parse()?,
+                None => 60
+            }),
+            compression: config.get("COMPRESSION_LEVEL").map(|l| l.parse()).transpose()?,
+            versioning: config.flag("VERSIONING"),
+            keep_versions: config.get("KEEP_VERSIONS").map(|n| n.parse()).transpose()?,
+            // In days
+            versions_max_age: config.get("KEEP_VERSIONS_DAYS")
+                .map(|d| d.parse::<u64>().map(|d| Duration::from_secs(d * 24 * 60 * 60)))
+                .transpose()?,
+            // In days, 0 to disable the trash
+            trash: config.get("TRASH_DAYS")
+                .map(|d| d.parse::<u64>().map(|d| Duration::from_secs(d * 24 * 60 * 60)))
+                .transpose()?
+                .filter(|d| !d.is_zero())
+        })
+    }
+}
+
+/// WebDAV filesystem storing its tree in the database and the content of the files in a drive
+#[derive(Clone, Debug)]
+pub struct DriveFs {
+    db: Arc<DB>,
+    drive: Arc<dyn Drive>,
+    cache: Arc<Cache>,
+    options: Options,
+    /// Held while purging
+    purging: Arc<Mutex<()>>,
+    /// Held while sending the objects of the outdated entries
+    sending: Arc<Mutex<()>>,
+    /// Last time the object of each entry was checked for changes
+    checked: Arc<std::sync::Mutex<HashMap<usize, Instant>>>
+}
+impl DriveFs {
+    pub fn new(db: Arc<DB>, drive: Arc<dyn Drive>, cache: Cache, options: Options) -> Self {
+        Self {
+            db,
+            drive,
+            cache: Arc::new(cache),
+            options,
+            purging: Arc::new(Mutex::new(())),
+            sending: Arc::new(Mutex::new(())),
+            checked: Arc::new(std::sync::Mutex::new(HashMap::new()))
+        }
+    }
+    pub fn db(&self) -> &Arc<DB> {
+        &self.db
+    }
+    pub fn drive(&self) -> &Arc<dyn Drive> {
+        &self.drive
+    }
+    /// Whether the object of an entry was not checked for `Options::freshness_ttl`
+    fn should_check(&self, id: usize) -> bool {
+        match self.checked.lock().unwrap().get(&id) {
+            Some(checked) => checked.elapsed() >= self.options.freshness_ttl,
+            None => true
+        }
+    }
+    fn mark_checked(&self, id: usize) {
+        self.checked.lock().unwrap().insert(id, Instant::now());
+    }
+    /// Delete the objects of the removed entries from the drive, and of the ones kept in the trash for longer than `Options::trash`
+    pub async fn purge(&self) -> Result<()> {
+        loop {
+            {
+                // Another purge is running and will also delete the new objects
+                let Ok(_purging) = self.purging.try_lock() else {
+                    return Ok(())
+                };
+                if let Some(expiry) = self.options.trash
+                    .and_then(|age| chrono::Duration::from_std(age).ok())
+                    .and_then(|age| Utc::now().checked_sub_signed(age)) {
+                    self.db.empty_trash(Some(expiry)).await?;
+                }
+                loop {
+                    let locators = self.db.get_pending_deletions(PURGE_BATCH).await?;
+                    if locators.is_empty() {
+                        break
+                    }
+                    self.drive.delete_many(&locators).await?;
+                    self.db.remove_pending_deletions(locators).await?;
+                }
+            }
+            // Queued by a purge that was skipped after this one found nothing left
+            if self.db.get_pending_deletions(1).await?.is_empty() {
+                return Ok(())
+            }
+        }
+    }
+    /// Purge in the background after `PURGE_DELAY`
+    pub fn spawn_purge(&self) {
+        let fs = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(PURGE_DELAY).await;
+            if let Err(e) = fs.purge().await {
+                eprintln!("Failed to purge removed objects: {}", e);
+            }
+        });
+    }
+    /// Purge every `TRASH_CHECK_INTERVAL` while `Options::trash` is set, so that the expired entries are deleted without waiting for another removal
+    pub fn spawn_expire_trash(&self) {
+        if self.options.trash.is_none() {
+            return
+        }
+        let fs = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(TRASH_CHECK_INTERVAL).await;
+                if let Err(e) = fs.purge().await {
+                    eprintln!("Failed to delete the expired entries of the trash: {}", e);
+                }
+            }
+        });
+    }
+    /// Send the files that were written but not sent before the last shutdown
+    pub async fn send_dirty(&self) -> Result<()> {
+        for entry in self.db.get_cache_entries().await? {
+            // Only whole files are written
+            if !entry.dirty || entry.name != entry.entry_id.to_string() {
+                continue
+            }
+            let Some(file) = self.db.get_file_by_id(entry.entry_id).await? else {
+                self.cache.remove(&entry.name).await?;
+                continue
+            };
+            let mut file = DriveFile::new(file, self.clone());
+            file.inner.metadata_mut().len = tokio::fs::metadata(file.path()).await?.len();
+            file.dirty = true;
+            file.flush_content().await?;
+        }
+        Ok(())
+    }
+    /// Send the dirty files in the background
+    pub fn spawn_send_dirty(&self) {
+        let fs = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = fs.send_dirty().await {
+                eprintln!("Failed to send the files written before the shutdown: {}", e);
+            }
+        });
+    }
+    /// Send the objects of the outdated entries again, so that they hold their new path or the new objects of their chunks.
+    /// The entries that fail stay queued until the next run
+    pub async fn send_outdated(&self) -> Result<()> {
+        // The entries queued while another run is sending are sent by this one afterwards
+        let _sending = self.sending.lock().await;
+        for id in self.db.get_outdated_entries().await? {
+            // Read now, the entry can have been sent since it was queued
+            let Some(file) = self.db.get_file_by_id(id).await? else {
+                continue
+            };
+            let mut file = DriveFile::new(file, self.clone());
+            let chunks = file.chunks().await?;
+            // Never sent, like the directories when they are not mirrored
+            let sent = file.inner.locator().is_some() || !chunks.is_empty() || (file.inner.metadata().is_dir() && self.options.mirror_dirs);
+            if sent {
+                if let Err(e) = file.send_meta(chunks).await {
+                    eprintln!("Failed to send the object of {} again: {}", file.inner.dir_entry.path, e);
+                    continue
+                }
+            }
+            self.db.remove_outdated_entry(id).await?;
+        }
+        Ok(())
+    }
+    /// Send the objects of the outdated entries in the background
+    pub fn spawn_send_outdated(&self) {
+        let fs = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = fs.send_outdated().await {
+                eprintln!("Failed to send the objects of the outdated entries: {}", e);
+            }
+        });
+    }
+    /// Remove an entry from the database and the cache, its objects are deleted from the drive later.
+    /// With `Options::trash` the entry is moved to the trash instead
+    async fn remove_entry(&self, path: &DavPath, is_dir: bool) -> FsResult<()> {
+        let path = decode_path(path)?;
+        if let Some(path) = trash::strip(&path) {
+            return self.delete_trash(path).await
+        }
+        if is_view(&path) {
+            return Err(FsError::Forbidden)
+        }
+        let file = self.db.get_file_by_path(path).await?.ok_or(FsError::NotFound)?;
+        if file.metadata().is_dir != is_dir || file.dir_entry.parent_id.is_none() {
+            return Err(FsError::Forbidden)
+        }
+        if is_dir && !self.db.get_dir_entries_by_parent_id(*file.id()).await?.is_empty() {
+            return Err(FsError::Forbidden)
+        }
+        let mut file = DriveFile::new(file, self.clone());
+        let chunks = file.chunks().await?;
+        match self.options.trash {
+            Some(_) => {
+                let deleted = Utc::now();
+                file.send_trashed(deleted).await?;
+                self.db.trash_dir_entry(*file.inner.id(), deleted).await?
+            },
+            None => self.db.remove_dir_entry(*file.inner.id()).await?
+        }
+        file.remove_cached(chunks.len()).await?;
+        self.spawn_purge();
+        Ok(())
+    }
+    /// Get the parent directory of a path, `FsError::NotFound` if it does not exist
+    async fn parent(&self, path: &str) -> FsResult<Option<DirEntry>> {
+        let parent_path = match Path::new(path).parent() {
+            Some(parent_path) => parent_path.to_str().ok_or(FsError::Forbidden)?,
+            None => return Ok(None)
+        };
+        let parent = self.db.get_dir_entry_by_path(parent_path.to_owned()).await?.ok_or(FsError::NotFound)?;
+        if !parent.metadata.is_dir {
+            return Err(FsError::Forbidden)
+        }
+        Ok(Some(parent))
+    }
+    /// Make a past content the content of the file at `to`, which is created if needed.
+    /// Nothing is sent but the object of the entry, the chunks are shared with the past content
+    async fn restore_content(&self, to: String, len: u64, checksum: Option<String>, chunks: Vec<Chunk>) -> FsResult<()> {
+        let metadata = Metadata {
+            len,
+            modified: Some(Utc::now()),
+            is_dir: false
+        };
+        let entry = match self.db.get_dir_entry_by_path(to.clone()).await? {
+            Some(entry) if entry.metadata.is_dir => return Err(FsError::Forbidden),
+            Some(entry) => entry,
+            None => {
+                let parent = self.parent(&to).await?;
+                self.db.insert_dir_entry(parent.map(|p| p.id), to.clone(), metadata.clone()).await?;
+                self.db.get_dir_entry_by_path(to).await?.ok_or(FsError::NotFound)?
+            }
+        };
+        let mut file = DriveFile::new(File {
+            dir_entry: entry,
+            cached: None,
+            cursor_pos: 0
+        }, self.clone());
+        // The cached content is replaced, even if it was not sent yet
+        file.remove_cached(file.chunks().await?.len()).await?;
+        *file.inner.metadata_mut() = metadata.clone();
+        file.inner.dir_entry.checksum = checksum;
+        self.db.set_dir_entry_metadata(*file.inner.id(), metadata).await?;
+        file.send_meta(chunks.clone()).await?;
+        file.record_version(chunks).await?;
+        self.spawn_purge();
+        Ok(())
+    }
+}
+
+impl DavFileSystem for DriveFs {
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> webdav_handler::fs::FsFuture<Box<dyn DavMetaData>> {
+        async move {
+            let path = decode_path(path)?;
+            println!("metadata on {}", path);
+            if let Some(path) = versions::strip(&path) {
+                return Ok(self.version_metadata(&path).await?.boxed() as Box<dyn DavMetaData>)
+            }
+            if let Some((name, path)) = snapshots::strip(&path) {
+                return Ok(self.snapshot_metadata(name, path).await?.boxed() as Box<dyn DavMetaData>)
+            }
+            if let Some(path) = trash::strip(&path) {
+                return Ok(self.trash_metadata(path).await?.boxed() as Box<dyn DavMetaData>)
+            }
+            let entry = self.db.get_dir_entry_by_path(path).await?.ok_or(FsError::NotFound)?;
+            Ok(entry.metadata.boxed() as Box<dyn DavMetaData>)
+        }.boxed()
+    }
+    fn read_dir<'a>(
+            &'a self,
+            path: &'a DavPath,
+            _meta: webdav_handler::fs::ReadDirMeta,
+    ) -> webdav_handler::fs::FsFuture<webdav_handler::fs::FsStream<Box<dyn DavDirEntry>>> {
+        async move {
+            let path = decode_path(path)?;
+            println!("read_dir on {}", path);
+            let entries = match (versions::strip(&path), snapshots::strip(&path), trash::strip(&path)) {
+                (Some(path), _, _) => self.read_versions_dir(&path).await?,
+                (_, Some((name, path)), _) => self.read_snapshots_dir(name, path).await?,
+                (_, _, Some(path)) => self.read_trash_dir(path).await?,
+                _ => {
+                    let dir = self.db.get_dir_entry_by_path(path).await?.ok_or(FsError::NotFound)?;
+                    self.db.get_dir_entries_by_parent_id(dir.id).await?
+                }
+            };
+            let entries: Vec<Box<dyn DavDirEntry>> = entries.into_iter().map(|e|Box::new(e) as Box<dyn DavDirEntry>).collect();
+            let stream = futures::stream::iter(entries);
+            Ok(Box::pin(stream) as webdav_handler::fs::FsStream<Box<dyn DavDirEntry>>)
+        }.boxed()
+    }
+    fn open<'a>(&'a self, path: &'a DavPath, options: webdav_handler::fs::OpenOptions) -> webdav_handler::fs::FsFuture<Box<dyn DavFile>> {
+        async move {
+            let original_path = path;
+            let path = decode_path(path)?;
+            println!("open on {}", path);
+            dbg!(options);
+            if let Some(path) = versions::strip(&path) {
+                return Ok(self.open_version(&path, options).await?.boxed() as Box<dyn DavFile>)
+            }
+            if let Some((name, path)) = snapshots::strip(&path) {
+                return Ok(self.open_snapshot(name, path, options).await?.boxed() as Box<dyn DavFile>)
+            }
+            if let Some(path) = trash::strip(&path) {
+                return Ok(self.open_trash(path, options).await?.boxed() as Box<dyn DavFile>)
+            }
+            let file = self.db.get_file_by_path(path.clone()).await?;
+            if file.is_some() && options.create_new {
+                return Err(FsError::Exists)
+            }
+            let mut file = match file {
+                Some(file) => file,
+                None => {
+                    if !(options.create_new || options.create) {
+                        return Err(FsError::NotFound)
+                    }
+                    let parent = self.parent(&path).await?;
+                    self.db.insert_dir_entry(parent.map(|p|p.id), path.clone(), Metadata { len: 0, modified: None, is_dir: false }).await?;
+                    return self.open(original_path, options).await
+                }
+            };
+            if options.append {
+                file.cursor_pos = file.metadata().len;
+            }
+
+            let mut file = DriveFile::new(file, self.clone());
+            if options.truncate && !file.inner.metadata().is_dir() {
+                // The truncation is applied on the first write or on flush
+                file.truncate = true;
+                file.dirty = true;
+            }
+            file.refresh(false).await?;
+            Ok(file.boxed() as Box<dyn DavFile>)
+        }.boxed()
+    }
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> webdav_handler::fs::FsFuture<()> {
+        async move {
+            let path = decode_path(path)?;
+            if is_view(&path) {
+                return Err(FsError::Forbidden)
+            }
+            if self.db.get_dir_entry_by_path(path.clone()).await?.is_some() {
+                return Err(FsError::Exists)
+            }
+            // A missing parent is reported as a conflict by the WebDAV handler
+            let parent = self.parent(&path).await?;
+            self.db.insert_dir_entry(parent.map(|p|p.id), path.clone(), Metadata { len: 0, modified: Some(Utc::now()), is_dir: true }).await?;
+
+            if self.options.mirror_dirs {
+                let file = self.db.get_file_by_path(path).await?.ok_or(FsError::NotFound)?;
+                DriveFile::new(file, self.clone()).send_create().await?;
+            }
+            Ok(())
+        }.boxed()
+    }
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> webdav_handler::fs::FsFuture<()> {
+        async move {
+            self.remove_entry(path, false).await
+        }.boxed()
+    }
+    /// Only remove empty directories, the WebDAV handler removes their content first
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> webdav_handler::fs::FsFuture<()> {
+        async move {
+            self.remove_entry(path, true).await
+        }.boxed()
+    }
+    /// The content is not sent again, only the objects of the entries that hold their path, in the background.
+    /// Moving an entry out of `/.trash` puts it back
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> webdav_handler::fs::FsFuture<()> {
+        async move {
+            let from = decode_path(from)?;
+            let to = decode_path(to)?;
+            if let (Some(from), false) = (trash::strip(&from), is_view(&to)) {
+                return self.restore_trash(from, to).await
+            }
+            if is_view(&from) || is_view(&to) {
+                return Err(FsError::Forbidden)
+            }
+            let entry = self.db.get_dir_entry_by_path(from.clone()).await?.ok_or(FsError::NotFound)?;
+            if entry.parent_id.is_none() || to.starts_with(&(from.clone() + "/")) {
+                return Err(FsError::Forbidden)
+            }
+            if self.db.get_dir_entry_by_path(to.clone()).await?.is_some() {
+                return Err(FsError::Exists)
+            }
+            let parent = self.parent(&to).await?;
+            self.db.rename_dir_entry(entry.id, from, to.clone(), parent.map(|p|p.id)).await?;
+            self.db.add_outdated_entries(to).await?;
+            self.spawn_send_outdated();
+            Ok(())
+        }.boxed()
+    }
+    /// The copies share the chunks of the original, see `DriveFile::upload`. Only their own objects are sent, in the background.
+    /// Copying a version out of `/.versions` or a file out of `/.snapshots` restores it
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> webdav_handler::fs::FsFuture<()> {
+        async move {
+            let from = decode_path(from)?;
+            let to = decode_path(to)?;
+            if is_view(&to) {
+                return Err(FsError::Forbidden)
+            }
+            if let Some(from) = versions::strip(&from) {
+                return self.restore_version(&from, to).await
+            }
+            if let Some((name, from)) = snapshots::strip(&from) {
+                return self.restore_snapshot_file(name, from, to).await
+            }
+            let entry = self.db.get_dir_entry_by_path(from.clone()).await?.ok_or(FsError::NotFound)?;
+            if entry.parent_id.is_none() || to.starts_with(&(from.clone() + "/")) {
+                return Err(FsError::Forbidden)
+            }
+            if self.db.get_dir_entry_by_path(to.clone()).await?.is_some() {
+                return Err(FsError::Exists)
+            }
+            let parent = self.parent(&to).await?;
+            self.db.copy_dir_entry(from, to.clone(), parent.map(|p|p.id)).await?;
+            self.db.add_outdated_entries(to).await?;
+            self.spawn_send_outdated();
+            Ok(())
+        }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webdav_handler::fs::OpenOptions;
+    use crate::drives::{DriveFuture, ObjectInfo};
+    use crate::drives::discord::{mock, DiscordClient};
+    use crate::tests::{content, path, Server};
+
+    /// A drive accepting smaller blobs than the one it wraps
+    #[derive(Debug)]
+    struct Limited {
+        inner: DiscordClient,
+        max_blob_size: usize
+    }
+    impl Drive for Limited {
+        fn max_blob_size(&self) -> usize {
+            self.max_blob_size
+        }
+        fn put<'a>(&'a self, name: &'a str, meta: &'a str, blob: Option<Vec<u8>>) -> DriveFuture<'a, String> {
+            assert!(blob.as_ref().map_or(0, |b| b.len()) <= self.max_blob_size);
+            self.inner.put(name, meta, blob)
+        }
+        fn get<'a>(&'a self, locator: &'a str) -> DriveFuture<'a, (String, Option<Vec<u8>>)> {
+            self.inner.get(locator)
+        }
+        fn get_meta<'a>(&'a self, locator: &'a str) -> DriveFuture<'a, (String, String)> {
+            self.inner.get_meta(locator)
+        }
+        fn update<'a>(&'a self, locator: &'a str, name: &'a str, meta: &'a str, blob: Option<Vec<u8>>) -> DriveFuture<'a, String> {
+            self.inner.update(locator, name, meta, blob)
+        }
+        fn list<'a>(&'a self, cursor: Option<String>) -> DriveFuture<'a, (Vec<ObjectInfo>, Option<String>)> {
+            self.inner.list(cursor)
+        }
+        fn pin<'a>(&'a self, locator: &'a str) -> DriveFuture<'a, ()> {
+            self.inner.pin(locator)
+        }
+        fn pinned<'a>(&'a self) -> DriveFuture<'a, Vec<ObjectInfo>> {
+            self.inner.pinned()
+        }
+        fn delete<'a>(&'a self, locator: &'a str) -> DriveFuture<'a, ()> {
+            self.inner.delete(locator)
+        }
+    }
+
+    async fn limited(max_blob_size: usize) -> Server {
+        let url = mock::start().await.unwrap();
+        let inner = DiscordClient::new("token".to_string(), "channel".to_string(), url.clone());
+        Server::with_drive(url, Arc::new(Limited { inner, max_blob_size }), Options::default()).await
+    }
+
+    async fn chunks_of(server: &Server, path: &str) -> Vec<Chunk> {
+        let entry = server.db.get_dir_entry_by_path(path.to_string()).await.unwrap().unwrap();
+        server.db.get_chunks_by_entry_id(entry.id).await.unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn chunks_fit_in_blobs() {
+        let server = limited(300 * 1024).await;
+        let data = content(10, 2_000_000);
+        server.write("/file", &data).await;
+        let chunks = chunks_of(&server, "/file").await;
+        assert!(chunks.len() >= 7);
+        assert!(chunks.iter().all(|c| c.size <= 300 * 1024));
+        assert_eq!(chunks.iter().map(|c| c.size).sum::<u64>(), 2_000_000);
+        server.clear_cache();
+        assert!(server.read("/file").await == data);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn empty_file() {
+        let server = limited(300 * 1024).await;
+        server.write("/file", b"").await;
+        assert!(chunks_of(&server, "/file").await.is_empty());
+        server.clear_cache();
+        assert!(server.read("/file").await.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn range_read_caches_covering_chunks() {
+        let server = limited(300 * 1024).await;
+        let data = content(11, 2_000_000);
+        server.write("/file", &data).await;
+        let id = server.db.get_dir_entry_by_path("/file".to_string()).await.unwrap().unwrap().id;
+        let chunks = chunks_of(&server, "/file").await;
+        server.clear_cache();
+
+        // Across the end of the second chunk
+        let offset = chunks[0].size + chunks[1].size - 10;
+        assert!(server.read_range("/file", offset, 20).await == data[offset as usize..offset as usize + 20]);
+        let mut cached: Vec<String> = std::fs::read_dir(&server.cache).unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        cached.sort();
+        assert_eq!(cached, vec![format!("{}.1", id), format!("{}.2", id)]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn create_dir() {
+        let server = Server::start(Options::default()).await;
+        server.fs.create_dir(&path("/dir")).await.unwrap();
+        assert!(server.fs.metadata(&path("/dir")).await.unwrap().is_dir());
+        assert!(matches!(server.fs.create_dir(&path("/dir")).await, Err(FsError::Exists)));
+        assert!(matches!(server.fs.create_dir(&path("/missing/dir")).await, Err(FsError::NotFound)));
+        server.write("/file", b"hello").await;
+        assert!(matches!(server.fs.create_dir(&path("/file/dir")).await, Err(FsError::Forbidden)));
+        // Not mirrored
+        let dir = server.db.get_dir_entry_by_path("/dir".to_string()).await.unwrap().unwrap();
+        assert!(dir.locator.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn create_mirrored_dir() {
+        let server = Server::start(Options { mirror_dirs: true, ..Default::default() }).await;
+        server.fs.create_dir(&path("/dir")).await.unwrap();
+        server.fs.create_dir(&path("/dir/sub")).await.unwrap();
+        let sub = server.db.get_dir_entry_by_path("/dir/sub".to_string()).await.unwrap().unwrap();
+        let (meta, blob) = server.drive.get(&sub.locator.unwrap()).await.unwrap();
+        assert!(meta.contains("/dir/sub"));
+        assert!(blob.is_none());
+    }
+
+    async fn is_dirty(server: &Server, name: String) -> bool {
+        server.db.get_cache_entries().await.unwrap().iter().any(|e| e.name == name && e.dirty)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn write_back() {
+        let server = Server::start(Options::default()).await;
+        let mut file = server.fs.open(&path("/file"), OpenOptions { write: true, create: true, ..Default::default() }).await.unwrap();
+        let id = server.db.get_dir_entry_by_path("/file".to_string()).await.unwrap().unwrap().id;
+        for _ in 0..10 {
+            file.write_bytes(bytes::Bytes::from_static(b"0123456789")).await.unwrap();
+        }
+        // Nothing is sent before the flush
+        assert!(server.db.get_dir_entry_by_path("/file".to_string()).await.unwrap().unwrap().locator.is_none());
+        assert!(is_dirty(&server, id.to_string()).await);
+        file.flush().await.unwrap();
+        drop(file);
+        assert!(!is_dirty(&server, id.to_string()).await);
+        assert_eq!(server.objects("/file").await.len(), 2);
+        assert_eq!(server.fs.metadata(&path("/file")).await.unwrap().len(), 100);
+        server.clear_cache();
+        assert_eq!(server.read("/file").await, b"0123456789".repeat(10));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn send_dirty_after_restart() {
+        let server = Server::start(Options::default()).await;
+        let mut file = server.fs.open(&path("/file"), OpenOptions { write: true, create: true, ..Default::default() }).await.unwrap();
+        file.write_bytes(bytes::Bytes::from_static(b"hello")).await.unwrap();
+        // Wait for the write to reach the cache, then stop without flushing or dropping the file
+        file.seek(SeekFrom::Current(0)).await.unwrap();
+        std::mem::forget(file);
+
+        let drive = server.drive.clone();
+        let server = server.with_new_drive(drive, Options::default());
+        server.fs.send_dirty().await.unwrap();
+        let id = server.db.get_dir_entry_by_path("/file".to_string()).await.unwrap().unwrap().id;
+        assert!(!is_dirty(&server, id.to_string()).await);
+        assert_eq!(server.objects("/file").await.len(), 2);
+        server.clear_cache();
+        assert_eq!(server.read("/file").await, b"hello");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn acquired_blob_is_not_purged() {
+        let server = Server::start(Options::default()).await;
+        let data = content(12, 10_000);
+        server.write("/a", &data).await;
+        let chunk = chunks_of(&server, "/a").await.remove(0);
+        let hash = chunk.hash.clone().unwrap();
+
+        // Found by an upload when the only file using it is removed
+        assert_eq!(server.db.acquire_blob(hash.clone()).await.unwrap().unwrap().locator, chunk.locator);
+        server.fs.remove_file(&path("/a")).await.unwrap();
+        server.fs.purge().await.unwrap();
+        assert!(server.drive.get(&chunk.locator).await.is_ok());
+
+        // The upload failed
+        server.db.release_blobs(vec![hash.clone()]).await.unwrap();
+        assert!(server.db.acquire_blob(hash).await.unwrap().is_none());
+        server.fs.purge().await.unwrap();
+        assert!(server.drive.get(&chunk.locator).await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reused_blob() {
+        let server = Server::start(Options::default()).await;
+        let data = content(13, 10_000);
+        server.write("/a", &data).await;
+        server.write("/b", &data).await;
+        let chunk = chunks_of(&server, "/a").await.remove(0);
+        assert_eq!(chunks_of(&server, "/b").await[0].locator, chunk.locator);
+        server.fs.remove_file(&path("/a")).await.unwrap();
+        server.fs.purge().await.unwrap();
+        assert!(server.drive.get(&chunk.locator).await.is_ok());
+        server.fs.remove_file(&path("/b")).await.unwrap();
+        server.fs.purge().await.unwrap();
+        assert!(server.drive.get(&chunk.locator).await.is_err());
+    }
+}
@@ -0,0 +1,137 @@
+//! Read-only view of the snapshots of the tree, under `/.snapshots/<name>`.
+//!
+//! The files of a snapshot are restored by copying them out of the view.
+
+use webdav_handler::fs::{FsError, FsResult, OpenOptions};
+use crate::types::{DirEntry, File, Metadata, Snapshot};
+use super::{DriveFile, DriveFs, Frozen};
+
+const SNAPSHOTS_DIR: &str = "/.snapshots";
+
+/// Name of the snapshot and path of the tree viewed by a path under `/.snapshots`, without name for `/.snapshots` itself.
+/// `None` if the path is not in the view
+pub(super) fn strip(path: &str) -> Option<(Option<String>, String)> {
+    let rest = path.strip_prefix(SNAPSHOTS_DIR)?;
+    if rest.is_empty() {
+        return Some((None, "/".to_string()))
+    }
+    match rest.strip_prefix('/')?.split_once('/') {
+        Some((name, path)) => Some((Some(name.to_string()), format!("/{}", path))),
+        None => Some((Some(rest[1..].to_string()), "/".to_string()))
+    }
+}
+
+/// Entry of a snapshot as seen in the view
+fn view_entry(snapshot: &Snapshot, mut entry: DirEntry) -> DirEntry {
+    entry.path = match entry.path.as_str() {
+        "/" => format!("{}/{}", SNAPSHOTS_DIR, snapshot.name),
+        path => format!("{}/{}{}", SNAPSHOTS_DIR, snapshot.name, path)
+    };
+    // The root has no date of its own
+    entry.metadata.modified = entry.metadata.modified.or(Some(snapshot.created));
+    entry
+}
+
+impl DriveFs {
+    async fn snapshot_entry(&self, name: String, path: String) -> FsResult<(Snapshot, DirEntry)> {
+        let snapshot = self.db.get_snapshot(name).await?.ok_or(FsError::NotFound)?;
+        let entry = self.db.get_snapshot_entry(snapshot.id, path).await?.ok_or(FsError::NotFound)?;
+        Ok((snapshot, entry))
+    }
+    pub(super) async fn snapshot_metadata(&self, name: Option<String>, path: String) -> FsResult<Metadata> {
+        let Some(name) = name else {
+            return Ok(Metadata { len: 0, modified: None, is_dir: true })
+        };
+        let (snapshot, entry) = self.snapshot_entry(name, path).await?;
+        Ok(view_entry(&snapshot, entry).metadata)
+    }
+    pub(super) async fn read_snapshots_dir(&self, name: Option<String>, path: String) -> FsResult<Vec<DirEntry>> {
+        let Some(name) = name else {
+            let mut roots = Vec::new();
+            for snapshot in self.db.get_snapshots().await? {
+                if let Some(root) = self.db.get_snapshot_entry(snapshot.id, "/".to_string()).await? {
+                    roots.push(view_entry(&snapshot, root));
+                }
+            }
+            return Ok(roots)
+        };
+        let (snapshot, dir) = self.snapshot_entry(name, path).await?;
+        if !dir.metadata.is_dir {
+            return Err(FsError::Forbidden)
+        }
+        Ok(self.db.get_snapshot_entries_by_parent_id(snapshot.id, dir.id).await?
+            .into_iter()
+            .map(|e| view_entry(&snapshot, e))
+            .collect())
+    }
+    /// Open a file of a snapshot, read-only
+    pub(super) async fn open_snapshot(&self, name: Option<String>, path: String, options: OpenOptions) -> FsResult<DriveFile> {
+        if options.write || options.append || options.truncate || options.create || options.create_new {
+            return Err(FsError::Forbidden)
+        }
+        let (snapshot, entry) = self.snapshot_entry(name.ok_or(FsError::Forbidden)?, path).await?;
+        if entry.metadata.is_dir {
+            return Err(FsError::Forbidden)
+        }
+        let mut file = DriveFile::new(File {
+            dir_entry: view_entry(&snapshot, entry),
+            cached: None,
+            cursor_pos: 0
+        }, self.clone());
+        file.frozen = Some(Frozen::Snapshot(snapshot.id));
+        Ok(file)
+    }
+    /// Make the content of a file of a snapshot the content of the file at `to`
+    pub(super) async fn restore_snapshot_file(&self, name: Option<String>, path: String, to: String) -> FsResult<()> {
+        let (snapshot, entry) = self.snapshot_entry(name.ok_or(FsError::Forbidden)?, path).await?;
+        if entry.metadata.is_dir {
+            return Err(FsError::Forbidden)
+        }
+        let chunks = self.db.get_snapshot_chunks(snapshot.id, entry.id).await?;
+        self.restore_content(to, entry.metadata.len, entry.checksum, chunks).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webdav_handler::fs::{DavFileSystem, OpenOptions};
+    use crate::dav::Options;
+    use crate::tests::{path, Server};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn isolation() {
+        let server = Server::start(Options::default()).await;
+        server.fs.create_dir(&path("/dir")).await.unwrap();
+        server.write("/dir/file", b"before").await;
+        server.write("/other", b"other").await;
+        let chunk = server.objects("/dir/file").await[1].clone();
+        assert!(server.db.create_snapshot("s".to_string()).await.unwrap());
+        assert!(!server.db.create_snapshot("s".to_string()).await.unwrap());
+
+        server.write("/dir/file", b"after").await;
+        server.fs.remove_file(&path("/other")).await.unwrap();
+        server.write("/new", b"new").await;
+        server.fs.purge().await.unwrap();
+        server.clear_cache();
+
+        assert_eq!(server.names("/.snapshots").await, vec!["s"]);
+        assert_eq!(server.names("/.snapshots/s").await, vec!["dir", "other"]);
+        assert_eq!(server.read("/.snapshots/s/dir/file").await, b"before");
+        assert_eq!(server.read("/.snapshots/s/other").await, b"other");
+        assert_eq!(server.read("/dir/file").await, b"after");
+        // Read-only
+        assert!(server.fs.open(&path("/.snapshots/s/other"), OpenOptions { write: true, ..Default::default() }).await.is_err());
+
+        server.fs.copy(&path("/.snapshots/s/dir/file"), &path("/dir/file")).await.unwrap();
+        server.fs.copy(&path("/.snapshots/s/other"), &path("/other")).await.unwrap();
+        assert_eq!(server.read("/dir/file").await, b"before");
+        assert_eq!(server.read("/other").await, b"other");
+
+        // The content only the snapshot held is deleted with it
+        server.write("/dir/file", b"after").await;
+        assert!(server.db.remove_snapshot("s".to_string()).await.unwrap());
+        server.fs.purge().await.unwrap();
+        assert!(server.drive.get(&chunk).await.is_err());
+        assert!(server.names("/.snapshots").await.is_empty());
+    }
+}
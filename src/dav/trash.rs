@@ -0,0 +1,221 @@
+//! Entries removed while `Options::trash` is set, under `/.trash`.
+//!
+//! `/.trash/<path>` shows the last entry removed at each path, and the directories under which entries were removed.
+//! Moving an entry out of the view puts it back with its content, removing it from the view deletes it for good.
+
+use webdav_handler::fs::{FsError, FsResult, OpenOptions};
+use crate::types::{DirEntry, File, Metadata, TrashEntry};
+use super::{DriveFile, DriveFs, Frozen};
+
+const TRASH_DIR: &str = "/.trash";
+
+/// Path of the tree viewed by a path under `/.trash`, `None` if the path is not in the view
+pub(super) fn strip(path: &str) -> Option<String> {
+    match path.strip_prefix(TRASH_DIR)? {
+        "" => Some("/".to_string()),
+        p if p.starts_with('/') => Some(p.to_string()),
+        _ => None
+    }
+}
+
+/// Removed entry as seen in the view
+fn view_entry(entry: TrashEntry) -> DirEntry {
+    DirEntry {
+        path: format!("{}{}", TRASH_DIR, entry.path),
+        id: entry.entry_id,
+        parent_id: None,
+        metadata: entry.metadata,
+        locator: None,
+        version: None,
+        checksum: entry.checksum
+    }
+}
+
+/// Directory of the view under which entries were removed
+fn view_dir(path: &str) -> DirEntry {
+    DirEntry {
+        path: format!("{}{}", TRASH_DIR, path),
+        id: 0,
+        parent_id: None,
+        metadata: Metadata { len: 0, modified: None, is_dir: true },
+        locator: None,
+        version: None,
+        checksum: None
+    }
+}
+
+impl DriveFs {
+    async fn trash_node(&self, path: String) -> FsResult<DirEntry> {
+        if let Some(entry) = self.db.get_trash_entry(path.clone()).await? {
+            return Ok(view_entry(entry))
+        }
+        if path == "/" || self.db.has_trash_entries(path.clone()).await? {
+            return Ok(view_dir(&path))
+        }
+        Err(FsError::NotFound)
+    }
+    pub(super) async fn trash_metadata(&self, path: String) -> FsResult<Metadata> {
+        Ok(self.trash_node(path).await?.metadata)
+    }
+    pub(super) async fn read_trash_dir(&self, path: String) -> FsResult<Vec<DirEntry>> {
+        if !self.trash_node(path.clone()).await?.metadata.is_dir {
+            return Err(FsError::Forbidden)
+        }
+        let mut entries = Vec::new();
+        for path in self.db.get_trash_paths(path).await? {
+            entries.push(self.trash_node(path).await?);
+        }
+        Ok(entries)
+    }
+    /// Open a removed file, read-only
+    pub(super) async fn open_trash(&self, path: String, options: OpenOptions) -> FsResult<DriveFile> {
+        if options.write || options.append || options.truncate || options.create || options.create_new {
+            return Err(FsError::Forbidden)
+        }
+        let entry = self.db.get_trash_entry(path).await?.ok_or(FsError::NotFound)?;
+        if entry.metadata.is_dir {
+            return Err(FsError::Forbidden)
+        }
+        let id = entry.id;
+        let mut file = DriveFile::new(File {
+            dir_entry: view_entry(entry),
+            cached: None,
+            cursor_pos: 0
+        }, self.clone());
+        file.frozen = Some(Frozen::Trash(id));
+        Ok(file)
+    }
+    /// Put back the entries removed at `from` and under it, at `to`
+    pub(super) async fn restore_trash(&self, from: String, to: String) -> FsResult<()> {
+        if from == "/" {
+            return Err(FsError::Forbidden)
+        }
+        if !self.db.has_trash_entries(from.clone()).await? {
+            return Err(FsError::NotFound)
+        }
+        if self.db.get_dir_entry_by_path(to.clone()).await?.is_some() {
+            return Err(FsError::Exists)
+        }
+        if self.parent(&to).await?.is_none() {
+            return Err(FsError::Forbidden)
+        }
+        self.db.restore_trash(from, to.clone()).await?;
+        // The objects hold the path of the entries and are marked as trashed
+        self.db.add_outdated_entries(to).await?;
+        self.spawn_send_outdated();
+        Ok(())
+    }
+    /// Delete for good the entries removed at a path and under it
+    pub(super) async fn delete_trash(&self, path: String) -> FsResult<()> {
+        self.db.delete_trash_entries(path).await?;
+        self.spawn_purge();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use webdav_handler::fs::{DavFileSystem, FsError, OpenOptions};
+    use crate::dav::Options;
+    use crate::index;
+    use crate::tests::{path, Server};
+    use crate::types::EntryMeta;
+
+    fn options(trash: Duration) -> Options {
+        Options { trash: Some(trash), ..Default::default() }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn restore() {
+        let server = Server::start(options(Duration::from_secs(3600))).await;
+        server.fs.create_dir(&path("/dir")).await.unwrap();
+        server.write("/dir/file", b"hello").await;
+        let objects = server.objects("/dir/file").await;
+        server.fs.remove_file(&path("/dir/file")).await.unwrap();
+        server.fs.remove_dir(&path("/dir")).await.unwrap();
+        server.fs.purge().await.unwrap();
+        assert!(server.names("/").await.is_empty());
+        assert_eq!(server.names("/.trash").await, vec!["dir"]);
+        assert_eq!(server.names("/.trash/dir").await, vec!["file"]);
+        server.clear_cache();
+        assert_eq!(server.read("/.trash/dir/file").await, b"hello");
+
+        server.fs.rename(&path("/.trash/dir"), &path("/dir")).await.unwrap();
+        assert!(server.names("/.trash").await.is_empty());
+        assert_eq!(server.read("/dir/file").await, b"hello");
+        assert_eq!(server.objects("/dir/file").await, objects);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn expiry() {
+        let server = Server::start(options(Duration::ZERO)).await;
+        server.write("/file", b"hello").await;
+        let objects = server.objects("/file").await;
+        server.fs.remove_file(&path("/file")).await.unwrap();
+        server.fs.purge().await.unwrap();
+        assert!(server.names("/.trash").await.is_empty());
+        for locator in objects {
+            assert!(server.drive.get(&locator).await.is_err());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn delete_for_good() {
+        let server = Server::start(options(Duration::from_secs(3600))).await;
+        server.write("/file", b"hello").await;
+        let objects = server.objects("/file").await;
+        server.fs.remove_file(&path("/file")).await.unwrap();
+        server.fs.purge().await.unwrap();
+        // Not expired yet
+        for locator in &objects {
+            assert!(server.drive.get(locator).await.is_ok());
+        }
+        server.fs.remove_file(&path("/.trash/file")).await.unwrap();
+        server.fs.purge().await.unwrap();
+        assert!(server.names("/.trash").await.is_empty());
+        for locator in &objects {
+            assert!(server.drive.get(locator).await.is_err());
+        }
+    }
+
+    /// Whether the object of an entry is marked as trashed
+    async fn is_marked(server: &Server, locator: &str) -> bool {
+        let (meta, _) = server.drive.get_meta(locator).await.unwrap();
+        serde_json::from_str::<EntryMeta>(&meta).unwrap().trashed.is_some()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn marked_in_the_drive() {
+        let server = Server::start(options(Duration::from_secs(3600))).await;
+        server.fs.create_dir(&path("/dir")).await.unwrap();
+        server.write("/dir/file", b"hello").await;
+        let entry = server.objects("/dir/file").await[0].clone();
+        // Another server sharing the drive
+        let other = Server::with_drive(server.url.clone(), server.drive.clone(), Options::default()).await;
+        index::rebuild(&other.db, other.drive.as_ref()).await.unwrap();
+        assert_eq!(other.read("/dir/file").await, b"hello");
+
+        server.fs.remove_file(&path("/dir/file")).await.unwrap();
+        assert!(is_marked(&server, &entry).await);
+        let opened = other.fs.open(&path("/dir/file"), OpenOptions { read: true, ..Default::default() }).await;
+        assert!(matches!(opened, Err(FsError::NotFound)));
+        assert!(other.names("/dir").await.is_empty());
+
+        // The index rebuilt from the drive puts it back in the trash
+        let rebuilt = Server::with_drive(server.url.clone(), server.drive.clone(), options(Duration::from_secs(3600))).await;
+        let report = index::rebuild(&rebuilt.db, rebuilt.drive.as_ref()).await.unwrap();
+        // The directories are not stored in the drive
+        assert_eq!((report.entries, report.trashed), (0, 1));
+        assert!(rebuilt.names("/").await.is_empty());
+        assert_eq!(rebuilt.names("/.trash/dir").await, vec!["file"]);
+        assert_eq!(rebuilt.read("/.trash/dir/file").await, b"hello");
+
+        // Restored, the mark is removed
+        server.fs.rename(&path("/.trash/dir/file"), &path("/dir/file")).await.unwrap();
+        server.fs.send_outdated().await.unwrap();
+        assert!(!is_marked(&server, &entry).await);
+        index::rebuild(&other.db, other.drive.as_ref()).await.unwrap();
+        assert_eq!(other.read("/dir/file").await, b"hello");
+    }
+}
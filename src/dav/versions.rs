@@ -0,0 +1,193 @@
+//! Read-only view of the versions of the files, under `/.versions`.
+//!
+//! `/.versions/<path>` mirrors the tree: every directory lists its entries, and the files and directories removed since they had versions,
+//! and every file is a directory listing its versions by id. A version is restored by copying it out of the view.
+
+use std::path::Path;
+use webdav_handler::fs::{FsError, FsResult, OpenOptions};
+use crate::types::{DirEntry, File, FileVersion, Metadata};
+use super::{DriveFile, DriveFs, Frozen};
+
+const VERSIONS_DIR: &str = "/.versions";
+
+/// Path of the tree viewed by a path under `/.versions`, `None` if the path is not in the view
+pub(super) fn strip(path: &str) -> Option<String> {
+    match path.strip_prefix(VERSIONS_DIR)? {
+        "" => Some("/".to_string()),
+        p if p.starts_with('/') => Some(p.to_string()),
+        _ => None
+    }
+}
+
+/// Directory of the view
+fn view_dir(path: &str) -> DirEntry {
+    DirEntry {
+        path: format!("{}{}", VERSIONS_DIR, path),
+        id: 0,
+        parent_id: None,
+        metadata: Metadata { len: 0, modified: None, is_dir: true },
+        locator: None,
+        version: None,
+        checksum: None
+    }
+}
+
+/// What a path of the tree designates in the view
+enum Node {
+    /// A directory or a file of the tree, existing or removed
+    Dir(String),
+    Version(FileVersion)
+}
+
+impl DriveFs {
+    async fn version_node(&self, path: &str) -> FsResult<Node> {
+        if self.db.get_dir_entry_by_path(path.to_string()).await?.is_some() || self.db.has_file_versions(path.to_string()).await? {
+            return Ok(Node::Dir(path.to_string()))
+        }
+        let (Some(parent), Some(name)) = (Path::new(path).parent(), Path::new(path).file_name()) else {
+            return Err(FsError::NotFound)
+        };
+        let id = name.to_str().and_then(|n| n.parse().ok()).ok_or(FsError::NotFound)?;
+        match self.db.get_file_version(id).await? {
+            Some(version) if Path::new(&version.path) == parent => Ok(Node::Version(version)),
+            _ => Err(FsError::NotFound)
+        }
+    }
+    pub(super) async fn version_metadata(&self, path: &str) -> FsResult<Metadata> {
+        match self.version_node(path).await? {
+            Node::Dir(path) => Ok(view_dir(&path).metadata),
+            Node::Version(version) => Ok(version.metadata)
+        }
+    }
+    pub(super) async fn read_versions_dir(&self, path: &str) -> FsResult<Vec<DirEntry>> {
+        let Node::Dir(path) = self.version_node(path).await? else {
+            return Err(FsError::Forbidden)
+        };
+        let dir = self.db.get_dir_entry_by_path(path.clone()).await?;
+        let mut entries: Vec<DirEntry> = match &dir {
+            Some(dir) if dir.metadata.is_dir => self.db.get_dir_entries_by_parent_id(dir.id).await?
+                .iter()
+                .map(|e| view_dir(&e.path))
+                .collect(),
+            // A file, or an entry that was removed
+            _ => self.db.get_file_versions(path.clone()).await?.into_iter().map(|v| DirEntry {
+                path: format!("{}{}/{}", VERSIONS_DIR, v.path, v.id),
+                id: v.entry_id.unwrap_or_default(),
+                parent_id: None,
+                metadata: v.metadata,
+                locator: None,
+                version: None,
+                checksum: v.checksum
+            }).collect()
+        };
+        if dir.is_none_or(|d| d.metadata.is_dir) {
+            entries.extend(self.db.get_removed_versioned_paths(path).await?.iter().map(|p| view_dir(p)));
+        }
+        Ok(entries)
+    }
+    /// Open a version, read-only
+    pub(super) async fn open_version(&self, path: &str, options: OpenOptions) -> FsResult<DriveFile> {
+        if options.write || options.append || options.truncate || options.create || options.create_new {
+            return Err(FsError::Forbidden)
+        }
+        let Node::Version(version) = self.version_node(path).await? else {
+            return Err(FsError::Forbidden)
+        };
+        let mut file = DriveFile::new(File {
+            dir_entry: DirEntry {
+                path: format!("{}{}", VERSIONS_DIR, path),
+                // The entries are never 0, which names the versions of the removed ones in the cache
+                id: version.entry_id.unwrap_or_default(),
+                parent_id: None,
+                metadata: version.metadata,
+                locator: None,
+                version: None,
+                checksum: version.checksum
+            },
+            cached: None,
+            cursor_pos: 0
+        }, self.clone());
+        file.frozen = Some(Frozen::Version(version.id));
+        Ok(file)
+    }
+    /// Make a version the content of the file at `to`. The current content stays in the versions
+    pub(super) async fn restore_version(&self, from: &str, to: String) -> FsResult<()> {
+        let Node::Version(version) = self.version_node(from).await? else {
+            return Err(FsError::Forbidden)
+        };
+        let chunks = self.db.get_version_chunks(version.id).await?;
+        self.restore_content(to, version.metadata.len, version.checksum, chunks).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use webdav_handler::fs::DavFileSystem;
+    use crate::dav::Options;
+    use crate::tests::{path, Server};
+
+    fn options(keep_versions: Option<usize>, versions_max_age: Option<Duration>) -> Options {
+        Options { versioning: true, keep_versions, versions_max_age, ..Default::default() }
+    }
+
+    /// Ids of the versions of a file, oldest first
+    async fn versions(server: &Server, of: &str) -> Vec<String> {
+        let mut ids = server.names(&format!("/.versions{}", of)).await;
+        ids.sort_by_key(|id| id.parse::<usize>().unwrap());
+        ids
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn keep_versions() {
+        let server = Server::start(options(Some(2), None)).await;
+        server.write("/file", b"first").await;
+        let first = server.objects("/file").await[1].clone();
+        server.write("/file", b"second").await;
+        server.write("/file", b"third").await;
+        let ids = versions(&server, "/file").await;
+        assert_eq!(ids.len(), 2);
+        assert_eq!(server.read(&format!("/.versions/file/{}", ids[0])).await, b"second");
+        assert_eq!(server.read(&format!("/.versions/file/{}", ids[1])).await, b"third");
+        // The chunk of the first content is not referenced anymore
+        server.fs.purge().await.unwrap();
+        assert!(server.drive.get(&first).await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn versions_max_age() {
+        let server = Server::start(options(None, Some(Duration::ZERO))).await;
+        server.write("/file", b"first").await;
+        server.write("/file", b"second").await;
+        // Only the current content is kept
+        let ids = versions(&server, "/file").await;
+        assert_eq!(ids.len(), 1);
+        assert_eq!(server.read(&format!("/.versions/file/{}", ids[0])).await, b"second");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn restore() {
+        let server = Server::start(options(None, None)).await;
+        server.fs.create_dir(&path("/dir")).await.unwrap();
+        server.write("/dir/file", b"first").await;
+        server.write("/dir/file", b"second").await;
+        let ids = versions(&server, "/dir/file").await;
+        let first = format!("/.versions/dir/file/{}", ids[0]);
+        // Read-only
+        assert!(server.fs.remove_file(&path(&first)).await.is_err());
+
+        server.fs.copy(&path(&first), &path("/dir/file")).await.unwrap();
+        assert_eq!(server.read("/dir/file").await, b"first");
+        // The replaced content is still a version
+        assert_eq!(versions(&server, "/dir/file").await.len(), 3);
+        server.fs.copy(&path(&first), &path("/restored")).await.unwrap();
+        server.clear_cache();
+        assert_eq!(server.read("/restored").await, b"first");
+
+        // The versions of a removed file stay in the view
+        server.fs.remove_file(&path("/dir/file")).await.unwrap();
+        assert_eq!(server.names("/.versions/dir").await, vec!["file"]);
+        server.fs.copy(&path(&first), &path("/dir/file")).await.unwrap();
+        assert_eq!(server.read("/dir/file").await, b"first");
+    }
+}
@@ -1,6 +1,250 @@
-use rusqlite::{OptionalExtension, params};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use rusqlite::{DatabaseName, OptionalExtension, params, Row, Transaction};
 use tokio_rusqlite::Connection;
-use crate::{error::{Result, Error}, types::{Metadata, File, DirEntry}};
+use chrono::{DateTime, Utc};
+use crate::{error::Result, types::{Metadata, File, DirEntry, Chunk, CacheEntry, IndexedEntry, FileVersion, Snapshot, TrashEntry}};
+use crate::drives::replicas;
+
+/// Locators of the object and the chunks of an entry
+fn objects_of(tx: &Transaction, id: usize) -> rusqlite::Result<Vec<String>> {
+    let mut locators = tx.prepare("SELECT locator FROM chunks WHERE entry_id = ?1")?
+        .query_map([id], |row| row.get(0))?
+        .collect::<std::result::Result<Vec<String>, rusqlite::Error>>()?;
+    let locator: Option<String> = tx.query_row("SELECT discord_msg_id FROM dir_entries WHERE id = ?1", [id], |row| row.get(0)).optional()?.flatten();
+    locators.extend(locator);
+    Ok(locators)
+}
+
+/// Queue the objects that are not referenced by any entry anymore in `pending_deletions`.
+/// The object of a blob is kept, it can be referenced by chunks that were stored in a duplicate of it.
+/// The blobs that are not referenced anymore must be forgotten before
+fn release(tx: &Transaction, locators: Vec<String>) -> rusqlite::Result<()> {
+    for locator in locators {
+        tx.execute("
+            INSERT OR IGNORE INTO pending_deletions (locator)
+            SELECT ?1
+            WHERE NOT EXISTS (SELECT 1 FROM chunks WHERE locator = ?1)
+            AND NOT EXISTS (SELECT 1 FROM version_chunks WHERE locator = ?1)
+            AND NOT EXISTS (SELECT 1 FROM snapshot_chunks WHERE locator = ?1)
+            AND NOT EXISTS (SELECT 1 FROM trash_chunks WHERE locator = ?1)
+            AND NOT EXISTS (SELECT 1 FROM dir_entries WHERE discord_msg_id = ?1)
+            AND NOT EXISTS (SELECT 1 FROM trash_entries WHERE discord_msg_id = ?1)
+            AND NOT EXISTS (SELECT 1 FROM blobs WHERE locator = ?1)
+        ", [locator])?;
+    }
+    Ok(())
+}
+
+/// Add `delta` times the number of chunks of an entry to the reference counts of their blobs
+fn add_references(tx: &Transaction, id: usize, delta: i64) -> rusqlite::Result<()> {
+    tx.execute("
+        UPDATE blobs
+        SET refcount = refcount + ?2 * (SELECT COUNT(*) FROM chunks WHERE entry_id = ?1 AND chunks.hash = blobs.hash)
+        WHERE hash IN (SELECT hash FROM chunks WHERE entry_id = ?1)
+    ", params![id, delta])?;
+    Ok(())
+}
+
+/// Insert the chunks of an entry and reference their blobs
+fn insert_chunks(tx: &Transaction, id: usize, chunks: Vec<Chunk>) -> rusqlite::Result<()> {
+    for (idx, chunk) in chunks.into_iter().enumerate() {
+        tx.execute("
+            INSERT INTO chunks (entry_id, idx, locator, size, compressed, hash)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+        ", params![id, idx, chunk.locator, chunk.size, chunk.compressed, chunk.hash])?;
+        if let Some(hash) = &chunk.hash {
+            tx.execute("
+                INSERT OR IGNORE INTO blobs (hash, locator, size, compressed)
+                VALUES (?1, ?2, ?3, ?4)
+            ", params![hash, chunk.locator, chunk.size, chunk.compressed])?;
+        }
+        // Used again before being purged
+        tx.execute("DELETE FROM pending_deletions WHERE locator = ?1", [&chunk.locator])?;
+    }
+    add_references(tx, id, 1)
+}
+
+/// Insert the chunks of a removed entry and reference their blobs
+fn insert_trash_chunks(tx: &Transaction, trash_id: usize, chunks: Vec<Chunk>) -> rusqlite::Result<()> {
+    for (idx, chunk) in chunks.into_iter().enumerate() {
+        tx.execute("
+            INSERT INTO trash_chunks (trash_id, idx, locator, size, compressed, hash)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+        ", params![trash_id, idx, chunk.locator, chunk.size, chunk.compressed, chunk.hash])?;
+        if let Some(hash) = &chunk.hash {
+            tx.execute("
+                INSERT OR IGNORE INTO blobs (hash, locator, size, compressed)
+                VALUES (?1, ?2, ?3, ?4)
+            ", params![hash, chunk.locator, chunk.size, chunk.compressed])?;
+        }
+        tx.execute("DELETE FROM pending_deletions WHERE locator = ?1", [&chunk.locator])?;
+    }
+    add_trash_references(tx, trash_id, 1)
+}
+
+/// Remove the chunks of an entry, the blobs that are not referenced anymore are forgotten and their objects must be released
+fn remove_chunks(tx: &Transaction, id: usize) -> rusqlite::Result<()> {
+    add_references(tx, id, -1)?;
+    tx.execute("DELETE FROM blobs WHERE refcount <= 0", ())?;
+    tx.execute("DELETE FROM chunks WHERE entry_id = ?1", [id])?;
+    Ok(())
+}
+
+/// Add `delta` times the number of chunks of a version to the reference counts of their blobs
+fn add_version_references(tx: &Transaction, version_id: usize, delta: i64) -> rusqlite::Result<()> {
+    tx.execute("
+        UPDATE blobs
+        SET refcount = refcount + ?2 * (SELECT COUNT(*) FROM version_chunks WHERE version_id = ?1 AND version_chunks.hash = blobs.hash)
+        WHERE hash IN (SELECT hash FROM version_chunks WHERE version_id = ?1)
+    ", params![version_id, delta])?;
+    Ok(())
+}
+
+/// Remove versions and their chunks, their objects are released
+fn remove_versions(tx: &Transaction, ids: Vec<usize>) -> rusqlite::Result<()> {
+    for id in ids {
+        let locators = tx.prepare("SELECT locator FROM version_chunks WHERE version_id = ?1")?
+            .query_map([id], |row| row.get(0))?
+            .collect::<std::result::Result<Vec<String>, rusqlite::Error>>()?;
+        add_version_references(tx, id, -1)?;
+        tx.execute("DELETE FROM blobs WHERE refcount <= 0", ())?;
+        tx.execute("DELETE FROM version_chunks WHERE version_id = ?1", [id])?;
+        tx.execute("DELETE FROM file_versions WHERE id = ?1", [id])?;
+        release(tx, locators)?;
+    }
+    Ok(())
+}
+
+/// Add `delta` times the number of chunks of a snapshot to the reference counts of their blobs
+fn add_snapshot_references(tx: &Transaction, snapshot_id: usize, delta: i64) -> rusqlite::Result<()> {
+    tx.execute("
+        UPDATE blobs
+        SET refcount = refcount + ?2 * (SELECT COUNT(*) FROM snapshot_chunks WHERE snapshot_id = ?1 AND snapshot_chunks.hash = blobs.hash)
+        WHERE hash IN (SELECT hash FROM snapshot_chunks WHERE snapshot_id = ?1)
+    ", params![snapshot_id, delta])?;
+    Ok(())
+}
+
+/// Add `delta` times the number of chunks of a removed entry to the reference counts of their blobs
+fn add_trash_references(tx: &Transaction, trash_id: usize, delta: i64) -> rusqlite::Result<()> {
+    tx.execute("
+        UPDATE blobs
+        SET refcount = refcount + ?2 * (SELECT COUNT(*) FROM trash_chunks WHERE trash_id = ?1 AND trash_chunks.hash = blobs.hash)
+        WHERE hash IN (SELECT hash FROM trash_chunks WHERE trash_id = ?1)
+    ", params![trash_id, delta])?;
+    Ok(())
+}
+
+/// Delete removed entries from the trash, their objects are released
+fn remove_trash_entries(tx: &Transaction, ids: Vec<usize>) -> rusqlite::Result<()> {
+    for id in ids {
+        let mut locators = tx.prepare("SELECT locator FROM trash_chunks WHERE trash_id = ?1")?
+            .query_map([id], |row| row.get(0))?
+            .collect::<std::result::Result<Vec<String>, rusqlite::Error>>()?;
+        let locator: Option<String> = tx.query_row("SELECT discord_msg_id FROM trash_entries WHERE id = ?1", [id], |row| row.get(0))?;
+        locators.extend(locator);
+        add_trash_references(tx, id, -1)?;
+        tx.execute("DELETE FROM blobs WHERE refcount <= 0", ())?;
+        tx.execute("DELETE FROM trash_chunks WHERE trash_id = ?1", [id])?;
+        tx.execute("DELETE FROM trash_entries WHERE id = ?1", [id])?;
+        release(tx, locators)?;
+    }
+    Ok(())
+}
+
+/// Id of the directory at `path`, created if only entries under it were restored from the trash
+fn restored_dir(tx: &Transaction, path: &str, ids: &mut HashMap<String, usize>) -> rusqlite::Result<usize> {
+    if let Some(id) = ids.get(path) {
+        return Ok(*id)
+    }
+    if let Some(id) = tx.query_row("SELECT id FROM dir_entries WHERE path = ?1", [path], |row| row.get(0)).optional()? {
+        return Ok(id)
+    }
+    let parent = std::path::Path::new(path).parent().and_then(|p| p.to_str()).unwrap_or("/");
+    let parent_id = restored_dir(tx, parent, ids)?;
+    tx.execute("
+        INSERT INTO dir_entries (parent_id, path, meta_len, meta_modified, meta_is_dir)
+        VALUES (?1, ?2, 0, ?3, TRUE)
+    ", params![parent_id, path, Utc::now()])?;
+    let id = tx.last_insert_rowid() as usize;
+    ids.insert(path.to_string(), id);
+    Ok(id)
+}
+
+/// Add a column to a table created by an older version
+fn add_column(conn: &rusqlite::Connection, table: &str, column: &str, definition: &str) -> rusqlite::Result<()> {
+    let exists: bool = conn.query_row("
+        SELECT EXISTS (SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2)
+    ", [table, column], |row| row.get(0))?;
+    if !exists {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), ())?;
+    }
+    Ok(())
+}
+
+fn cache_entry_from_row(row: &Row) -> rusqlite::Result<CacheEntry> {
+    Ok(CacheEntry {
+        name: row.get(0)?,
+        entry_id: row.get(1)?,
+        size: row.get(2)?,
+        last_access: row.get(3)?,
+        dirty: row.get(4)?
+    })
+}
+
+fn file_version_from_row(row: &Row) -> rusqlite::Result<FileVersion> {
+    Ok(FileVersion {
+        id: row.get(0)?,
+        entry_id: row.get(1)?,
+        path: row.get(2)?,
+        metadata: Metadata {
+            len: row.get(3)?,
+            modified: row.get(4).ok(),
+            is_dir: false
+        },
+        checksum: row.get(5)?
+    })
+}
+
+fn snapshot_from_row(row: &Row) -> rusqlite::Result<Snapshot> {
+    Ok(Snapshot {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        created: row.get(2)?
+    })
+}
+
+fn trash_entry_from_row(row: &Row) -> rusqlite::Result<TrashEntry> {
+    Ok(TrashEntry {
+        id: row.get(0)?,
+        entry_id: row.get(1)?,
+        path: row.get(2)?,
+        metadata: Metadata {
+            len: row.get(3)?,
+            modified: row.get(4).ok(),
+            is_dir: row.get(5)?
+        },
+        checksum: row.get(6)?,
+        deleted: row.get(7)?
+    })
+}
+
+fn dir_entry_from_row(row: &Row) -> rusqlite::Result<DirEntry> {
+    Ok(DirEntry {
+        id: row.get(0)?,
+        parent_id: row.get(1)?,
+        path: row.get(2)?,
+        metadata: Metadata {
+            len: row.get(3)?,
+            modified: row.get(4).ok(),
+            is_dir: row.get(5)?
+        },
+        locator: row.get(6)?,
+        version: row.get(7)?,
+        checksum: row.get(8)?
+    })
+}
 
 #[derive(Debug)]
 pub struct DB {
@@ -26,14 +270,189 @@ impl DB {
                     meta_len INTEGER NOT NULL,
                     meta_modified TEXT,
                     meta_is_dir BOOLEAN NOT NULL,
-                    discord_msg_id TEXT UNIQUE
+                    -- locator of the entry in the drive
+                    discord_msg_id TEXT UNIQUE,
+                    -- version of the object of the entry
+                    version TEXT,
+                    -- BLAKE3 hash of the content of the file
+                    checksum TEXT
+                )
+            ", ())?;
+            add_column(conn, "dir_entries", "version", "TEXT")?;
+            add_column(conn, "dir_entries", "checksum", "TEXT")?;
+            conn.execute("
+                CREATE TABLE IF NOT EXISTS chunks (
+                    entry_id INTEGER NOT NULL REFERENCES dir_entries(id) ON DELETE CASCADE,
+                    idx INTEGER NOT NULL,
+                    locator TEXT NOT NULL,
+                    size INTEGER NOT NULL,
+                    -- the object is compressed with zstd
+                    compressed BOOLEAN NOT NULL DEFAULT FALSE,
+                    -- hash of the content, NULL if the object is not shared through blobs
+                    hash TEXT,
+                    PRIMARY KEY (entry_id, idx)
+                )
+            ", ())?;
+            add_column(conn, "chunks", "compressed", "BOOLEAN NOT NULL DEFAULT FALSE")?;
+            add_column(conn, "chunks", "hash", "TEXT")?;
+            conn.execute("
+                CREATE TABLE IF NOT EXISTS blobs (
+                    hash TEXT PRIMARY KEY,
+                    locator TEXT NOT NULL,
+                    size INTEGER NOT NULL,
+                    compressed BOOLEAN NOT NULL DEFAULT FALSE,
+                    -- number of chunks, version chunks, snapshot chunks and trash chunks with this hash
+                    refcount INTEGER NOT NULL DEFAULT 0
+                )
+            ", ())?;
+            conn.execute("
+                CREATE TABLE IF NOT EXISTS file_versions (
+                    -- never reused, the versions are cached by id
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    -- NULL once the entry is removed, its versions are kept
+                    entry_id INTEGER,
+                    -- path of the entry, followed when it is moved
+                    path TEXT NOT NULL,
+                    meta_len INTEGER NOT NULL,
+                    meta_modified TEXT,
+                    checksum TEXT,
+                    -- when the version was saved
+                    created TEXT NOT NULL
+                )
+            ", ())?;
+            conn.execute("CREATE INDEX IF NOT EXISTS file_versions_path ON file_versions (path)", ())?;
+            conn.execute("
+                CREATE TABLE IF NOT EXISTS version_chunks (
+                    version_id INTEGER NOT NULL REFERENCES file_versions(id) ON DELETE CASCADE,
+                    idx INTEGER NOT NULL,
+                    locator TEXT NOT NULL,
+                    size INTEGER NOT NULL,
+                    compressed BOOLEAN NOT NULL DEFAULT FALSE,
+                    hash TEXT,
+                    PRIMARY KEY (version_id, idx)
+                )
+            ", ())?;
+            conn.execute("
+                CREATE TABLE IF NOT EXISTS snapshots (
+                    -- never reused, the files of the snapshots are cached by id
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    name TEXT NOT NULL UNIQUE,
+                    created TEXT NOT NULL
+                )
+            ", ())?;
+            conn.execute("
+                CREATE TABLE IF NOT EXISTS snapshot_entries (
+                    snapshot_id INTEGER NOT NULL REFERENCES snapshots(id) ON DELETE CASCADE,
+                    -- id of the entry when the snapshot was created
+                    id INTEGER NOT NULL,
+                    parent_id INTEGER,
+                    path TEXT NOT NULL,
+                    meta_len INTEGER NOT NULL,
+                    meta_modified TEXT,
+                    meta_is_dir BOOLEAN NOT NULL,
+                    checksum TEXT,
+                    PRIMARY KEY (snapshot_id, id)
+                )
+            ", ())?;
+            conn.execute("CREATE UNIQUE INDEX IF NOT EXISTS snapshot_entries_path ON snapshot_entries (snapshot_id, path)", ())?;
+            conn.execute("
+                CREATE TABLE IF NOT EXISTS snapshot_chunks (
+                    snapshot_id INTEGER NOT NULL REFERENCES snapshots(id) ON DELETE CASCADE,
+                    entry_id INTEGER NOT NULL,
+                    idx INTEGER NOT NULL,
+                    locator TEXT NOT NULL,
+                    size INTEGER NOT NULL,
+                    compressed BOOLEAN NOT NULL DEFAULT FALSE,
+                    hash TEXT,
+                    PRIMARY KEY (snapshot_id, entry_id, idx)
+                )
+            ", ())?;
+            conn.execute("
+                CREATE TABLE IF NOT EXISTS trash_entries (
+                    -- never reused, the removed files are cached by id
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    -- id of the entry before it was removed
+                    entry_id INTEGER NOT NULL,
+                    -- path of the entry before it was removed, several removed entries can share it
+                    path TEXT NOT NULL,
+                    meta_len INTEGER NOT NULL,
+                    meta_modified TEXT,
+                    meta_is_dir BOOLEAN NOT NULL,
+                    -- the object of the entry is kept until it is deleted from the trash
+                    discord_msg_id TEXT,
+                    version TEXT,
+                    checksum TEXT,
+                    deleted TEXT NOT NULL
+                )
+            ", ())?;
+            conn.execute("CREATE INDEX IF NOT EXISTS trash_entries_path ON trash_entries (path)", ())?;
+            conn.execute("
+                CREATE TABLE IF NOT EXISTS trash_chunks (
+                    trash_id INTEGER NOT NULL REFERENCES trash_entries(id) ON DELETE CASCADE,
+                    idx INTEGER NOT NULL,
+                    locator TEXT NOT NULL,
+                    size INTEGER NOT NULL,
+                    compressed BOOLEAN NOT NULL DEFAULT FALSE,
+                    hash TEXT,
+                    PRIMARY KEY (trash_id, idx)
+                )
+            ", ())?;
+            conn.execute("
+                CREATE TABLE IF NOT EXISTS pending_deletions (
+                    locator TEXT PRIMARY KEY
+                )
+            ", ())?;
+            // Entries whose object must be sent again, because they moved or their chunks are stored elsewhere
+            conn.execute("
+                CREATE TABLE IF NOT EXISTS outdated_entries (
+                    entry_id INTEGER PRIMARY KEY REFERENCES dir_entries(id) ON DELETE CASCADE
+                )
+            ", ())?;
+            conn.execute("
+                CREATE TABLE IF NOT EXISTS cache_entries (
+                    name TEXT PRIMARY KEY,
+                    entry_id INTEGER NOT NULL,
+                    size INTEGER NOT NULL,
+                    last_access INTEGER NOT NULL,
+                    dirty BOOLEAN NOT NULL DEFAULT FALSE
                 )
             ", ())?;
+            // Files sent before chunking hold their content in their own object, which becomes their only chunk.
+            // The files with unsent changes are left to their next upload
+            conn.execute("
+                INSERT INTO chunks (entry_id, idx, locator, size)
+                SELECT id, 0, discord_msg_id, meta_len
+                FROM dir_entries
+                WHERE discord_msg_id IS NOT NULL AND NOT meta_is_dir AND meta_len > 0
+                AND NOT EXISTS (SELECT 1 FROM chunks WHERE entry_id = dir_entries.id)
+                AND NOT EXISTS (SELECT 1 FROM cache_entries WHERE entry_id = dir_entries.id AND dirty)
+            ", ())?;
 
             Ok(())
         }).await.expect("Failed to create tables");
     }
 
+    /// Number of rows changed since the database was opened
+    pub async fn total_changes(&self) -> Result<u64> {
+        Ok(self.conn.call(|conn| {
+            conn.query_row("SELECT total_changes()", [], |row| row.get(0))
+        }).await?)
+    }
+    /// Copy the database to a file while it is used
+    pub async fn backup(&self, path: PathBuf) -> Result<()> {
+        self.conn.call(move |conn| {
+            conn.backup(DatabaseName::Main, path, None)
+        }).await?;
+        Ok(())
+    }
+    /// Replace the content of the database by the one of a backup
+    pub async fn restore(&self, path: PathBuf) -> Result<()> {
+        self.conn.call(move |conn| {
+            conn.restore(DatabaseName::Main, path, None::<fn(rusqlite::backup::Progress)>)
+        }).await?;
+        Ok(())
+    }
+
     // files
     pub async fn insert_dir_entry(&self, parent_id: Option<usize>, path: String, metadata: Metadata) -> Result<()> {
         self.conn.call(move |conn| {
@@ -47,41 +466,19 @@ impl DB {
     pub async fn get_dir_entry_by_path(&self, path: String) -> Result<Option<DirEntry>> {
         Ok(self.conn.call(move |conn| {
             conn.query_row("
-                SELECT id, parent_id, path, meta_len, meta_modified, meta_is_dir
+                SELECT id, parent_id, path, meta_len, meta_modified, meta_is_dir, discord_msg_id, version, checksum
                 FROM dir_entries
                 WHERE path = ?1
-            ", [path], |row| {
-                Ok(DirEntry {
-                    id: row.get(0)?,
-                    parent_id: row.get(1)?,
-                    path: row.get(2)?,
-                    metadata: Metadata {
-                        len: row.get(3)?,
-                        modified: row.get(4).ok(),
-                        is_dir: row.get(5)?
-                    }
-                })
-            }).optional()
+            ", [path], dir_entry_from_row).optional()
         }).await?)
     }
     pub async fn get_dir_entry_by_id(&self, id: usize) -> Result<Option<DirEntry>> {
         Ok(self.conn.call(move |conn| {
             conn.query_row("
-                SELECT id, parent_id, path, meta_len, meta_modified, meta_is_dir
+                SELECT id, parent_id, path, meta_len, meta_modified, meta_is_dir, discord_msg_id, version, checksum
                 FROM dir_entries
                 WHERE id = ?1
-            ", [id], |row| {
-                Ok(DirEntry {
-                    id: row.get(0)?,
-                    parent_id: row.get(1)?,
-                    path: row.get(2)?,
-                    metadata: Metadata {
-                        len: row.get(3)?,
-                        modified: row.get(4).ok(),
-                        is_dir: row.get(5)?
-                    }
-                })
-            }).optional()
+            ", [id], dir_entry_from_row).optional()
         }).await?)
     }
     pub async fn get_file_by_path(&self, path: String) -> Result<Option<File>> {
@@ -105,26 +502,832 @@ impl DB {
         }))
     }
     pub async fn get_dir_entries_by_parent_id(&self, parent_id: usize) -> Result<Vec<DirEntry>> {
-        self.conn.call(move |conn| {
+        Ok(self.conn.call(move |conn| {
             let mut stmt = conn.prepare("
-                SELECT id, parent_id, path, meta_len, meta_modified, meta_is_dir
+                SELECT id, parent_id, path, meta_len, meta_modified, meta_is_dir, discord_msg_id, version, checksum
                 FROM dir_entries
                 WHERE parent_id = ?1
             ")?;
-            let entries: Vec<DirEntry> = stmt.query_map([parent_id], |row| {
-                Ok(DirEntry {
-                    id: row.get(0)?,
-                    parent_id: row.get(1)?,
-                    path: row.get(2)?,
-                    metadata: Metadata {
-                        len: row.get(3)?,
-                        modified: row.get(4).ok(),
-                        is_dir: row.get(5)?
-                    }
-                })
-            })?.collect::<std::result::Result<Vec<DirEntry>, rusqlite::Error>>()?;
+            let entries: Vec<DirEntry> = stmt.query_map([parent_id], dir_entry_from_row)?
+                .collect::<std::result::Result<Vec<DirEntry>, rusqlite::Error>>()?;
+            Ok(entries)
+        }).await?)
+    }
+    /// All the entries, parents first
+    pub async fn get_dir_entries(&self) -> Result<Vec<DirEntry>> {
+        Ok(self.conn.call(move |conn| {
+            let mut stmt = conn.prepare("
+                SELECT id, parent_id, path, meta_len, meta_modified, meta_is_dir, discord_msg_id, version, checksum
+                FROM dir_entries
+                ORDER BY length(path), path
+            ")?;
+            let entries = stmt.query_map([], dir_entry_from_row)?
+                .collect::<std::result::Result<Vec<DirEntry>, rusqlite::Error>>()?;
             Ok(entries)
+        }).await?)
+    }
+    pub async fn set_dir_entry_metadata(&self, id: usize, metadata: Metadata) -> Result<()> {
+        self.conn.call(move |conn| {
+            conn.execute("
+                UPDATE dir_entries
+                SET meta_len = ?1, meta_modified = ?2, meta_is_dir = ?3
+                WHERE id = ?4
+            ", params![metadata.len, metadata.modified, metadata.is_dir, id])
+        }).await?;
+        Ok(())
+    }
+    pub async fn set_dir_entry_version(&self, id: usize, version: String) -> Result<()> {
+        self.conn.call(move |conn| {
+            conn.execute("UPDATE dir_entries SET version = ?1 WHERE id = ?2", params![version, id])
+        }).await?;
+        Ok(())
+    }
+    /// Replace the objects of an entry, the old ones are released
+    pub async fn set_dir_entry_objects(&self, id: usize, locator: String, version: String, chunks: Vec<Chunk>, checksum: Option<String>) -> Result<()> {
+        self.conn.call(move |conn| {
+            let tx = conn.transaction()?;
+            let old = objects_of(&tx, id)?;
+            tx.execute("
+                UPDATE dir_entries
+                SET discord_msg_id = ?1, version = ?2, checksum = ?3
+                WHERE id = ?4
+            ", params![locator, version, checksum, id])?;
+            remove_chunks(&tx, id)?;
+            insert_chunks(&tx, id, chunks)?;
+            release(&tx, old)?;
+            tx.commit()
         }).await?;
-        Ok(Vec::new())
+        Ok(())
     }
-}
\ No newline at end of file
+    /// Copy an entry and all its descendants, the copies reference the chunks of the originals
+    pub async fn copy_dir_entry(&self, from: String, to: String, parent_id: Option<usize>) -> Result<()> {
+        self.conn.call(move |conn| {
+            let tx = conn.transaction()?;
+            let entries = tx.prepare("
+                SELECT id, parent_id, path, meta_len, meta_modified, meta_is_dir, discord_msg_id, version, checksum
+                FROM dir_entries
+                WHERE path = ?1 OR substr(path, 1, length(?1) + 1) = ?1 || '/'
+                ORDER BY length(path)
+            ")?.query_map([&from], dir_entry_from_row)?.collect::<std::result::Result<Vec<DirEntry>, rusqlite::Error>>()?;
+            // Id of the copy of each entry, parents are copied before their children
+            let mut copies = HashMap::new();
+            for entry in entries {
+                let path = to.clone() + &entry.path[from.len()..];
+                let parent_id = match entry.parent_id.and_then(|p| copies.get(&p)) {
+                    Some(copy_id) => Some(*copy_id),
+                    None => parent_id
+                };
+                tx.execute("
+                    INSERT INTO dir_entries (parent_id, path, meta_len, meta_modified, meta_is_dir, checksum)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                ", params![parent_id, path, entry.metadata.len, entry.metadata.modified, entry.metadata.is_dir, entry.checksum])?;
+                let copy_id = tx.last_insert_rowid() as usize;
+                tx.execute("
+                    INSERT INTO chunks (entry_id, idx, locator, size, compressed, hash)
+                    SELECT ?1, idx, locator, size, compressed, hash FROM chunks WHERE entry_id = ?2
+                ", params![copy_id, entry.id])?;
+                add_references(&tx, copy_id, 1)?;
+                copies.insert(entry.id, copy_id);
+            }
+            tx.commit()
+        }).await?;
+        Ok(())
+    }
+    /// Whether there are versions, snapshots or removed entries, which `replace_dir_entries` deletes
+    pub async fn has_history(&self) -> Result<bool> {
+        Ok(self.conn.call(move |conn| {
+            conn.query_row("
+                SELECT EXISTS (SELECT 1 FROM file_versions)
+                OR EXISTS (SELECT 1 FROM snapshots)
+                OR EXISTS (SELECT 1 FROM trash_entries)
+            ", [], |row| row.get(0))
+        }).await?)
+    }
+    /// Replace all the entries but the root, parents must come before their children.
+    /// The entries that were moved to the trash are put back in it
+    pub async fn replace_dir_entries(&self, entries: Vec<IndexedEntry>) -> Result<()> {
+        self.conn.call(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM chunks", ())?;
+            tx.execute("DELETE FROM version_chunks", ())?;
+            tx.execute("DELETE FROM file_versions", ())?;
+            tx.execute("DELETE FROM snapshot_chunks", ())?;
+            tx.execute("DELETE FROM snapshot_entries", ())?;
+            tx.execute("DELETE FROM snapshots", ())?;
+            tx.execute("DELETE FROM trash_chunks", ())?;
+            tx.execute("DELETE FROM trash_entries", ())?;
+            tx.execute("DELETE FROM blobs", ())?;
+            tx.execute("DELETE FROM dir_entries WHERE path != '/'", ())?;
+            tx.execute("DELETE FROM cache_entries", ())?;
+            let root: usize = tx.query_row("SELECT id FROM dir_entries WHERE path = '/'", [], |row| row.get(0))?;
+            let mut ids = HashMap::from([("/".to_string(), root)]);
+            for entry in entries {
+                if let Some(deleted) = entry.trashed {
+                    // The id the entry had is not stored in the drive, 0 is never used by an entry
+                    tx.execute("
+                        INSERT INTO trash_entries (entry_id, path, meta_len, meta_modified, meta_is_dir, discord_msg_id, version, checksum, deleted)
+                        VALUES (0, ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                    ", params![entry.path, entry.metadata.len, entry.metadata.modified, entry.metadata.is_dir, entry.locator, entry.version, entry.checksum, deleted])?;
+                    insert_trash_chunks(&tx, tx.last_insert_rowid() as usize, entry.chunks)?;
+                    continue
+                }
+                let parent_id = std::path::Path::new(&entry.path).parent()
+                    .and_then(|p| p.to_str())
+                    .and_then(|p| ids.get(p))
+                    .copied();
+                tx.execute("
+                    INSERT INTO dir_entries (parent_id, path, meta_len, meta_modified, meta_is_dir, discord_msg_id, version, checksum)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                ", params![parent_id, entry.path, entry.metadata.len, entry.metadata.modified, entry.metadata.is_dir, entry.locator, entry.version, entry.checksum])?;
+                let id = tx.last_insert_rowid() as usize;
+                insert_chunks(&tx, id, entry.chunks)?;
+                ids.insert(entry.path, id);
+            }
+            // The objects found in the drive are used again
+            tx.execute("
+                DELETE FROM pending_deletions
+                WHERE locator IN (SELECT locator FROM chunks)
+                OR locator IN (SELECT discord_msg_id FROM dir_entries)
+                OR locator IN (SELECT discord_msg_id FROM trash_entries)
+            ", ())?;
+            tx.commit()
+        }).await?;
+        Ok(())
+    }
+    /// Move an entry and all its descendants to a new path
+    pub async fn rename_dir_entry(&self, id: usize, old_path: String, new_path: String, new_parent_id: Option<usize>) -> Result<()> {
+        self.conn.call(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("
+                UPDATE dir_entries
+                SET path = ?1, parent_id = ?2
+                WHERE id = ?3
+            ", params![new_path, new_parent_id, id])?;
+            tx.execute("
+                UPDATE dir_entries
+                SET path = ?1 || substr(path, length(?2) + 1)
+                WHERE substr(path, 1, length(?2) + 1) = ?2 || '/'
+            ", params![new_path, old_path])?;
+            // The versions follow their entries
+            tx.execute("
+                UPDATE file_versions
+                SET path = (SELECT path FROM dir_entries WHERE id = entry_id)
+                WHERE entry_id IN (SELECT id FROM dir_entries WHERE path = ?1 OR substr(path, 1, length(?1) + 1) = ?1 || '/')
+            ", [new_path])?;
+            tx.commit()
+        }).await?;
+        Ok(())
+    }
+    /// Remove an entry and its chunks, their objects are released. Its versions are kept
+    pub async fn remove_dir_entry(&self, id: usize) -> Result<()> {
+        self.conn.call(move |conn| {
+            let tx = conn.transaction()?;
+            let old = objects_of(&tx, id)?;
+            remove_chunks(&tx, id)?;
+            tx.execute("UPDATE file_versions SET entry_id = NULL WHERE entry_id = ?1", [id])?;
+            tx.execute("DELETE FROM dir_entries WHERE id = ?1", [id])?;
+            release(&tx, old)?;
+            tx.commit()
+        }).await?;
+        Ok(())
+    }
+
+    /// Remove an entry and the ones under it after another server removed them from the drive.
+    /// Their objects are not released, the server that removed them deletes them
+    pub async fn forget_dir_entry(&self, path: String) -> Result<()> {
+        self.conn.call(move |conn| {
+            let tx = conn.transaction()?;
+            let ids = tx.prepare("SELECT id FROM dir_entries WHERE path = ?1 OR substr(path, 1, length(?1) + 1) = ?1 || '/'")?
+                .query_map([path], |row| row.get(0))?
+                .collect::<std::result::Result<Vec<usize>, rusqlite::Error>>()?;
+            for id in ids {
+                remove_chunks(&tx, id)?;
+                tx.execute("UPDATE file_versions SET entry_id = NULL WHERE entry_id = ?1", [id])?;
+                tx.execute("DELETE FROM dir_entries WHERE id = ?1", [id])?;
+            }
+            tx.commit()
+        }).await?;
+        Ok(())
+    }
+
+    // chunks
+    pub async fn get_chunks_by_entry_id(&self, entry_id: usize) -> Result<Vec<Chunk>> {
+        Ok(self.conn.call(move |conn| {
+            let mut stmt = conn.prepare("
+                SELECT locator, size, compressed, hash
+                FROM chunks
+                WHERE entry_id = ?1
+                ORDER BY idx
+            ")?;
+            let chunks = stmt.query_map([entry_id], |row| Ok(Chunk {
+                locator: row.get(0)?,
+                size: row.get(1)?,
+                compressed: row.get(2)?,
+                hash: row.get(3)?
+            }))?.collect::<std::result::Result<Vec<Chunk>, rusqlite::Error>>()?;
+            Ok(chunks)
+        }).await?)
+    }
+    /// Chunk already sent with the given content, to reference its object instead of sending it again.
+    /// A reference is taken in the same transaction, so that the object is not purged before the entry references it.
+    /// It must be dropped with `release_blobs` once the entry references the chunk, or if it never will
+    pub async fn acquire_blob(&self, hash: String) -> Result<Option<Chunk>> {
+        Ok(self.conn.call(move |conn| {
+            let tx = conn.transaction()?;
+            let chunk = tx.query_row("
+                SELECT locator, size, compressed
+                FROM blobs
+                WHERE hash = ?1
+            ", [&hash], |row| Ok(Chunk {
+                locator: row.get(0)?,
+                size: row.get(1)?,
+                compressed: row.get(2)?,
+                hash: Some(hash.clone())
+            })).optional()?;
+            tx.execute("UPDATE blobs SET refcount = refcount + 1 WHERE hash = ?1", [&hash])?;
+            tx.commit()?;
+            Ok(chunk)
+        }).await?)
+    }
+    /// Drop the references taken by `acquire_blob`. The blobs that are not referenced anymore are forgotten and their objects released
+    pub async fn release_blobs(&self, hashes: Vec<String>) -> Result<()> {
+        self.conn.call(move |conn| {
+            let tx = conn.transaction()?;
+            let mut locators = Vec::new();
+            for hash in hashes {
+                tx.execute("UPDATE blobs SET refcount = refcount - 1 WHERE hash = ?1", [&hash])?;
+                locators.extend(tx.query_row("SELECT locator FROM blobs WHERE hash = ?1 AND refcount <= 0", [&hash], |row| row.get(0)).optional()?);
+            }
+            tx.execute("DELETE FROM blobs WHERE refcount <= 0", ())?;
+            release(&tx, locators)?;
+            tx.commit()
+        }).await?;
+        Ok(())
+    }
+    /// Every blob, to check that its object is stored
+    pub async fn get_blobs(&self) -> Result<Vec<Chunk>> {
+        Ok(self.conn.call(move |conn| {
+            let mut stmt = conn.prepare("
+                SELECT locator, size, compressed, hash
+                FROM blobs
+            ")?;
+            let blobs = stmt.query_map([], |row| Ok(Chunk {
+                locator: row.get(0)?,
+                size: row.get(1)?,
+                compressed: row.get(2)?,
+                hash: row.get(3)?
+            }))?.collect::<std::result::Result<Vec<Chunk>, rusqlite::Error>>()?;
+            Ok(blobs)
+        }).await?)
+    }
+    /// Make the chunks stored in an object use another one, after it was sent again or its copies changed.
+    /// The copies of the old object that the new one doesn't keep are released, and the entries using it are queued to send their objects again
+    pub async fn replace_chunk_object(&self, old: String, new: String, compressed: bool) -> Result<()> {
+        self.conn.call(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("INSERT OR IGNORE INTO outdated_entries (entry_id) SELECT entry_id FROM chunks WHERE locator = ?1", [&old])?;
+            tx.execute("UPDATE chunks SET locator = ?1, compressed = ?2 WHERE locator = ?3", params![new, compressed, old])?;
+            tx.execute("UPDATE version_chunks SET locator = ?1, compressed = ?2 WHERE locator = ?3", params![new, compressed, old])?;
+            tx.execute("UPDATE snapshot_chunks SET locator = ?1, compressed = ?2 WHERE locator = ?3", params![new, compressed, old])?;
+            tx.execute("UPDATE trash_chunks SET locator = ?1, compressed = ?2 WHERE locator = ?3", params![new, compressed, old])?;
+            tx.execute("UPDATE blobs SET locator = ?1, compressed = ?2 WHERE locator = ?3", params![new, compressed, old])?;
+            let kept: HashSet<&str> = replicas(&new).collect();
+            release(&tx, replicas(&old).filter(|r| !kept.contains(r)).map(String::from).collect())?;
+            tx.commit()
+        }).await?;
+        Ok(())
+    }
+    pub async fn is_chunk(&self, locator: String) -> Result<bool> {
+        Ok(self.conn.call(move |conn| {
+            conn.query_row("SELECT EXISTS (SELECT 1 FROM chunks WHERE locator = ?1)", [locator], |row| row.get(0))
+        }).await?)
+    }
+
+    // file versions
+    /// Record the content just saved of a file as its newest version, and remove the versions that the retention does not keep:
+    /// the ones of the path beyond the `keep` newest, and the ones of any path saved before `expiry`.
+    /// The newest version of an existing file is always kept, it is its current content.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn add_file_version(&self, entry_id: usize, path: String, metadata: Metadata, chunks: Vec<Chunk>, checksum: Option<String>, keep: Option<usize>, expiry: Option<DateTime<Utc>>) -> Result<()> {
+        self.conn.call(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("
+                INSERT INTO file_versions (entry_id, path, meta_len, meta_modified, checksum, created)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            ", params![entry_id, path, metadata.len, metadata.modified, checksum, Utc::now()])?;
+            let id = tx.last_insert_rowid() as usize;
+            for (idx, chunk) in chunks.into_iter().enumerate() {
+                tx.execute("
+                    INSERT INTO version_chunks (version_id, idx, locator, size, compressed, hash)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                ", params![id, idx, chunk.locator, chunk.size, chunk.compressed, chunk.hash])?;
+                if let Some(hash) = &chunk.hash {
+                    tx.execute("
+                        INSERT OR IGNORE INTO blobs (hash, locator, size, compressed)
+                        VALUES (?1, ?2, ?3, ?4)
+                    ", params![hash, chunk.locator, chunk.size, chunk.compressed])?;
+                }
+            }
+            add_version_references(&tx, id, 1)?;
+
+            let mut expired: Vec<usize> = match keep {
+                Some(keep) => tx.prepare("SELECT id FROM file_versions WHERE path = ?1 ORDER BY id DESC LIMIT -1 OFFSET ?2")?
+                    .query_map(params![path, keep.max(1)], |row| row.get(0))?
+                    .collect::<std::result::Result<_, rusqlite::Error>>()?,
+                None => Vec::new()
+            };
+            if let Some(expiry) = expiry {
+                expired.extend(tx.prepare("
+                    SELECT id FROM file_versions v
+                    WHERE created < ?1
+                    AND NOT (
+                        id = (SELECT MAX(id) FROM file_versions WHERE path = v.path)
+                        AND EXISTS (SELECT 1 FROM dir_entries WHERE path = v.path)
+                    )
+                ")?.query_map([expiry], |row| row.get(0))?.collect::<std::result::Result<Vec<usize>, rusqlite::Error>>()?);
+            }
+            expired.sort_unstable();
+            expired.dedup();
+            remove_versions(&tx, expired)?;
+            tx.commit()
+        }).await?;
+        Ok(())
+    }
+    /// Versions saved at a path, oldest first
+    pub async fn get_file_versions(&self, path: String) -> Result<Vec<FileVersion>> {
+        Ok(self.conn.call(move |conn| {
+            let mut stmt = conn.prepare("
+                SELECT id, entry_id, path, meta_len, meta_modified, checksum
+                FROM file_versions
+                WHERE path = ?1
+                ORDER BY id
+            ")?;
+            let versions = stmt.query_map([path], file_version_from_row)?
+                .collect::<std::result::Result<Vec<FileVersion>, rusqlite::Error>>()?;
+            Ok(versions)
+        }).await?)
+    }
+    pub async fn get_file_version(&self, id: usize) -> Result<Option<FileVersion>> {
+        Ok(self.conn.call(move |conn| {
+            conn.query_row("
+                SELECT id, entry_id, path, meta_len, meta_modified, checksum
+                FROM file_versions
+                WHERE id = ?1
+            ", [id], file_version_from_row).optional()
+        }).await?)
+    }
+    /// Whether versions were saved at a path or under it
+    pub async fn has_file_versions(&self, path: String) -> Result<bool> {
+        Ok(self.conn.call(move |conn| {
+            conn.query_row("
+                SELECT EXISTS (
+                    SELECT 1 FROM file_versions
+                    WHERE path = ?1 OR substr(path, 1, length(?1) + 1) = ?1 || '/'
+                )
+            ", [path], |row| row.get(0))
+        }).await?)
+    }
+    /// Paths directly in a directory that have versions, at them or under them, but no entry anymore
+    pub async fn get_removed_versioned_paths(&self, dir: String) -> Result<Vec<String>> {
+        let prefix = match dir.as_str() {
+            "/" => dir,
+            _ => dir + "/"
+        };
+        Ok(self.conn.call(move |conn| {
+            let mut stmt = conn.prepare("
+                SELECT DISTINCT ?1 || substr(path, length(?1) + 1, instr(substr(path, length(?1) + 1) || '/', '/') - 1) AS child
+                FROM file_versions
+                WHERE substr(path, 1, length(?1)) = ?1
+                AND child NOT IN (SELECT path FROM dir_entries)
+            ")?;
+            let paths = stmt.query_map([prefix], |row| row.get(0))?
+                .collect::<std::result::Result<Vec<String>, rusqlite::Error>>()?;
+            Ok(paths)
+        }).await?)
+    }
+    pub async fn get_version_chunks(&self, version_id: usize) -> Result<Vec<Chunk>> {
+        Ok(self.conn.call(move |conn| {
+            let mut stmt = conn.prepare("
+                SELECT locator, size, compressed, hash
+                FROM version_chunks
+                WHERE version_id = ?1
+                ORDER BY idx
+            ")?;
+            let chunks = stmt.query_map([version_id], |row| Ok(Chunk {
+                locator: row.get(0)?,
+                size: row.get(1)?,
+                compressed: row.get(2)?,
+                hash: row.get(3)?
+            }))?.collect::<std::result::Result<Vec<Chunk>, rusqlite::Error>>()?;
+            Ok(chunks)
+        }).await?)
+    }
+
+    // snapshots
+    /// Freeze the current tree under a name, the snapshot references the chunks of the entries so that their objects are kept.
+    /// Return `false` if the name is taken
+    pub async fn create_snapshot(&self, name: String) -> Result<bool> {
+        Ok(self.conn.call(move |conn| {
+            let tx = conn.transaction()?;
+            let inserted = tx.execute("
+                INSERT OR IGNORE INTO snapshots (name, created)
+                VALUES (?1, ?2)
+            ", params![name, Utc::now()])?;
+            if inserted == 0 {
+                return Ok(false)
+            }
+            let id = tx.last_insert_rowid() as usize;
+            tx.execute("
+                INSERT INTO snapshot_entries (snapshot_id, id, parent_id, path, meta_len, meta_modified, meta_is_dir, checksum)
+                SELECT ?1, id, parent_id, path, meta_len, meta_modified, meta_is_dir, checksum FROM dir_entries
+            ", [id])?;
+            tx.execute("
+                INSERT INTO snapshot_chunks (snapshot_id, entry_id, idx, locator, size, compressed, hash)
+                SELECT ?1, entry_id, idx, locator, size, compressed, hash FROM chunks
+            ", [id])?;
+            add_snapshot_references(&tx, id, 1)?;
+            tx.commit()?;
+            Ok(true)
+        }).await?)
+    }
+    /// Remove a snapshot, the objects that only it referenced are released. Return `false` if there is none with this name
+    pub async fn remove_snapshot(&self, name: String) -> Result<bool> {
+        Ok(self.conn.call(move |conn| {
+            let tx = conn.transaction()?;
+            let Some(id) = tx.query_row("SELECT id FROM snapshots WHERE name = ?1", [name], |row| row.get::<_, usize>(0)).optional()? else {
+                return Ok(false)
+            };
+            let locators = tx.prepare("SELECT DISTINCT locator FROM snapshot_chunks WHERE snapshot_id = ?1")?
+                .query_map([id], |row| row.get(0))?
+                .collect::<std::result::Result<Vec<String>, rusqlite::Error>>()?;
+            add_snapshot_references(&tx, id, -1)?;
+            tx.execute("DELETE FROM blobs WHERE refcount <= 0", ())?;
+            tx.execute("DELETE FROM snapshot_chunks WHERE snapshot_id = ?1", [id])?;
+            tx.execute("DELETE FROM snapshot_entries WHERE snapshot_id = ?1", [id])?;
+            tx.execute("DELETE FROM snapshots WHERE id = ?1", [id])?;
+            release(&tx, locators)?;
+            tx.commit()?;
+            Ok(true)
+        }).await?)
+    }
+    /// All the snapshots, oldest first
+    pub async fn get_snapshots(&self) -> Result<Vec<Snapshot>> {
+        Ok(self.conn.call(move |conn| {
+            let mut stmt = conn.prepare("SELECT id, name, created FROM snapshots ORDER BY id")?;
+            let snapshots = stmt.query_map([], snapshot_from_row)?
+                .collect::<std::result::Result<Vec<Snapshot>, rusqlite::Error>>()?;
+            Ok(snapshots)
+        }).await?)
+    }
+    pub async fn get_snapshot(&self, name: String) -> Result<Option<Snapshot>> {
+        Ok(self.conn.call(move |conn| {
+            conn.query_row("SELECT id, name, created FROM snapshots WHERE name = ?1", [name], snapshot_from_row).optional()
+        }).await?)
+    }
+    /// An entry as it was when the snapshot was created, it has no object of its own
+    pub async fn get_snapshot_entry(&self, snapshot_id: usize, path: String) -> Result<Option<DirEntry>> {
+        Ok(self.conn.call(move |conn| {
+            conn.query_row("
+                SELECT id, parent_id, path, meta_len, meta_modified, meta_is_dir, NULL, NULL, checksum
+                FROM snapshot_entries
+                WHERE snapshot_id = ?1 AND path = ?2
+            ", params![snapshot_id, path], dir_entry_from_row).optional()
+        }).await?)
+    }
+    pub async fn get_snapshot_entries_by_parent_id(&self, snapshot_id: usize, parent_id: usize) -> Result<Vec<DirEntry>> {
+        Ok(self.conn.call(move |conn| {
+            let mut stmt = conn.prepare("
+                SELECT id, parent_id, path, meta_len, meta_modified, meta_is_dir, NULL, NULL, checksum
+                FROM snapshot_entries
+                WHERE snapshot_id = ?1 AND parent_id = ?2
+            ")?;
+            let entries = stmt.query_map([snapshot_id, parent_id], dir_entry_from_row)?
+                .collect::<std::result::Result<Vec<DirEntry>, rusqlite::Error>>()?;
+            Ok(entries)
+        }).await?)
+    }
+    pub async fn get_snapshot_chunks(&self, snapshot_id: usize, entry_id: usize) -> Result<Vec<Chunk>> {
+        Ok(self.conn.call(move |conn| {
+            let mut stmt = conn.prepare("
+                SELECT locator, size, compressed, hash
+                FROM snapshot_chunks
+                WHERE snapshot_id = ?1 AND entry_id = ?2
+                ORDER BY idx
+            ")?;
+            let chunks = stmt.query_map([snapshot_id, entry_id], |row| Ok(Chunk {
+                locator: row.get(0)?,
+                size: row.get(1)?,
+                compressed: row.get(2)?,
+                hash: row.get(3)?
+            }))?.collect::<std::result::Result<Vec<Chunk>, rusqlite::Error>>()?;
+            Ok(chunks)
+        }).await?)
+    }
+
+    // trash
+    /// Move an entry to the trash at `deleted`, it keeps its objects and chunks. Its versions are kept
+    pub async fn trash_dir_entry(&self, id: usize, deleted: DateTime<Utc>) -> Result<()> {
+        self.conn.call(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("
+                INSERT INTO trash_entries (entry_id, path, meta_len, meta_modified, meta_is_dir, discord_msg_id, version, checksum, deleted)
+                SELECT id, path, meta_len, meta_modified, meta_is_dir, discord_msg_id, version, checksum, ?2
+                FROM dir_entries
+                WHERE id = ?1
+            ", params![id, deleted])?;
+            let trash_id = tx.last_insert_rowid() as usize;
+            tx.execute("
+                INSERT INTO trash_chunks (trash_id, idx, locator, size, compressed, hash)
+                SELECT ?1, idx, locator, size, compressed, hash FROM chunks WHERE entry_id = ?2
+            ", [trash_id, id])?;
+            add_trash_references(&tx, trash_id, 1)?;
+            remove_chunks(&tx, id)?;
+            tx.execute("UPDATE file_versions SET entry_id = NULL WHERE entry_id = ?1", [id])?;
+            tx.execute("DELETE FROM dir_entries WHERE id = ?1", [id])?;
+            tx.commit()
+        }).await?;
+        Ok(())
+    }
+    /// All the removed entries, parents first
+    pub async fn get_trash_entries(&self) -> Result<Vec<TrashEntry>> {
+        Ok(self.conn.call(move |conn| {
+            let mut stmt = conn.prepare("
+                SELECT id, entry_id, path, meta_len, meta_modified, meta_is_dir, checksum, deleted
+                FROM trash_entries
+                ORDER BY length(path), path, id
+            ")?;
+            let entries = stmt.query_map([], trash_entry_from_row)?
+                .collect::<std::result::Result<Vec<TrashEntry>, rusqlite::Error>>()?;
+            Ok(entries)
+        }).await?)
+    }
+    /// The last entry removed at a path
+    pub async fn get_trash_entry(&self, path: String) -> Result<Option<TrashEntry>> {
+        Ok(self.conn.call(move |conn| {
+            conn.query_row("
+                SELECT id, entry_id, path, meta_len, meta_modified, meta_is_dir, checksum, deleted
+                FROM trash_entries
+                WHERE path = ?1
+                ORDER BY id DESC
+                LIMIT 1
+            ", [path], trash_entry_from_row).optional()
+        }).await?)
+    }
+    /// Whether entries were removed at a path or under it
+    pub async fn has_trash_entries(&self, path: String) -> Result<bool> {
+        Ok(self.conn.call(move |conn| {
+            conn.query_row("
+                SELECT EXISTS (
+                    SELECT 1 FROM trash_entries
+                    WHERE path = ?1 OR substr(path, 1, length(?1) + 1) = ?1 || '/'
+                )
+            ", [path], |row| row.get(0))
+        }).await?)
+    }
+    /// Paths directly in a directory at which, or under which, entries were removed
+    pub async fn get_trash_paths(&self, dir: String) -> Result<Vec<String>> {
+        let prefix = match dir.as_str() {
+            "/" => dir,
+            _ => dir + "/"
+        };
+        Ok(self.conn.call(move |conn| {
+            let mut stmt = conn.prepare("
+                SELECT DISTINCT ?1 || substr(path, length(?1) + 1, instr(substr(path, length(?1) + 1) || '/', '/') - 1)
+                FROM trash_entries
+                WHERE substr(path, 1, length(?1)) = ?1 AND path != '/'
+            ")?;
+            let paths = stmt.query_map([prefix], |row| row.get(0))?
+                .collect::<std::result::Result<Vec<String>, rusqlite::Error>>()?;
+            Ok(paths)
+        }).await?)
+    }
+    pub async fn get_trash_chunks(&self, trash_id: usize) -> Result<Vec<Chunk>> {
+        Ok(self.conn.call(move |conn| {
+            let mut stmt = conn.prepare("
+                SELECT locator, size, compressed, hash
+                FROM trash_chunks
+                WHERE trash_id = ?1
+                ORDER BY idx
+            ")?;
+            let chunks = stmt.query_map([trash_id], |row| Ok(Chunk {
+                locator: row.get(0)?,
+                size: row.get(1)?,
+                compressed: row.get(2)?,
+                hash: row.get(3)?
+            }))?.collect::<std::result::Result<Vec<Chunk>, rusqlite::Error>>()?;
+            Ok(chunks)
+        }).await?)
+    }
+    /// Put back the last entries removed at a path and under it, at `to`. The versions of the files follow them.
+    /// The directories under which entries were removed are created if needed, the parent of `to` must exist
+    pub async fn restore_trash(&self, from: String, to: String) -> Result<()> {
+        self.conn.call(move |conn| {
+            let tx = conn.transaction()?;
+            let entries: Vec<(usize, String)> = tx.prepare("
+                SELECT MAX(id), path
+                FROM trash_entries
+                WHERE path = ?1 OR substr(path, 1, length(?1) + 1) = ?1 || '/'
+                GROUP BY path
+                ORDER BY length(path)
+            ")?.query_map([&from], |row| Ok((row.get(0)?, row.get(1)?)))?.collect::<std::result::Result<_, rusqlite::Error>>()?;
+            // Id of the restored directories, parents are restored before their children
+            let mut ids = HashMap::new();
+            for (trash_id, path) in entries {
+                let new_path = to.clone() + &path[from.len()..];
+                let parent = std::path::Path::new(&new_path).parent().and_then(|p| p.to_str()).unwrap_or("/");
+                let parent_id = restored_dir(&tx, parent, &mut ids)?;
+                tx.execute("
+                    INSERT INTO dir_entries (parent_id, path, meta_len, meta_modified, meta_is_dir, discord_msg_id, version, checksum)
+                    SELECT ?1, ?2, meta_len, meta_modified, meta_is_dir, discord_msg_id, version, checksum
+                    FROM trash_entries
+                    WHERE id = ?3
+                ", params![parent_id, new_path, trash_id])?;
+                let id = tx.last_insert_rowid() as usize;
+                tx.execute("
+                    INSERT INTO chunks (entry_id, idx, locator, size, compressed, hash)
+                    SELECT ?1, idx, locator, size, compressed, hash FROM trash_chunks WHERE trash_id = ?2
+                ", [id, trash_id])?;
+                add_references(&tx, id, 1)?;
+                add_trash_references(&tx, trash_id, -1)?;
+                tx.execute("DELETE FROM trash_chunks WHERE trash_id = ?1", [trash_id])?;
+                tx.execute("DELETE FROM trash_entries WHERE id = ?1", [trash_id])?;
+                tx.execute("
+                    UPDATE file_versions
+                    SET entry_id = ?1, path = ?2
+                    WHERE path = ?3 AND entry_id IS NULL
+                ", params![id, new_path, path])?;
+                ids.insert(new_path, id);
+            }
+            restored_dir(&tx, &to, &mut ids)?;
+            tx.commit()
+        }).await?;
+        Ok(())
+    }
+    /// Delete the entries removed before `before` from the trash, all of them if `None`. Return the number of entries deleted
+    pub async fn empty_trash(&self, before: Option<DateTime<Utc>>) -> Result<usize> {
+        Ok(self.conn.call(move |conn| {
+            let tx = conn.transaction()?;
+            let ids: Vec<usize> = tx.prepare("SELECT id FROM trash_entries WHERE ?1 IS NULL OR deleted < ?1")?
+                .query_map([before], |row| row.get(0))?
+                .collect::<std::result::Result<_, rusqlite::Error>>()?;
+            let count = ids.len();
+            remove_trash_entries(&tx, ids)?;
+            tx.commit()?;
+            Ok(count)
+        }).await?)
+    }
+    /// Delete the entries removed at a path and under it from the trash
+    pub async fn delete_trash_entries(&self, path: String) -> Result<()> {
+        self.conn.call(move |conn| {
+            let tx = conn.transaction()?;
+            let ids: Vec<usize> = tx.prepare("
+                SELECT id FROM trash_entries
+                WHERE path = ?1 OR substr(path, 1, length(?1) + 1) = ?1 || '/'
+            ")?.query_map([path], |row| row.get(0))?.collect::<std::result::Result<_, rusqlite::Error>>()?;
+            remove_trash_entries(&tx, ids)?;
+            tx.commit()
+        }).await?;
+        Ok(())
+    }
+
+    // pending deletions
+    /// Locators of all the objects used by an entry or waiting to be deleted
+    pub async fn get_referenced_objects(&self) -> Result<HashSet<String>> {
+        Ok(self.conn.call(move |conn| {
+            let mut stmt = conn.prepare("
+                SELECT discord_msg_id FROM dir_entries WHERE discord_msg_id IS NOT NULL
+                UNION SELECT locator FROM chunks
+                UNION SELECT locator FROM version_chunks
+                UNION SELECT locator FROM snapshot_chunks
+                UNION SELECT locator FROM trash_chunks
+                UNION SELECT discord_msg_id FROM trash_entries WHERE discord_msg_id IS NOT NULL
+                UNION SELECT locator FROM blobs
+                UNION SELECT locator FROM pending_deletions
+            ")?;
+            let locators = stmt.query_map([], |row| row.get(0))?
+                .collect::<std::result::Result<HashSet<String>, rusqlite::Error>>()?;
+            Ok(locators)
+        }).await?)
+    }
+    pub async fn get_pending_deletions(&self, limit: usize) -> Result<Vec<String>> {
+        Ok(self.conn.call(move |conn| {
+            let mut stmt = conn.prepare("
+                SELECT locator
+                FROM pending_deletions
+                LIMIT ?1
+            ")?;
+            let locators = stmt.query_map([limit], |row| row.get(0))?
+                .collect::<std::result::Result<Vec<String>, rusqlite::Error>>()?;
+            Ok(locators)
+        }).await?)
+    }
+    pub async fn remove_pending_deletions(&self, locators: Vec<String>) -> Result<()> {
+        self.conn.call(move |conn| {
+            let tx = conn.transaction()?;
+            for locator in locators {
+                tx.execute("DELETE FROM pending_deletions WHERE locator = ?1", [locator])?;
+            }
+            tx.commit()
+        }).await?;
+        Ok(())
+    }
+
+    // outdated entries
+    /// Queue an entry and all its descendants to send their objects again, after they moved
+    pub async fn add_outdated_entries(&self, path: String) -> Result<()> {
+        self.conn.call(move |conn| {
+            conn.execute("
+                INSERT OR IGNORE INTO outdated_entries (entry_id)
+                SELECT id FROM dir_entries
+                WHERE path = ?1 OR substr(path, 1, length(?1) + 1) = ?1 || '/'
+            ", [path])
+        }).await?;
+        Ok(())
+    }
+    /// Ids of the queued entries, parents first. The removed ones are dropped from the queue
+    pub async fn get_outdated_entries(&self) -> Result<Vec<usize>> {
+        Ok(self.conn.call(move |conn| {
+            conn.execute("DELETE FROM outdated_entries WHERE entry_id NOT IN (SELECT id FROM dir_entries)", ())?;
+            let mut stmt = conn.prepare("
+                SELECT id
+                FROM dir_entries
+                WHERE id IN (SELECT entry_id FROM outdated_entries)
+                ORDER BY length(path)
+            ")?;
+            let ids = stmt.query_map([], |row| row.get(0))?
+                .collect::<std::result::Result<Vec<usize>, rusqlite::Error>>()?;
+            Ok(ids)
+        }).await?)
+    }
+    pub async fn remove_outdated_entry(&self, id: usize) -> Result<()> {
+        self.conn.call(move |conn| {
+            conn.execute("DELETE FROM outdated_entries WHERE entry_id = ?1", [id])
+        }).await?;
+        Ok(())
+    }
+
+    // cache entries
+    /// Record an access to a file of the cache, with its current size
+    pub async fn touch_cache_entry(&self, name: String, entry_id: usize, size: u64, last_access: i64) -> Result<()> {
+        self.conn.call(move |conn| {
+            conn.execute("
+                INSERT INTO cache_entries (name, entry_id, size, last_access)
+                VALUES (?1, ?2, ?3, ?4)
+                ON CONFLICT (name) DO UPDATE SET entry_id = ?2, size = ?3, last_access = ?4
+            ", params![name, entry_id, size, last_access])
+        }).await?;
+        Ok(())
+    }
+    pub async fn get_cache_entry(&self, name: String) -> Result<Option<CacheEntry>> {
+        Ok(self.conn.call(move |conn| {
+            conn.query_row("
+                SELECT name, entry_id, size, last_access, dirty
+                FROM cache_entries
+                WHERE name = ?1
+            ", [name], cache_entry_from_row).optional()
+        }).await?)
+    }
+    pub async fn set_cache_entry_dirty(&self, name: String, dirty: bool) -> Result<()> {
+        self.conn.call(move |conn| {
+            conn.execute("UPDATE cache_entries SET dirty = ?1 WHERE name = ?2", params![dirty, name])
+        }).await?;
+        Ok(())
+    }
+    pub async fn get_cache_entries(&self) -> Result<Vec<CacheEntry>> {
+        Ok(self.conn.call(move |conn| {
+            let mut stmt = conn.prepare("
+                SELECT name, entry_id, size, last_access, dirty
+                FROM cache_entries
+            ")?;
+            let entries = stmt.query_map([], cache_entry_from_row)?
+                .collect::<std::result::Result<Vec<CacheEntry>, rusqlite::Error>>()?;
+            Ok(entries)
+        }).await?)
+    }
+    /// Clean entries, least recently used first
+    pub async fn get_evictable_cache_entries(&self, limit: usize) -> Result<Vec<CacheEntry>> {
+        Ok(self.conn.call(move |conn| {
+            let mut stmt = conn.prepare("
+                SELECT name, entry_id, size, last_access, dirty
+                FROM cache_entries
+                WHERE NOT dirty
+                ORDER BY last_access
+                LIMIT ?1
+            ")?;
+            let entries = stmt.query_map([limit], cache_entry_from_row)?
+                .collect::<std::result::Result<Vec<CacheEntry>, rusqlite::Error>>()?;
+            Ok(entries)
+        }).await?)
+    }
+    pub async fn get_cache_size(&self) -> Result<u64> {
+        Ok(self.conn.call(move |conn| {
+            conn.query_row("SELECT COALESCE(SUM(size), 0) FROM cache_entries", [], |row| row.get(0))
+        }).await?)
+    }
+    pub async fn remove_cache_entry(&self, name: String) -> Result<()> {
+        self.conn.call(move |conn| {
+            conn.execute("DELETE FROM cache_entries WHERE name = ?1", [name])
+        }).await?;
+        Ok(())
+    }
+}
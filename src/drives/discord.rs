@@ -1,328 +1,36 @@
-use std::path::{PathBuf, Path};
+pub mod mock;
+mod ratelimit;
+
+use std::borrow::Cow;
 use std::sync::Arc;
-use std::{collections::HashMap, borrow::Cow};
-use futures::io::Cursor;
-use futures::{FutureExt, AsyncReadExt, AsyncSeekExt};
-use reqwest::multipart;
-use rusqlite::{OptionalExtension, params};
+use chrono::{DateTime, Utc};
+use futures::FutureExt;
+use reqwest::{multipart, Method, RequestBuilder, StatusCode};
 use serde::{Deserialize, Serialize};
-use tokio::io::AsyncWriteExt;
-use tokio::sync::{RwLock, Mutex};
-use webdav_handler::fs::{DavMetaData, DavFileSystem, FsError, DavFile, DavDirEntry};
-use crate::db::DB;
+use crate::drives::{Drive, DriveFuture, ObjectInfo};
+use crate::drives::pool::PoolDrive;
 use crate::error::{Result, Error};
-use crate::types::{File, Metadata, DirEntry};
-use bytes::Buf;
-
-impl DB {
-    pub async fn get_discord_file_by_path(&self, path: String, fs: Arc<DiscordFs>, cache: Arc<PathBuf>) -> Result<Option<DiscordFile>> {
-        Ok(self.conn.call(move |conn| {
-            conn.query_row("
-                SELECT id, parent_id, path, meta_len, meta_modified, meta_is_dir, discord_msg_id
-                FROM dir_entries
-                WHERE path = ?1
-            ", [path], |row| {
-                let file = File {
-                    dir_entry: DirEntry {
-                        id: row.get(0)?,
-                        parent_id: row.get(1)?,
-                        path: row.get(2)?,
-                        metadata: Metadata {
-                            len: row.get(3)?,
-                            modified: row.get(4).ok(),
-                            is_dir: row.get(5)?
-                        }
-                    },
-                    cached: None,
-                    cursor_pos: 0
-                };
-                let msg_id = row.get::<_, Option<String>>(6)?;
-                Ok(DiscordFile::new(msg_id, file, fs, cache))
-            }).optional()
-        }).await?)
-    }
-    pub async fn get_discord_file_by_id(&self, id: usize, fs: Arc<DiscordFs>, cache: Arc<PathBuf>) -> Result<Option<DiscordFile>> {
-        Ok(self.conn.call(move |conn| {
-            conn.query_row("
-                SELECT id, parent_id, path, meta_len, meta_modified, meta_is_dir, discord_msg_id
-                FROM dir_entries
-                WHERE id = ?1
-            ", [id], |row| {
-                let file = File {
-                    dir_entry: DirEntry {
-                        id: row.get(0)?,
-                        parent_id: row.get(1)?,
-                        path: row.get(2)?,
-                        metadata: Metadata {
-                            len: row.get(3)?,
-                            modified: row.get(4).ok(),
-                            is_dir: row.get(5)?
-                        }
-                    },
-                    cached: None,
-                    cursor_pos: 0
-                };
-                let msg_id = row.get::<_, Option<String>>(6)?;
-                Ok(DiscordFile::new(msg_id, file, fs, cache))
-            }).optional()
-        }).await?)
-    }
-    pub async fn edit_discord_file_msg_id_by_id(&self, id: usize, msg_id: String) -> Result<()> {
-        self.conn.call(move |conn| {
-            conn.execute("
-                UPDATE dir_entries
-                SET discord_msg_id = ?1
-                WHERE id = ?2
-            ", params![msg_id, id])
-        }).await?;
-        Ok(())
-    }
-}
-
-
-#[derive(Debug)]
-pub struct DiscordFile {
-    pub msg_id: Option<String>,
-    pub inner: File,
-    pub fs: Arc<DiscordFs>,
-    pub cache: Arc<PathBuf>
-}
-impl DiscordFile {
-    pub fn new(msg_id: Option<String>, inner: File, fs: Arc<DiscordFs>, cache: Arc<PathBuf>) -> Self {
-        Self {
-            msg_id,
-            inner,
-            fs,
-            cache
-        }
-    }
-    pub fn client(&self) -> &Arc<DiscordClient> {
-        &self.fs.client
-    }
-    pub fn db(&self) -> &Arc<DB> {
-        &self.fs.db
-    }
-    pub fn boxed(self) -> Box<Self> {
-        Box::new(self)
-    }
-    pub fn path(&self) -> String {
-        format!("./cache/{}", self.inner.id())
-    }
-    pub async fn load(&mut self) -> Result<()> {
-        let path = self.path();
-        if tokio::fs::try_exists(&path).await? {
-            return Ok(())
-        }
-
-        match &self.msg_id {
-            Some(msg_id) => {
-                let msg = self.client().get_message(msg_id).await?;
-
-                let new_meta: Metadata = serde_json::from_str(&msg.content)?;
-
-                if self.inner.metadata().is_dir() != new_meta.is_dir() {
-                    eprintln!("Distant file is_dir value is different from local file is_dir value");
-                    return Err(Error::BadContent)
-                }
-
-                if !self.inner.metadata().is_dir() {
-                    let url = &msg.attachments.get(0).ok_or(Error::DiscordAttachmentNotFound)?.url;
-                    let content = self.client().get_attachment(url).await?;
-                    tokio::fs::OpenOptions::new()
-                        .write(true)
-                        .create_new(true)
-                        .open(&path).await?
-                        .write_all(&content).await?;
-                }
-                *self.inner.metadata_mut() = new_meta;
-            },
-            None => {
-                self.send_create().await?;
-            }
-        }
-
-        Ok(())
-    }
-    /// Generate the message to send to Discord from the local file
-    pub async fn get_msg_data(&self) -> Result<(String, Vec<(String, Vec<u8>)>)> {
-        let mut attachments = Vec::new();
-        if !self.inner.metadata().is_dir() {
-            if !tokio::fs::try_exists(self.path()).await? {
-                return Err(Error::NotFound)
-            }
-            attachments.push((self.inner.id().to_string(), tokio::fs::read(self.path()).await?))
-        }
-
-        Ok((serde_json::to_string(self.inner.metadata())?, attachments))
-    }
-    /// Send the file to Discord for the first time
-    pub async fn send_create(&mut self) -> Result<()> {
-        let (content, attachments) = self.get_msg_data().await?;
-        
-        let msg_id = self.client().send_msg_with_attachment(&content, attachments).await?;
-
-        self.msg_id = Some(msg_id.clone());
+use ratelimit::RateLimiter;
 
-        self.db().edit_discord_file_msg_id_by_id(*self.inner.id(), msg_id).await?;
+pub const DEFAULT_API_URL: &str = "https://discord.com/api/v10";
 
-        Ok(())
-    }
-    /// Edit the file on Discord, return an error if the file was not sent
-    pub async fn send_edit(&self) -> Result<()> {
-        let msg_data = self.get_msg_data().await?;
-
-        self.client().edit_msg_with_attachments(self.msg_id.as_ref().ok_or(Error::DiscordMessageIdIsNone)?, &msg_data.0, msg_data.1).await?;
-
-        Ok(())
-    }
-    // Create or edit distant file
-    pub async fn send(&mut self) -> Result<()> {
-        match self.msg_id {
-            Some(_) => self.send_edit().await,
-            None => self.send_create().await
-        }
-    }
-}
+/// First second of 2015 in milliseconds, the origin of the timestamps of the snowflakes
+pub const DISCORD_EPOCH: u64 = 1420070400000;
 
-impl DavFile for DiscordFile {
-    fn metadata<'a>(&'a mut self) -> webdav_handler::fs::FsFuture<Box<dyn DavMetaData>> {
-        async {
-            Ok(self.inner.metadata().clone().boxed() as Box<(dyn DavMetaData + 'static)>)
-        }.boxed()
-    }
-    fn write_bytes<'a>(&'a mut self, buf: bytes::Bytes) -> webdav_handler::fs::FsFuture<()> {
-        async move {
-            self.load().await?;
-            self.inner.
-            let mut cached = self.inner.write().await;
-            let content = cached.cached.as_mut().unwrap();
-            content.write_all(&buf[..]).await?;
-            self.send_edit().await?;
-            Ok(())
-        }.boxed()
-    }
-    fn write_buf<'a>(&'a mut self, mut buf: Box<dyn bytes::Buf + Send>) -> webdav_handler::fs::FsFuture<()> {
-        async move {
-            self.load().await?;
-            let _loaded_lock = self.loaded.try_lock();
-            let mut cached = self.inner.write().await;
-            let content = cached.cached.as_mut().unwrap();
-            while buf.has_remaining() {
-                let chunk = buf.chunk();
-                let n = content.write(chunk).await?;
-                buf.advance(n);
-            }
-            self.send_edit().await?;
-            Ok(())
-        }.boxed()
-    }
-    fn read_bytes<'a>(&'a mut self, count: usize) -> webdav_handler::fs::FsFuture<bytes::Bytes> {
-        async move {
-            self.load().await?;
-            let _loaded_lock = self.loaded.try_lock();
-            let mut buf = Vec::with_capacity(count);
-            buf.fill(0);
-            self.inner.write().await.cached.as_mut().unwrap().read_exact(&mut buf).await?;
-            Ok(buf.into())
-        }.boxed()
-    }
-    fn seek<'a>(&'a mut self, pos: std::io::SeekFrom) -> webdav_handler::fs::FsFuture<u64> {
-        async move {
-            self.load().await?;
-            let _loaded_lock = self.loaded.try_lock();
-            let mut cached = self.inner.write().await;
-            let content = cached.cached.as_mut().unwrap();
-            let res = content.seek(pos).await?;
-            Ok(res)
-        }.boxed()
-    }
-    fn flush<'a>(&'a mut self) -> webdav_handler::fs::FsFuture<()> {
-        async move {
-            let mut cached = self.inner.write().await;
-            if let Some(c) = &mut cached.cached {
-                c.flush().await?;
-            }
-            Ok(())
-        }.boxed()
-    }
-}
+/// Messages older than this (in milliseconds) can't be bulk deleted, with a margin
+const BULK_DELETE_MAX_AGE: u64 = (14 * 24 - 1) * 60 * 60 * 1000;
 
-#[derive(Clone, Debug)]
-pub struct DiscordFs {
-    db: Arc<DB>,
-    client: Arc<DiscordClient>
-}
-impl DiscordFs {
-    pub fn new(db: Arc<DB>, token: String, channel_id: String) -> Self {
-        let client = DiscordClient::new(token, channel_id);
+/// Maximum size of an attachment, kept under the upload limit of Discord
+pub const MAX_ATTACHMENT_SIZE: usize = 8 * 1024 * 1024;
 
-        Self {
-            db,
-            client: Arc::new(client)
-        }
-    }
-}
+/// Maximum number of characters of the content of a message
+const MAX_CONTENT_LENGTH: usize = 2000;
 
-impl DavFileSystem for DiscordFs {
-    fn metadata<'a>(&'a self, path: &'a webdav_handler::davpath::DavPath) -> webdav_handler::fs::FsFuture<Box<dyn DavMetaData>> {
-        async move {
-            let mut path = path.as_url_string();
-            path = percent_encoding::percent_decode_str(&path).decode_utf8().map_err(|_|FsError::Forbidden)?.to_string();
-            println!("metadata on {}", path);
-            let mut file = self.db.get_discord_file_by_path(path, Arc::new(self.clone())).await?.unwrap();//.ok_or(FsError::NotFound)?;
-            Ok(file.metadata().await?)
-        }.boxed()
-    }
-    fn read_dir<'a>(
-            &'a self,
-            path: &'a webdav_handler::davpath::DavPath,
-            meta: webdav_handler::fs::ReadDirMeta,
-    ) -> webdav_handler::fs::FsFuture<webdav_handler::fs::FsStream<Box<dyn webdav_handler::fs::DavDirEntry>>> {
-        async move {
-            let mut path = path.as_url_string();
-            path = percent_encoding::percent_decode_str(&path).decode_utf8().map_err(|_|FsError::Forbidden)?.to_string();
-            println!("read_dir on {}", path);
-            let dir = self.db.get_dir_entry_by_path(path).await?.unwrap();//.ok_or(FsError::NotFound)?;
-            let entries: Vec<Box<dyn DavDirEntry>> = self.db.get_dir_entries_by_parent_id(dir.id).await?.into_iter().map(|e|Box::new(e) as Box<dyn DavDirEntry>).collect();
-            let stream = futures::stream::iter(entries);
-            Ok(Box::pin(stream) as webdav_handler::fs::FsStream<Box<dyn webdav_handler::fs::DavDirEntry>>)
-        }.boxed()
-    }
-    fn open<'a>(&'a self, path: &'a webdav_handler::davpath::DavPath, options: webdav_handler::fs::OpenOptions) -> webdav_handler::fs::FsFuture<Box<dyn DavFile>> {
-        async move {
-            let original_path = path;
-            let mut path = path.as_url_string();
-            path = percent_encoding::percent_decode_str(&path).decode_utf8().map_err(|_|FsError::Forbidden)?.to_string();
-            println!("open on {}", path);
-            dbg!(options);
-            let file = self.db.get_discord_file_by_path(path.to_string(), Arc::new(self.clone())).await?;
-            if file.is_some() && options.create_new {
-                return Err(FsError::Exists)
-            }
-            if file.is_none() {
-                if options.create_new || options.create {
-                    let p = original_path.as_pathbuf();
-                    let parent_path = p.parent().unwrap().to_str().ok_or(FsError::Forbidden)?;//.ok_or(FsError::NotFound)?.to_str().ok_or(FsError::Forbidden)?;
-                    let parent = if parent_path.is_empty() {
-                        None
-                    } else {
-                        Some(self.db.get_dir_entry_by_path(parent_path.to_owned()).await?.unwrap())//.ok_or(FsError::NotFound)?)
-                    };
-                    self.db.insert_dir_entry(parent.map(|p|p.id), path.clone(), Metadata { len: 0, modified: None, is_dir: false }).await?;
-                    return self.open(original_path, options).await
-                } else {
-                    return Err(FsError::NotFound)
-                }
-            }
-            let mut file = file.unwrap();
-            if options.append {
-                file.cursor_pos = file.metadata().len;
-            }
+/// Name of the attachment holding the metadata of an object when it doesn't fit in the content of its message
+const META_ATTACHMENT: &str = "meta.json";
 
-            Ok(Box::new(DiscordFile::new(None, Arc::new(RwLock::new(file)), Arc::new(self.clone()))))
-        }.boxed()
-    }
-}
+/// Maximum number of messages returned by a request to the history of a channel
+const MESSAGES_PAGE_SIZE: usize = 100;
 
 #[derive(Deserialize, Debug)]
 pub struct MsgAttachmentJson {
@@ -335,9 +43,18 @@ pub struct MsgAttachmentJson {
 
 #[derive(Deserialize, Debug)]
 pub struct MsgJson {
+    id: String,
     content: String,
+    timestamp: DateTime<Utc>,
+    edited_timestamp: Option<String>,
     attachments: Vec<MsgAttachmentJson>
 }
+impl MsgJson {
+    /// Changes every time the message is edited, empty if it never was
+    fn version(&self) -> String {
+        self.edited_timestamp.clone().unwrap_or_default()
+    }
+}
 
 #[derive(Serialize)]
 pub struct SendAttachmentJson<'a> {
@@ -357,38 +74,73 @@ pub struct SendMsgResJson {
     pub id: String
 }
 
+#[derive(Serialize)]
+pub struct BulkDeleteReqJson<'a> {
+    pub messages: &'a [&'a String]
+}
+
+/// Whether a message is recent enough to be bulk deleted
+fn is_bulk_deletable(msg_id: &str) -> bool {
+    let Ok(snowflake) = msg_id.parse::<u64>() else {
+        return false
+    };
+    let timestamp = (snowflake >> 22) + DISCORD_EPOCH;
+    let now = chrono::Utc::now().timestamp_millis() as u64;
+    now.saturating_sub(timestamp) < BULK_DELETE_MAX_AGE
+}
+
 #[derive(Debug)]
 pub struct DiscordClient {
     token: String,
     channel_id: String,
-    http: reqwest::Client
+    /// Base url of the API, `DEFAULT_API_URL` unless testing
+    api_url: String,
+    http: reqwest::Client,
+    /// Shared by the clients of the same bot, the global rate limit applies to all its channels
+    ratelimiter: Arc<RateLimiter>
 }
 impl DiscordClient {
-    pub fn new(token: String, channel_id: String) -> Self {
+    pub fn new(token: String, channel_id: String, api_url: String) -> Self {
         Self {
             token,
             channel_id,
-            http: reqwest::Client::new()
+            api_url,
+            http: reqwest::Client::new(),
+            ratelimiter: Arc::new(RateLimiter::new())
         }
     }
-    pub async fn get_message(&self, msg_id: &str) -> Result<MsgJson> {
-        let res = self.http.get(format!("https://discord.com/api/v10/channels/{}/messages/{}", self.channel_id, msg_id))
+    /// Client of the same bot for another channel
+    pub fn in_channel(&self, channel_id: String) -> Self {
+        Self {
+            token: self.token.clone(),
+            channel_id,
+            api_url: self.api_url.clone(),
+            http: self.http.clone(),
+            ratelimiter: self.ratelimiter.clone()
+        }
+    }
+    /// Drive of the channels `channels`, served by the bots `tokens` in turn. Both must not be empty.
+    /// A single channel gives a plain client, otherwise the objects are spread over the channels by a `PoolDrive`
+    /// which stores each blob in `replicas` of them
+    pub fn pool(tokens: &[String], channels: &[String], api_url: String, replicas: usize) -> Result<Arc<dyn Drive>> {
+        let bots: Vec<Self> = tokens.iter().map(|t| Self::new(t.clone(), channels[0].clone(), api_url.clone())).collect();
+        if let ([channel], 1) = (channels, replicas) {
+            return Ok(Arc::new(bots[0].in_channel(channel.clone())))
+        }
+        Ok(Arc::new(PoolDrive::new(channels.iter().enumerate().map(|(i, channel)| {
+            let client: Arc<dyn Drive> = Arc::new(bots[i % bots.len()].in_channel(channel.clone()));
+            (channel.clone(), client)
+        }).collect(), replicas)?))
+    }
+    /// Start a request to the Discord API with the headers of the bot
+    fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        self.http.request(method, url)
             .header("User-Agent", "DiscordBot (https://github.com/Arkitu/multi-drive, 0.0.1)")
             .header("Authorization", "Bot ".to_string() + &self.token)
-            .send()
-            .await?
-            .text()
-            .await?;
-
-        let res: MsgJson = serde_json::from_str(&res)?;
-
-        Ok(res)
     }
-    pub async fn get_attachment(&self, url: &str) -> Result<Vec<u8>> {
-        Ok(self.http.get(url).send().await?.bytes().await?.to_vec())
-    }
-    pub async fn send_msg_with_attachment<T>(&self, content: &str, attachment: Vec<(String, T)>) -> Result<String>
-    where T: Into<Cow<'static, [u8]>> {
+    /// Build the multipart form of a message with attachments
+    fn form<T>(content: &str, attachment: &[(String, T)]) -> Result<multipart::Form>
+    where T: Into<Cow<'static, [u8]>> + Clone {
         let mut form = multipart::Form::new()
             .part("payload_json", multipart::Part::text(serde_json::to_string(&SendMsgReqJson {
                 content,
@@ -398,17 +150,98 @@ impl DiscordClient {
                     filename: n
                 }).collect()
             })?));
-        
-        for (i, (n, a)) in attachment.into_iter().enumerate() {
-            form = form.part(format!("files[{}]", i), multipart::Part::bytes(a).file_name(n))
+
+        for (i, (n, a)) in attachment.iter().enumerate() {
+            form = form.part(format!("files[{}]", i), multipart::Part::bytes(a.clone()).file_name(n.clone()))
         }
 
-        let res = self.http.post(format!("https://discord.com/api/v10/channels/{}/messages", self.channel_id))
-            .header("User-Agent", "DiscordBot (https://github.com/Arkitu/multi-drive, 0.0.1)")
-            .header("Authorization", "Bot ".to_string() + &self.token)
-            .multipart(form)
-            .send()
-            .await?;
+        Ok(form)
+    }
+    pub async fn get_message(&self, msg_id: &str) -> Result<MsgJson> {
+        let url = format!("{}/channels/{}/messages/{}", self.api_url, self.channel_id, msg_id);
+        let res = self.ratelimiter.send("GET /channels/{channel_id}/messages/{message_id}", &self.channel_id, || {
+            Ok(self.request(Method::GET, &url))
+        }).await?;
+
+        if res.status() == StatusCode::NOT_FOUND {
+            return Err(Error::NotFound)
+        }
+
+        if !res.status().is_success() {
+            return Err(Error::DiscordError)
+        }
+
+        let res: MsgJson = serde_json::from_str(&res.text().await?)?;
+
+        Ok(res)
+    }
+    /// Get the messages of the channel older than `before`, or the latest ones, newest first
+    pub async fn get_messages(&self, before: Option<&str>) -> Result<Vec<MsgJson>> {
+        let mut url = format!("{}/channels/{}/messages?limit={}", self.api_url, self.channel_id, MESSAGES_PAGE_SIZE);
+        if let Some(before) = before {
+            url += &format!("&before={}", before);
+        }
+        let res = self.ratelimiter.send("GET /channels/{channel_id}/messages", &self.channel_id, || {
+            Ok(self.request(Method::GET, &url))
+        }).await?;
+
+        if !res.status().is_success() {
+            return Err(Error::DiscordError)
+        }
+
+        let res: Vec<MsgJson> = serde_json::from_str(&res.text().await?)?;
+
+        Ok(res)
+    }
+    pub async fn pin_message(&self, msg_id: &str) -> Result<()> {
+        let url = format!("{}/channels/{}/pins/{}", self.api_url, self.channel_id, msg_id);
+        let res = self.ratelimiter.send("PUT /channels/{channel_id}/pins/{message_id}", &self.channel_id, || {
+            Ok(self.request(Method::PUT, &url))
+        }).await?;
+
+        if !res.status().is_success() {
+            return Err(Error::DiscordError)
+        }
+
+        Ok(())
+    }
+    /// Get the pinned messages of the channel, at most 50
+    pub async fn get_pinned_messages(&self) -> Result<Vec<MsgJson>> {
+        let url = format!("{}/channels/{}/pins", self.api_url, self.channel_id);
+        let res = self.ratelimiter.send("GET /channels/{channel_id}/pins", &self.channel_id, || {
+            Ok(self.request(Method::GET, &url))
+        }).await?;
+
+        if !res.status().is_success() {
+            return Err(Error::DiscordError)
+        }
+
+        let res: Vec<MsgJson> = serde_json::from_str(&res.text().await?)?;
+
+        Ok(res)
+    }
+    /// Download an attachment from the CDN, which doesn't take the token of the bot
+    pub async fn get_attachment(&self, url: &str) -> Result<Vec<u8>> {
+        let res = self.ratelimiter.send("GET attachment", &self.channel_id, || {
+            Ok(self.http.get(url))
+        }).await?;
+
+        if res.status() == StatusCode::NOT_FOUND {
+            return Err(Error::NotFound)
+        }
+
+        if !res.status().is_success() {
+            return Err(Error::DiscordError)
+        }
+
+        Ok(res.bytes().await?.to_vec())
+    }
+    pub async fn send_msg_with_attachment<T>(&self, content: &str, attachment: Vec<(String, T)>) -> Result<String>
+    where T: Into<Cow<'static, [u8]>> + Clone {
+        let url = format!("{}/channels/{}/messages", self.api_url, self.channel_id);
+        let res = self.ratelimiter.send("POST /channels/{channel_id}/messages", &self.channel_id, || {
+            Ok(self.request(Method::POST, &url).multipart(Self::form(content, &attachment)?))
+        }).await?;
 
         if !res.status().is_success() {
             return Err(Error::DiscordError)
@@ -419,28 +252,31 @@ impl DiscordClient {
 
         Ok(res.id)
     }
-    pub async fn edit_msg_with_attachments<T>(&self, msg_id: &str, content: &str, attachment: Vec<(String, T)>) -> Result<()>
-    where T: Into<Cow<'static, [u8]>> {
-        let mut form = multipart::Form::new()
-            .part("payload_json", multipart::Part::text(serde_json::to_string(&SendMsgReqJson {
-                content,
-                attachments: attachment.iter().enumerate().map(|(i, (n, _))| SendAttachmentJson {
-                    id: i,
-                    description: "",
-                    filename: n
-                }).collect()
-            })?));
-        
-        for (i, (n, a)) in attachment.into_iter().enumerate() {
-            form = form.part(format!("files[{}]", i), multipart::Part::bytes(a).file_name(n))
+    pub async fn edit_msg_with_attachments<T>(&self, msg_id: &str, content: &str, attachment: Vec<(String, T)>) -> Result<MsgJson>
+    where T: Into<Cow<'static, [u8]>> + Clone {
+        let url = format!("{}/channels/{}/messages/{}", self.api_url, self.channel_id, msg_id);
+        let res = self.ratelimiter.send("PATCH /channels/{channel_id}/messages/{message_id}", &self.channel_id, || {
+            Ok(self.request(Method::PATCH, &url).multipart(Self::form(content, &attachment)?))
+        }).await?;
+
+        if !res.status().is_success() {
+            return Err(Error::DiscordError)
         }
 
-        let res = self.http.patch(format!("https://discord.com/api/v10/channels/{}/messages/{}", self.channel_id, msg_id))
-            .header("User-Agent", "DiscordBot (https://github.com/Arkitu/multi-drive, 0.0.1)")
-            .header("Authorization", "Bot ".to_string() + &self.token)
-            .multipart(form)
-            .send()
-            .await?;
+        let res: MsgJson = serde_json::from_str(&res.text().await?)?;
+
+        Ok(res)
+    }
+    pub async fn delete_message(&self, msg_id: &str) -> Result<()> {
+        let url = format!("{}/channels/{}/messages/{}", self.api_url, self.channel_id, msg_id);
+        let res = self.ratelimiter.send("DELETE /channels/{channel_id}/messages/{message_id}", &self.channel_id, || {
+            Ok(self.request(Method::DELETE, &url))
+        }).await?;
+
+        // Already deleted
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(())
+        }
 
         if !res.status().is_success() {
             return Err(Error::DiscordError)
@@ -448,4 +284,146 @@ impl DiscordClient {
 
         Ok(())
     }
+    /// Delete between 2 and 100 messages that are less than 2 weeks old
+    pub async fn bulk_delete_messages(&self, msg_ids: &[&String]) -> Result<()> {
+        let url = format!("{}/channels/{}/messages/bulk-delete", self.api_url, self.channel_id);
+        let body = serde_json::to_string(&BulkDeleteReqJson { messages: msg_ids })?;
+        let res = self.ratelimiter.send("POST /channels/{channel_id}/messages/bulk-delete", &self.channel_id, || {
+            Ok(self.request(Method::POST, &url)
+                .header("Content-Type", "application/json")
+                .body(body.clone()))
+        }).await?;
+
+        if !res.status().is_success() {
+            return Err(Error::DiscordError)
+        }
+
+        Ok(())
+    }
+}
+
+impl DiscordClient {
+    /// Content and attachments of the message of an object
+    fn object_message(name: &str, meta: &str, blob: Option<Vec<u8>>) -> (String, Vec<(String, Vec<u8>)>) {
+        let mut attachments: Vec<(String, Vec<u8>)> = blob.map(|b| vec![(name.to_string(), b)]).unwrap_or_default();
+        if meta.chars().count() <= MAX_CONTENT_LENGTH {
+            return (meta.to_string(), attachments)
+        }
+        attachments.push((META_ATTACHMENT.to_string(), meta.as_bytes().to_vec()));
+        (String::new(), attachments)
+    }
+    /// Metadata of the object of a message
+    async fn object_meta(&self, msg: &MsgJson) -> Result<String> {
+        match msg.attachments.iter().find(|a| a.filename == META_ATTACHMENT) {
+            Some(attachment) => String::from_utf8(self.get_attachment(&attachment.url).await?).map_err(|_| Error::BadContent),
+            None => Ok(msg.content.clone())
+        }
+    }
+    async fn object_info(&self, msg: MsgJson) -> Result<ObjectInfo> {
+        Ok(ObjectInfo {
+            meta: self.object_meta(&msg).await?,
+            version: msg.version(),
+            created: msg.timestamp,
+            locator: msg.id
+        })
+    }
+}
+
+impl Drive for DiscordClient {
+    fn max_blob_size(&self) -> usize {
+        MAX_ATTACHMENT_SIZE
+    }
+    fn put<'a>(&'a self, name: &'a str, meta: &'a str, blob: Option<Vec<u8>>) -> DriveFuture<'a, String> {
+        async move {
+            let (content, attachments) = Self::object_message(name, meta, blob);
+            self.send_msg_with_attachment(&content, attachments).await
+        }.boxed()
+    }
+    fn get<'a>(&'a self, locator: &'a str) -> DriveFuture<'a, (String, Option<Vec<u8>>)> {
+        async move {
+            let msg = self.get_message(locator).await?;
+            let blob = match msg.attachments.iter().find(|a| a.filename != META_ATTACHMENT) {
+                Some(attachment) => Some(self.get_attachment(&attachment.url).await?),
+                None => None
+            };
+            Ok((self.object_meta(&msg).await?, blob))
+        }.boxed()
+    }
+    fn get_meta<'a>(&'a self, locator: &'a str) -> DriveFuture<'a, (String, String)> {
+        async move {
+            let msg = self.get_message(locator).await?;
+            Ok((self.object_meta(&msg).await?, msg.version()))
+        }.boxed()
+    }
+    fn update<'a>(&'a self, locator: &'a str, name: &'a str, meta: &'a str, blob: Option<Vec<u8>>) -> DriveFuture<'a, String> {
+        async move {
+            let (content, attachments) = Self::object_message(name, meta, blob);
+            Ok(self.edit_msg_with_attachments(locator, &content, attachments).await?.version())
+        }.boxed()
+    }
+    fn list<'a>(&'a self, cursor: Option<String>) -> DriveFuture<'a, (Vec<ObjectInfo>, Option<String>)> {
+        async move {
+            let msgs = self.get_messages(cursor.as_deref()).await?;
+            let next = match msgs.len() {
+                MESSAGES_PAGE_SIZE => msgs.last().map(|m| m.id.clone()),
+                _ => None
+            };
+            let mut objects = Vec::with_capacity(msgs.len());
+            for msg in msgs {
+                objects.push(self.object_info(msg).await?);
+            }
+            Ok((objects, next))
+        }.boxed()
+    }
+    fn pin<'a>(&'a self, locator: &'a str) -> DriveFuture<'a, ()> {
+        async move {
+            self.pin_message(locator).await
+        }.boxed()
+    }
+    fn pinned<'a>(&'a self) -> DriveFuture<'a, Vec<ObjectInfo>> {
+        async move {
+            let mut objects = Vec::new();
+            for msg in self.get_pinned_messages().await? {
+                objects.push(self.object_info(msg).await?);
+            }
+            Ok(objects)
+        }.boxed()
+    }
+    fn delete<'a>(&'a self, locator: &'a str) -> DriveFuture<'a, ()> {
+        async move {
+            self.delete_message(locator).await
+        }.boxed()
+    }
+    fn delete_many<'a>(&'a self, locators: &'a [String]) -> DriveFuture<'a, ()> {
+        async move {
+            let (recent, old): (Vec<&String>, Vec<&String>) = locators.iter().partition(|id| is_bulk_deletable(id));
+            for batch in recent.chunks(100) {
+                match batch {
+                    [msg_id] => self.delete_message(msg_id).await?,
+                    _ => self.bulk_delete_messages(batch).await?
+                }
+            }
+            for msg_id in old {
+                self.delete_message(msg_id).await?;
+            }
+            Ok(())
+        }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn missing_attachment() {
+        let url = mock::start().await.unwrap();
+        let client = DiscordClient::new("token".to_string(), "channel".to_string(), url.clone());
+        let locator = client.put("file", "meta", Some(b"content".to_vec())).await.unwrap();
+        let msg = client.get_message(&locator).await.unwrap();
+        let attachment = &msg.attachments[0].url;
+        assert_eq!(client.get_attachment(attachment).await.unwrap(), b"content");
+        client.delete(&locator).await.unwrap();
+        assert!(matches!(client.get_attachment(attachment).await, Err(Error::NotFound)));
+    }
 }
@@ -0,0 +1,373 @@
+//! In-process fake of the parts of the Discord API used by `DiscordClient`, to run the drive offline
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use actix_multipart::Multipart;
+use actix_web::{web, App, HttpResponse, HttpServer};
+use actix_web::dev::{Service, ServiceRequest};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use chrono::Utc;
+use futures::{FutureExt, TryStreamExt};
+use futures::future::{ready, Either};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::error::Result;
+use super::DISCORD_EPOCH;
+
+#[derive(Deserialize, Debug)]
+struct BulkDeleteJson {
+    messages: Vec<String>
+}
+
+#[derive(Deserialize, Debug)]
+struct HistoryQuery {
+    before: Option<String>,
+    limit: Option<usize>
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct PayloadJson {
+    #[serde(default)]
+    content: String
+}
+
+#[derive(Debug)]
+struct MockAttachment {
+    id: String,
+    filename: String,
+    data: Vec<u8>
+}
+
+#[derive(Debug)]
+struct MockMessage {
+    id: String,
+    channel_id: String,
+    content: String,
+    attachments: Vec<MockAttachment>,
+    timestamp: String,
+    edited_timestamp: Option<String>,
+    pinned: bool
+}
+
+/// Rate limits enforced by the fake API, none by default
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// Number of requests of a bucket, a method in a channel, allowed in each `window`
+    pub per_bucket: u64,
+    pub window: Duration,
+    /// Number of requests answered before the global rate limit is hit for `window`, once. `None` for never
+    pub global_after: Option<u64>
+}
+
+#[derive(Debug)]
+struct RateLimits {
+    limits: Limits,
+    /// Requests and start of the current window of each bucket
+    buckets: HashMap<String, (u64, Instant)>,
+    requests: u64
+}
+impl RateLimits {
+    /// Count a request, and return the rate limit headers of its response or the response rejecting it
+    fn check(&mut self, req: &ServiceRequest) -> std::result::Result<Vec<(&'static str, String)>, Box<HttpResponse>> {
+        self.requests += 1;
+        if self.limits.global_after == Some(self.requests - 1) {
+            return Err(Box::new(HttpResponse::TooManyRequests()
+                .insert_header(("X-RateLimit-Scope", "global"))
+                .json(json!({ "message": "You are being rate limited.", "retry_after": self.limits.window.as_secs_f64(), "global": true }))))
+        }
+        // The attachments are served by the CDN, which has no rate limit
+        let Some(channel) = req.path().strip_prefix("/channels/").and_then(|p| p.split('/').next()) else {
+            return Ok(Vec::new())
+        };
+        let name = format!("bucket-{}", req.method());
+        let (count, start) = self.buckets.entry(format!("{}:{}", name, channel)).or_insert((0, Instant::now()));
+        if start.elapsed() >= self.limits.window {
+            *count = 0;
+            *start = Instant::now();
+        }
+        let reset_after = (self.limits.window.saturating_sub(start.elapsed())).as_secs_f64();
+        if *count >= self.limits.per_bucket {
+            return Err(Box::new(HttpResponse::TooManyRequests()
+                .insert_header(("X-RateLimit-Bucket", name.clone()))
+                .insert_header(("X-RateLimit-Remaining", "0"))
+                .insert_header(("X-RateLimit-Reset-After", reset_after.to_string()))
+                .json(json!({ "message": "You are being rate limited.", "retry_after": reset_after, "global": false }))))
+        }
+        *count += 1;
+        Ok(vec![
+            ("x-ratelimit-bucket", name),
+            ("x-ratelimit-limit", self.limits.per_bucket.to_string()),
+            ("x-ratelimit-remaining", (self.limits.per_bucket - *count).to_string()),
+            ("x-ratelimit-reset-after", reset_after.to_string())
+        ])
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Base url of the server, used to build the urls of the attachments
+    url: String,
+    /// Last snowflake generated
+    last_id: u64,
+    messages: HashMap<String, MockMessage>
+}
+impl State {
+    /// Generate a snowflake, so that the age of the messages can be computed like with Discord.
+    /// The snowflakes always increase, even when many are generated in the same millisecond
+    fn next_id(&mut self) -> String {
+        let timestamp = Utc::now().timestamp_millis() as u64 - DISCORD_EPOCH;
+        self.last_id = (self.last_id + 1).max(timestamp << 22);
+        self.last_id.to_string()
+    }
+    fn message_json(&self, msg: &MockMessage) -> Value {
+        json!({
+            "id": msg.id,
+            "channel_id": msg.channel_id,
+            "content": msg.content,
+            "timestamp": msg.timestamp,
+            "edited_timestamp": msg.edited_timestamp,
+            "pinned": msg.pinned,
+            "attachments": msg.attachments.iter().map(|a| {
+                let url = format!("{}/attachments/{}/{}", self.url, a.id, a.filename);
+                json!({
+                    "id": a.id,
+                    "filename": a.filename,
+                    "size": a.data.len(),
+                    "url": url,
+                    "proxy_url": url
+                })
+            }).collect::<Vec<_>>()
+        })
+    }
+    /// Message of the channel, `None` if it does not exist or is in another channel
+    fn message(&self, channel_id: &str, msg_id: &str) -> Option<&MockMessage> {
+        self.messages.get(msg_id).filter(|m| m.channel_id == channel_id)
+    }
+}
+
+type SharedState = web::Data<Mutex<State>>;
+
+fn content_too_long(payload: &PayloadJson) -> Option<HttpResponse> {
+    (payload.content.chars().count() > 2000).then(|| {
+        HttpResponse::BadRequest().json(json!({ "message": "Invalid Form Body", "code": 50035 }))
+    })
+}
+
+/// Read the `payload_json` and the files of a multipart message
+async fn read_form(mut form: Multipart) -> std::result::Result<(PayloadJson, Vec<(String, Vec<u8>)>), actix_web::Error> {
+    let mut payload = PayloadJson::default();
+    let mut files = Vec::new();
+    while let Some(mut field) = form.try_next().await? {
+        let name = field.name().unwrap_or_default().to_string();
+        let filename = field.content_disposition().and_then(|c| c.get_filename()).unwrap_or_default().to_string();
+        let mut data = Vec::new();
+        while let Some(chunk) = field.try_next().await? {
+            data.extend_from_slice(&chunk);
+        }
+        if name == "payload_json" {
+            payload = serde_json::from_slice(&data)?;
+        } else {
+            files.push((filename, data));
+        }
+    }
+    Ok((payload, files))
+}
+
+fn attachments(state: &mut State, files: Vec<(String, Vec<u8>)>) -> Vec<MockAttachment> {
+    files.into_iter().map(|(filename, data)| MockAttachment {
+        id: state.next_id(),
+        filename,
+        data
+    }).collect()
+}
+
+async fn create_message(state: SharedState, path: web::Path<String>, form: Multipart) -> actix_web::Result<HttpResponse> {
+    let channel_id = path.into_inner();
+    let (payload, files) = read_form(form).await?;
+    if let Some(res) = content_too_long(&payload) {
+        return Ok(res)
+    }
+    let mut state = state.lock().unwrap();
+    let msg = MockMessage {
+        id: state.next_id(),
+        channel_id,
+        content: payload.content,
+        attachments: attachments(&mut state, files),
+        timestamp: Utc::now().to_rfc3339(),
+        edited_timestamp: None,
+        pinned: false
+    };
+    let res = state.message_json(&msg);
+    state.messages.insert(msg.id.clone(), msg);
+    Ok(HttpResponse::Ok().json(res))
+}
+
+async fn get_message(state: SharedState, path: web::Path<(String, String)>) -> HttpResponse {
+    let (channel_id, msg_id) = path.into_inner();
+    let state = state.lock().unwrap();
+    match state.message(&channel_id, &msg_id) {
+        Some(msg) => HttpResponse::Ok().json(state.message_json(msg)),
+        None => HttpResponse::NotFound().json(json!({ "message": "Unknown Message", "code": 10008 }))
+    }
+}
+
+async fn get_messages(state: SharedState, path: web::Path<String>, query: web::Query<HistoryQuery>) -> HttpResponse {
+    let channel_id = path.into_inner();
+    let state = state.lock().unwrap();
+    // Snowflakes of the same length are ordered like their strings
+    let key = |id: &str| (id.len(), id.to_string());
+    let mut msgs: Vec<&MockMessage> = state.messages.values()
+        .filter(|m| m.channel_id == channel_id)
+        .filter(|m| query.before.as_deref().map_or(true, |b| key(&m.id) < key(b)))
+        .collect();
+    msgs.sort_by_key(|m| std::cmp::Reverse(key(&m.id)));
+    msgs.truncate(query.limit.unwrap_or(50).clamp(1, 100));
+    HttpResponse::Ok().json(msgs.into_iter().map(|m| state.message_json(m)).collect::<Vec<_>>())
+}
+
+async fn edit_message(state: SharedState, path: web::Path<(String, String)>, form: Multipart) -> actix_web::Result<HttpResponse> {
+    let (channel_id, msg_id) = path.into_inner();
+    let (payload, files) = read_form(form).await?;
+    if let Some(res) = content_too_long(&payload) {
+        return Ok(res)
+    }
+    let mut state = state.lock().unwrap();
+    if state.message(&channel_id, &msg_id).is_none() {
+        return Ok(HttpResponse::NotFound().json(json!({ "message": "Unknown Message", "code": 10008 })))
+    }
+    let attachments = attachments(&mut state, files);
+    let msg = state.messages.get_mut(&msg_id).unwrap();
+    msg.content = payload.content;
+    msg.attachments = attachments;
+    msg.edited_timestamp = Some(Utc::now().to_rfc3339());
+    let res = state.message_json(&state.messages[&msg_id]);
+    Ok(HttpResponse::Ok().json(res))
+}
+
+async fn delete_message(state: SharedState, path: web::Path<(String, String)>) -> HttpResponse {
+    let (channel_id, msg_id) = path.into_inner();
+    let mut state = state.lock().unwrap();
+    if state.message(&channel_id, &msg_id).is_none() {
+        return HttpResponse::NotFound().json(json!({ "message": "Unknown Message", "code": 10008 }))
+    }
+    state.messages.remove(&msg_id);
+    HttpResponse::NoContent().finish()
+}
+
+async fn bulk_delete_messages(state: SharedState, path: web::Path<String>, body: web::Json<BulkDeleteJson>) -> HttpResponse {
+    let channel_id = path.into_inner();
+    let mut state = state.lock().unwrap();
+    if !(2..=100).contains(&body.messages.len()) {
+        return HttpResponse::BadRequest().json(json!({ "message": "Invalid Form Body", "code": 50035 }))
+    }
+    for msg_id in &body.messages {
+        if state.message(&channel_id, msg_id).is_some() {
+            state.messages.remove(msg_id);
+        }
+    }
+    HttpResponse::NoContent().finish()
+}
+
+async fn pin_message(state: SharedState, path: web::Path<(String, String)>) -> HttpResponse {
+    let (channel_id, msg_id) = path.into_inner();
+    let mut state = state.lock().unwrap();
+    if state.message(&channel_id, &msg_id).is_none() {
+        return HttpResponse::NotFound().json(json!({ "message": "Unknown Message", "code": 10008 }))
+    }
+    if state.messages.values().filter(|m| m.channel_id == channel_id && m.pinned).count() >= 50 {
+        return HttpResponse::BadRequest().json(json!({ "message": "Maximum number of pins reached (50)", "code": 30003 }))
+    }
+    state.messages.get_mut(&msg_id).unwrap().pinned = true;
+    HttpResponse::NoContent().finish()
+}
+
+async fn get_pinned_messages(state: SharedState, path: web::Path<String>) -> HttpResponse {
+    let channel_id = path.into_inner();
+    let state = state.lock().unwrap();
+    let msgs: Vec<Value> = state.messages.values()
+        .filter(|m| m.channel_id == channel_id && m.pinned)
+        .map(|m| state.message_json(m))
+        .collect();
+    HttpResponse::Ok().json(msgs)
+}
+
+async fn get_attachment(state: SharedState, path: web::Path<(String, String)>) -> HttpResponse {
+    let (attachment_id, _) = path.into_inner();
+    let state = state.lock().unwrap();
+    let attachment = state.messages.values()
+        .flat_map(|m| &m.attachments)
+        .find(|a| a.id == attachment_id);
+    match attachment {
+        Some(a) => HttpResponse::Ok().body(a.data.clone()),
+        None => HttpResponse::NotFound().finish()
+    }
+}
+
+/// Start a fake Discord server on a random local port and return the base url of its API
+pub async fn start() -> Result<String> {
+    serve(None).await
+}
+
+/// Start a fake Discord server enforcing `limits`, like `start`
+#[cfg(test)]
+pub async fn start_with_limits(limits: Limits) -> Result<String> {
+    serve(Some(limits)).await
+}
+
+async fn serve(limits: Option<Limits>) -> Result<String> {
+    let state = web::Data::new(Mutex::new(State::default()));
+    let app_state = state.clone();
+    let rate_limits = web::Data::new(limits.map(|limits| Mutex::new(RateLimits { limits, buckets: HashMap::new(), requests: 0 })));
+    let server = HttpServer::new(move || {
+        let rate_limits = rate_limits.clone();
+        App::new()
+            .app_data(app_state.clone())
+            .wrap_fn(move |req, srv| {
+                let checked = match rate_limits.as_ref() {
+                    Some(rate_limits) => rate_limits.lock().unwrap().check(&req),
+                    None => Ok(Vec::new())
+                };
+                match checked {
+                    Ok(headers) => Either::Left(srv.call(req).map(move |res| res.map(|mut res| {
+                        for (name, value) in headers {
+                            if let Ok(value) = HeaderValue::from_str(&value) {
+                                res.headers_mut().insert(HeaderName::from_static(name), value);
+                            }
+                        }
+                        res
+                    }))),
+                    Err(limited) => Either::Right(ready(Ok(req.into_response(*limited))))
+                }
+            })
+            .route("/channels/{channel_id}/messages", web::get().to(get_messages))
+            .route("/channels/{channel_id}/messages", web::post().to(create_message))
+            .route("/channels/{channel_id}/messages/bulk-delete", web::post().to(bulk_delete_messages))
+            .route("/channels/{channel_id}/messages/{message_id}", web::get().to(get_message))
+            .route("/channels/{channel_id}/messages/{message_id}", web::patch().to(edit_message))
+            .route("/channels/{channel_id}/messages/{message_id}", web::delete().to(delete_message))
+            .route("/channels/{channel_id}/pins", web::get().to(get_pinned_messages))
+            .route("/channels/{channel_id}/pins/{message_id}", web::put().to(pin_message))
+            .route("/attachments/{attachment_id}/{filename}", web::get().to(get_attachment))
+    })
+    .workers(1)
+    .bind("127.0.0.1:0")?;
+
+    let url = format!("http://{}", server.addrs()[0]);
+    state.lock().unwrap().url = url.clone();
+    tokio::spawn(server.run());
+
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snowflakes_increase() {
+        let mut state = State::default();
+        let ids: Vec<u64> = (0..10_000).map(|_| state.next_id().parse().unwrap()).collect();
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+    }
+}
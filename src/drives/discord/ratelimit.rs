@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use reqwest::header::HeaderMap;
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use crate::error::{Result, Error};
+
+/// Number of times a rate limited request is sent before giving up
+const MAX_ATTEMPTS: usize = 5;
+
+#[derive(Deserialize, Debug)]
+struct RateLimitedJson {
+    retry_after: f64,
+    #[serde(default)]
+    global: bool
+}
+
+#[derive(Debug, Default)]
+struct Bucket {
+    /// Number of requests that can be sent before `reset`, `None` if unknown
+    remaining: Option<u64>,
+    reset: Option<Instant>
+}
+
+fn header<T: std::str::FromStr>(headers: &HeaderMap, name: &str) -> Option<T> {
+    headers.get(name)?.to_str().ok()?.parse().ok()
+}
+
+/// Schedule the requests sent to Discord so that they respect its rate limits.
+///
+/// Requests are grouped by route and each route is mapped to the bucket returned in the `X-RateLimit-Bucket` header.
+/// Requests of an exhausted bucket wait in queue until the bucket resets, and requests that are rate limited anyway are retried after `retry_after`.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    /// Bucket of each route, once known
+    routes: Mutex<HashMap<String, String>>,
+    buckets: Mutex<HashMap<String, Arc<Mutex<Bucket>>>>,
+    /// Requests are blocked until this instant when the global rate limit is hit
+    global: Mutex<Option<Instant>>
+}
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    async fn bucket(&self, route: &str, major: &str) -> Arc<Mutex<Bucket>> {
+        let key = match self.routes.lock().await.get(route) {
+            Some(bucket) => format!("{}:{}", bucket, major),
+            None => route.to_string()
+        };
+        self.buckets.lock().await.entry(key).or_default().clone()
+    }
+    async fn wait_global(&self) {
+        let until = *self.global.lock().await;
+        if let Some(until) = until {
+            tokio::time::sleep_until(until).await;
+        }
+    }
+    /// Wait until the bucket of the route allows a new request and reserve it
+    async fn acquire(&self, route: &str, major: &str) {
+        let bucket = self.bucket(route, major).await;
+        // The lock is kept while waiting so that the requests of the bucket are queued
+        let mut bucket = bucket.lock().await;
+        if let (Some(0), Some(reset)) = (bucket.remaining, bucket.reset) {
+            tokio::time::sleep_until(reset).await;
+            bucket.remaining = None;
+        }
+        if let Some(remaining) = &mut bucket.remaining {
+            *remaining = remaining.saturating_sub(1);
+        }
+    }
+    /// Update the state of the bucket of the route from the headers of a response
+    async fn update(&self, route: &str, major: &str, headers: &HeaderMap) {
+        if let Some(bucket) = header::<String>(headers, "X-RateLimit-Bucket") {
+            self.routes.lock().await.insert(route.to_string(), bucket);
+        }
+        let bucket = self.bucket(route, major).await;
+        let mut bucket = bucket.lock().await;
+        if let Some(remaining) = header(headers, "X-RateLimit-Remaining") {
+            bucket.remaining = Some(remaining);
+        }
+        if let Some(reset_after) = header::<f64>(headers, "X-RateLimit-Reset-After") {
+            bucket.reset = Some(Instant::now() + Duration::from_secs_f64(reset_after));
+        }
+    }
+    /// Send a request built by `build`, waiting for the rate limits and retrying it when it is rate limited.
+    ///
+    /// `route` identifies the endpoint (without the ids that are not major parameters) and `major` is the value of its major parameter.
+    pub async fn send<F>(&self, route: &str, major: &str, build: F) -> Result<Response>
+    where F: Fn() -> Result<RequestBuilder> {
+        for _ in 0..MAX_ATTEMPTS {
+            self.wait_global().await;
+            self.acquire(route, major).await;
+
+            let res = build()?.send().await?;
+            self.update(route, major, res.headers()).await;
+
+            if res.status() != StatusCode::TOO_MANY_REQUESTS {
+                return Ok(res)
+            }
+
+            let global = header::<String>(res.headers(), "X-RateLimit-Scope").as_deref() == Some("global");
+            let limited: RateLimitedJson = serde_json::from_str(&res.text().await?)?;
+            let until = Instant::now() + Duration::from_secs_f64(limited.retry_after);
+            if global || limited.global {
+                *self.global.lock().await = Some(until);
+            } else {
+                let bucket = self.bucket(route, major).await;
+                let mut bucket = bucket.lock().await;
+                bucket.remaining = Some(0);
+                bucket.reset = Some(until);
+            }
+        }
+        Err(Error::DiscordError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::mock::{self, Limits};
+
+    /// Send `count` requests of the same bucket through `limiter` at the same time, and return whether each succeeded
+    async fn send(limiter: &RateLimiter, url: &str, count: usize) -> Vec<bool> {
+        let http = reqwest::Client::new();
+        let url = format!("{}/channels/channel/pins", url);
+        let requests = (0..count).map(|_| limiter.send("GET /channels/{channel_id}/pins", "channel", || Ok(http.get(&url))));
+        futures::future::join_all(requests).await.into_iter()
+            .map(|res| res.is_ok_and(|res| res.status().is_success()))
+            .collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn waits_for_the_bucket_to_reset() {
+        let url = mock::start_with_limits(Limits { per_bucket: 2, window: Duration::from_millis(500), global_after: None }).await.unwrap();
+        let limiter = RateLimiter::new();
+        let start = Instant::now();
+        assert!(send(&limiter, &url, 6).await.into_iter().all(|ok| ok));
+        // 2 requests per window
+        assert!(start.elapsed() >= Duration::from_millis(1000));
+        assert_eq!(limiter.routes.lock().await.get("GET /channels/{channel_id}/pins").map(String::as_str), Some("bucket-GET"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn retries_after_a_rate_limited_request() {
+        let url = mock::start_with_limits(Limits { per_bucket: 2, window: Duration::from_millis(500), global_after: None }).await.unwrap();
+        // Both exhaust the same bucket without knowing it, some of their requests get a 429
+        let (first, second) = (RateLimiter::new(), RateLimiter::new());
+        let start = Instant::now();
+        let (a, b) = tokio::join!(send(&first, &url, 3), send(&second, &url, 3));
+        assert!(a.into_iter().chain(b).all(|ok| ok));
+        assert!(start.elapsed() >= Duration::from_millis(1000));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn waits_for_the_global_rate_limit() {
+        let url = mock::start_with_limits(Limits { per_bucket: 100, window: Duration::from_millis(500), global_after: Some(1) }).await.unwrap();
+        let limiter = RateLimiter::new();
+        let start = Instant::now();
+        assert_eq!(send(&limiter, &url, 1).await, vec![true]);
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert_eq!(send(&limiter, &url, 3).await, vec![true; 3]);
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        assert!(limiter.global.lock().await.is_some());
+    }
+}
@@ -0,0 +1,441 @@
+//! Encryption of the objects of another drive
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fmt::Debug;
+use std::sync::Arc;
+use argon2::Argon2;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use futures::FutureExt;
+use crate::config::Config;
+use crate::drives::{Check, Drive, DriveFuture, ObjectInfo};
+use crate::error::{Error, Result};
+use crate::types::SaltsMeta;
+
+/// Start of the encrypted metadata, the metadata that doesn't start with it is read as is
+const META_PREFIX: &str = "enc:";
+/// Start of the encrypted metadata of the objects stored in the envelope of obfuscation
+const PADDED_META_PREFIX: &str = "encp:";
+/// Name of the blobs when obfuscating
+const OBFUSCATED_NAME: &str = "data";
+/// Minimum size of the padded metadata. Once encoded it is longer than the content of a Discord message,
+/// so that the metadata of every object is stored in an attachment of the same name
+const MIN_PADDED_META: usize = 2048;
+/// Minimum size of the padded blobs
+const MIN_PADDED_BLOB: usize = 4096;
+const KEY_ID_SIZE: usize = 4;
+const NONCE_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+
+/// Name of the pinned object holding the salts
+const SALTS_NAME: &str = "salts";
+
+/// Derive a key from a passphrase and the salt of the key.
+/// The keys used before the salts were stored have none and derive it from their id
+fn derive_key(passphrase: &str, key_id: u32, salt: Option<&[u8]>) -> Result<XChaCha20Poly1305> {
+    let legacy = format!("multi-drive-key-{}", key_id);
+    let mut key = [0; 32];
+    Argon2::default().hash_password_into(passphrase.as_bytes(), salt.unwrap_or(legacy.as_bytes()), &mut key).map_err(|_| Error::Crypto)?;
+    Ok(XChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+/// Salts of the keys, read from the pinned object of `inner`.
+///
+/// A new current key gets a random salt, which is stored before it is used.
+/// On a drive that already has objects but no salts, the current key keeps deriving its salt from its id.
+async fn salts(inner: &dyn Drive, key_id: u32) -> Result<HashMap<u32, Option<Vec<u8>>>> {
+    let object = inner.pinned().await?.into_iter()
+        .find_map(|o| Some((o.locator, serde_json::from_str::<SaltsMeta>(&o.meta).ok()?)));
+    let mut meta = match &object {
+        Some((_, meta)) => meta.clone(),
+        None => SaltsMeta::default()
+    };
+    if let Entry::Vacant(entry) = meta.salts.entry(key_id) {
+        let salt = match (&object, inner.list(None).await?.0.is_empty()) {
+            (None, false) => None,
+            // A nonce is made of random bytes
+            _ => Some(BASE64.encode(&*XChaCha20Poly1305::generate_nonce(&mut OsRng)))
+        };
+        entry.insert(salt);
+        let json = serde_json::to_string(&meta)?;
+        match &object {
+            Some((locator, _)) => {
+                inner.update(locator, SALTS_NAME, &json, None).await?;
+            },
+            None => {
+                let locator = inner.put(SALTS_NAME, &json, None).await?;
+                inner.pin(&locator).await?;
+            }
+        }
+    }
+    meta.salts.into_iter()
+        .map(|(id, salt)| Ok((id, salt.map(|s| BASE64.decode(s)).transpose().map_err(|_| Error::Crypto)?)))
+        .collect()
+}
+
+/// Pad data to the next power of two, with at least `min` and at most `max` bytes, so that its size reveals little.
+/// A 0x80 byte marks the end of the data, there must be room for it.
+fn pad(data: &mut Vec<u8>, min: usize, max: usize) {
+    let size = (data.len() + 1).next_power_of_two().clamp(min, max).max(data.len() + 1);
+    data.push(0x80);
+    data.resize(size, 0);
+}
+
+fn unpad(data: &mut Vec<u8>) -> Result<()> {
+    let end = data.iter().rposition(|b| *b != 0).ok_or(Error::Crypto)?;
+    if data[end] != 0x80 {
+        return Err(Error::Crypto)
+    }
+    data.truncate(end);
+    Ok(())
+}
+
+/// Drive encrypting the metadata and the blobs of the objects of another drive with XChaCha20-Poly1305.
+///
+/// Each encrypted value starts with the id of its key and a random nonce, so that the keys can be rotated:
+/// new objects are encrypted with the current key and the old ones stay readable as long as their key is configured.
+/// The blob of an object is encrypted if and only if its metadata is.
+///
+/// When obfuscating, every object is stored in the same envelope: a blob named `data`, holding nothing for the objects without one,
+/// and the metadata, both padded to powers of two. The objects of the entries and of the chunks then have the same shape
+/// and reveal neither the names nor the exact sizes of the files. What still leaks:
+/// - the number of objects and when they were sent, so roughly how many files were written and when;
+/// - the size bucket of each blob: the chunks of the big files are bigger than the objects of the entries, which are the size of the smallest blobs;
+/// - which objects were updated: the objects of the entries are edited when their file changes, the chunks never are;
+/// - the pinned objects, which are the salts, stored in clear, and the backups of the database.
+pub struct EncryptedDrive {
+    inner: Arc<dyn Drive>,
+    /// Id of the key used to encrypt
+    key_id: u32,
+    ciphers: HashMap<u32, XChaCha20Poly1305>,
+    obfuscate: bool
+}
+impl Debug for EncryptedDrive {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptedDrive")
+            .field("inner", &self.inner)
+            .field("key_id", &self.key_id)
+            .field("obfuscate", &self.obfuscate)
+            .finish_non_exhaustive()
+    }
+}
+impl EncryptedDrive {
+    /// `passphrases` are the passphrases of the keys by id, `key_id` is the one of the key used to encrypt.
+    /// The salts of the keys are stored in a pinned object of `inner`, created if needed
+    pub async fn new(inner: Arc<dyn Drive>, key_id: u32, passphrases: HashMap<u32, String>, obfuscate: bool) -> Result<Self> {
+        if !passphrases.contains_key(&key_id) {
+            return Err(Error::UnknownKey(key_id))
+        }
+        let salts = salts(inner.as_ref(), key_id).await?;
+        let ciphers = passphrases.into_iter()
+            .map(|(id, passphrase)| Ok((id, derive_key(&passphrase, id, salts.get(&id).and_then(Option::as_deref))?)))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            inner,
+            key_id,
+            ciphers,
+            obfuscate
+        })
+    }
+    /// Wrap `inner` if a passphrase is configured.
+    ///
+    /// The current key is `ENCRYPTION_PASSPHRASE` with the id `ENCRYPTION_KEY_ID` (1 by default),
+    /// the previous ones are listed in `ENCRYPTION_OLD_KEYS` as `<id>=<passphrase>,<id>=<passphrase>`.
+    /// The `OBFUSCATE` flag also hides the names and the sizes.
+    pub async fn from_config(inner: Arc<dyn Drive>, config: &Config) -> Result<Arc<dyn Drive>> {
+        let obfuscate = config.flag("OBFUSCATE");
+        let Some(passphrase) = config.get("ENCRYPTION_PASSPHRASE") else {
+            if obfuscate {
+                return Err(Error::MissingPassphrase)
+            }
+            return Ok(inner)
+        };
+        let key_id = match config.get("ENCRYPTION_KEY_ID") {
+            Some(id) => id.parse()?,
+            None => 1
+        };
+        let mut passphrases = HashMap::new();
+        if let Some(old_keys) = config.get("ENCRYPTION_OLD_KEYS") {
+            for old_key in old_keys.split(',').filter(|k| !k.is_empty()) {
+                let (id, passphrase) = old_key.split_once('=').ok_or(Error::Crypto)?;
+                passphrases.insert(id.trim().parse()?, passphrase.to_string());
+            }
+        }
+        passphrases.insert(key_id, passphrase);
+        Ok(Arc::new(Self::new(inner, key_id, passphrases, obfuscate).await?))
+    }
+    /// Maximum size of the data encrypted in a blob
+    fn capacity(&self) -> usize {
+        self.inner.max_blob_size() - KEY_ID_SIZE - NONCE_SIZE - TAG_SIZE
+    }
+    fn name<'a>(&self, name: &'a str) -> &'a str {
+        match self.obfuscate {
+            true => OBFUSCATED_NAME,
+            false => name
+        }
+    }
+    /// Encrypt the blob of an object. When obfuscating, every object gets one, starting with whether the object has a blob
+    fn encrypt_blob(&self, blob: Option<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        if !self.obfuscate {
+            return blob.map(|b| self.encrypt(&b)).transpose()
+        }
+        let mut data = vec![blob.is_some() as u8];
+        data.extend(blob.unwrap_or_default());
+        pad(&mut data, MIN_PADDED_BLOB, self.capacity());
+        Ok(Some(self.encrypt(&data)?))
+    }
+    fn decrypt_blob(&self, blob: &[u8], padded: bool) -> Result<Option<Vec<u8>>> {
+        let mut blob = self.decrypt(blob)?;
+        if !padded {
+            return Ok(Some(blob))
+        }
+        unpad(&mut blob)?;
+        match blob.first() {
+            Some(0) => Ok(None),
+            Some(1) => Ok(Some(blob.split_off(1))),
+            _ => Err(Error::Crypto)
+        }
+    }
+    fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let encrypted = self.ciphers[&self.key_id].encrypt(&nonce, data).map_err(|_| Error::Crypto)?;
+        let mut result = Vec::with_capacity(KEY_ID_SIZE + NONCE_SIZE + encrypted.len());
+        result.extend_from_slice(&self.key_id.to_be_bytes());
+        result.extend_from_slice(&nonce);
+        result.extend(encrypted);
+        Ok(result)
+    }
+    fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < KEY_ID_SIZE + NONCE_SIZE + TAG_SIZE {
+            return Err(Error::Crypto)
+        }
+        let (key_id, data) = data.split_at(KEY_ID_SIZE);
+        let (nonce, encrypted) = data.split_at(NONCE_SIZE);
+        let key_id = u32::from_be_bytes(key_id.try_into().unwrap());
+        let cipher = self.ciphers.get(&key_id).ok_or(Error::UnknownKey(key_id))?;
+        cipher.decrypt(XNonce::from_slice(nonce), encrypted).map_err(|_| Error::Crypto)
+    }
+    fn encrypt_meta(&self, meta: &str) -> Result<String> {
+        let mut meta = meta.as_bytes().to_vec();
+        if !self.obfuscate {
+            return Ok(META_PREFIX.to_string() + &BASE64.encode(self.encrypt(&meta)?))
+        }
+        pad(&mut meta, MIN_PADDED_META, usize::MAX);
+        Ok(PADDED_META_PREFIX.to_string() + &BASE64.encode(self.encrypt(&meta)?))
+    }
+    /// Decrypt the metadata of an object and tell whether the object is padded, `None` if it is not encrypted
+    fn decrypt_meta(&self, meta: &str) -> Result<Option<(String, bool)>> {
+        let (encoded, padded) = match (meta.strip_prefix(META_PREFIX), meta.strip_prefix(PADDED_META_PREFIX)) {
+            (Some(encoded), _) => (encoded, false),
+            (_, Some(encoded)) => (encoded, true),
+            _ => return Ok(None)
+        };
+        let mut meta = self.decrypt(&BASE64.decode(encoded).map_err(|_| Error::Crypto)?)?;
+        if padded {
+            unpad(&mut meta)?;
+        }
+        Ok(Some((String::from_utf8(meta).map_err(|_| Error::Crypto)?, padded)))
+    }
+    /// Decrypt the metadata and the blob of an object, the objects that are not encrypted are left as is
+    fn decrypt_object(&self, meta: String, blob: Option<Vec<u8>>) -> Result<(String, Option<Vec<u8>>)> {
+        let Some((meta, padded)) = self.decrypt_meta(&meta)? else {
+            return Ok((meta, blob))
+        };
+        match blob {
+            Some(blob) => Ok((meta, self.decrypt_blob(&blob, padded)?)),
+            None => Ok((meta, None))
+        }
+    }
+    /// Decrypt the objects found by `list` or `pinned`, the ones that can't be decrypted are left as is
+    fn decrypt_infos(&self, objects: Vec<ObjectInfo>) -> Vec<ObjectInfo> {
+        objects.into_iter().map(|mut object| {
+            match self.decrypt_meta(&object.meta) {
+                Ok(Some((meta, _))) => object.meta = meta,
+                Ok(None) => {},
+                Err(e) => eprintln!("Failed to decrypt the object {}: {}", object.locator, e)
+            }
+            object
+        }).collect()
+    }
+}
+
+impl Drive for EncryptedDrive {
+    fn max_blob_size(&self) -> usize {
+        match self.obfuscate {
+            // Room for whether there is a blob and for the end of the padding
+            true => self.capacity() - 2,
+            false => self.capacity()
+        }
+    }
+    fn concurrency(&self) -> usize {
+        self.inner.concurrency()
+    }
+    fn put<'a>(&'a self, name: &'a str, meta: &'a str, blob: Option<Vec<u8>>) -> DriveFuture<'a, String> {
+        async move {
+            let blob = self.encrypt_blob(blob)?;
+            self.inner.put(self.name(name), &self.encrypt_meta(meta)?, blob).await
+        }.boxed()
+    }
+    fn get<'a>(&'a self, locator: &'a str) -> DriveFuture<'a, (String, Option<Vec<u8>>)> {
+        async move {
+            let (meta, blob) = self.inner.get(locator).await?;
+            self.decrypt_object(meta, blob)
+        }.boxed()
+    }
+    fn get_meta<'a>(&'a self, locator: &'a str) -> DriveFuture<'a, (String, String)> {
+        async move {
+            let (meta, version) = self.inner.get_meta(locator).await?;
+            match self.decrypt_meta(&meta)? {
+                Some((meta, _)) => Ok((meta, version)),
+                None => Ok((meta, version))
+            }
+        }.boxed()
+    }
+    fn update<'a>(&'a self, locator: &'a str, name: &'a str, meta: &'a str, blob: Option<Vec<u8>>) -> DriveFuture<'a, String> {
+        async move {
+            let blob = self.encrypt_blob(blob)?;
+            self.inner.update(locator, self.name(name), &self.encrypt_meta(meta)?, blob).await
+        }.boxed()
+    }
+    fn list<'a>(&'a self, cursor: Option<String>) -> DriveFuture<'a, (Vec<ObjectInfo>, Option<String>)> {
+        async move {
+            let (objects, next) = self.inner.list(cursor).await?;
+            Ok((self.decrypt_infos(objects), next))
+        }.boxed()
+    }
+    fn pin<'a>(&'a self, locator: &'a str) -> DriveFuture<'a, ()> {
+        self.inner.pin(locator)
+    }
+    fn pinned<'a>(&'a self) -> DriveFuture<'a, Vec<ObjectInfo>> {
+        async move {
+            Ok(self.decrypt_infos(self.inner.pinned().await?))
+        }.boxed()
+    }
+    fn delete<'a>(&'a self, locator: &'a str) -> DriveFuture<'a, ()> {
+        self.inner.delete(locator)
+    }
+    fn delete_many<'a>(&'a self, locators: &'a [String]) -> DriveFuture<'a, ()> {
+        self.inner.delete_many(locators)
+    }
+    /// The copies are made from the encrypted objects
+    /// The copies are decrypted before `check`, the ones that can't be are corrupted
+    fn replicate<'a>(&'a self, locator: &'a str, name: &'a str, check: Check<'a>) -> DriveFuture<'a, Option<String>> {
+        async move {
+            let check = |meta: &str, blob: Option<&[u8]>| match self.decrypt_object(meta.to_string(), blob.map(<[u8]>::to_vec)) {
+                Ok((meta, blob)) => check(&meta, blob.as_deref()),
+                Err(_) => false
+            };
+            self.inner.replicate(locator, self.name(name), &check).await
+        }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drives::discord::{mock, DiscordClient};
+
+    /// A drive recording the name and the sizes of the metadata and of the blob of the objects it stores
+    #[derive(Debug)]
+    struct Recorder {
+        inner: DiscordClient,
+        puts: std::sync::Mutex<Vec<(String, usize, Option<usize>)>>
+    }
+    impl Drive for Recorder {
+        fn max_blob_size(&self) -> usize {
+            self.inner.max_blob_size()
+        }
+        fn put<'a>(&'a self, name: &'a str, meta: &'a str, blob: Option<Vec<u8>>) -> DriveFuture<'a, String> {
+            self.puts.lock().unwrap().push((name.to_string(), meta.len(), blob.as_ref().map(Vec::len)));
+            self.inner.put(name, meta, blob)
+        }
+        fn get<'a>(&'a self, locator: &'a str) -> DriveFuture<'a, (String, Option<Vec<u8>>)> {
+            self.inner.get(locator)
+        }
+        fn get_meta<'a>(&'a self, locator: &'a str) -> DriveFuture<'a, (String, String)> {
+            self.inner.get_meta(locator)
+        }
+        fn update<'a>(&'a self, locator: &'a str, name: &'a str, meta: &'a str, blob: Option<Vec<u8>>) -> DriveFuture<'a, String> {
+            self.inner.update(locator, name, meta, blob)
+        }
+        fn list<'a>(&'a self, cursor: Option<String>) -> DriveFuture<'a, (Vec<ObjectInfo>, Option<String>)> {
+            self.inner.list(cursor)
+        }
+        fn pin<'a>(&'a self, locator: &'a str) -> DriveFuture<'a, ()> {
+            self.inner.pin(locator)
+        }
+        fn pinned<'a>(&'a self) -> DriveFuture<'a, Vec<ObjectInfo>> {
+            self.inner.pinned()
+        }
+        fn delete<'a>(&'a self, locator: &'a str) -> DriveFuture<'a, ()> {
+            self.inner.delete(locator)
+        }
+    }
+
+    async fn open(inner: Arc<dyn Drive>) -> EncryptedDrive {
+        EncryptedDrive::new(inner, 1, HashMap::from([(1, "passphrase".to_string())]), false).await.unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn salts_are_random_and_stored() {
+        let url = mock::start().await.unwrap();
+        let first: Arc<dyn Drive> = Arc::new(DiscordClient::new("token".to_string(), "first".to_string(), url.clone()));
+        let second: Arc<dyn Drive> = Arc::new(DiscordClient::new("token".to_string(), "second".to_string(), url));
+        let locator = open(first.clone()).await.put("file", "meta", Some(b"content".to_vec())).await.unwrap();
+        // The same passphrase gives another key on another drive
+        let meta = open(second).await.encrypt_meta("meta").unwrap();
+        assert!(open(first.clone()).await.decrypt_meta(&meta).is_err());
+        // The salt is read back when the drive is opened again
+        assert_eq!(open(first).await.get(&locator).await.unwrap(), ("meta".to_string(), Some(b"content".to_vec())));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn drives_encrypted_before_the_salts_keep_their_key() {
+        let url = mock::start().await.unwrap();
+        let inner: Arc<dyn Drive> = Arc::new(DiscordClient::new("token".to_string(), "channel".to_string(), url));
+        let legacy = EncryptedDrive {
+            inner: inner.clone(),
+            key_id: 1,
+            ciphers: HashMap::from([(1, derive_key("passphrase", 1, None).unwrap())]),
+            obfuscate: false
+        };
+        let locator = legacy.put("file", "meta", Some(b"content".to_vec())).await.unwrap();
+        assert_eq!(open(inner).await.get(&locator).await.unwrap(), ("meta".to_string(), Some(b"content".to_vec())));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn obfuscated_objects_look_alike() {
+        let url = mock::start().await.unwrap();
+        let recorder = Arc::new(Recorder {
+            inner: DiscordClient::new("token".to_string(), "channel".to_string(), url),
+            puts: std::sync::Mutex::new(Vec::new())
+        });
+        let drive = EncryptedDrive::new(recorder.clone(), 1, HashMap::from([(1, "passphrase".to_string())]), true).await.unwrap();
+        // The salts are stored before
+        recorder.puts.lock().unwrap().clear();
+
+        let entry_meta = format!("{{\"path\":\"/{}\"}}", "a".repeat(1500));
+        let entry = drive.put("entry", &entry_meta, None).await.unwrap();
+        let small = drive.put("chunk", "{}", Some(vec![1; 10])).await.unwrap();
+        let big = drive.put("chunk", "{}", Some(vec![2; 3000])).await.unwrap();
+
+        let puts = recorder.puts.lock().unwrap().clone();
+        assert_eq!(puts.len(), 3);
+        // Too long for the content of a message, like the metadata of every object
+        assert!(puts[0].1 > 2000);
+        for (name, meta_len, blob_len) in &puts {
+            assert_eq!(name, OBFUSCATED_NAME);
+            assert_eq!(*meta_len, puts[0].1);
+            assert_eq!(*blob_len, Some(MIN_PADDED_BLOB + KEY_ID_SIZE + NONCE_SIZE + TAG_SIZE));
+        }
+        assert_eq!(drive.get(&entry).await.unwrap(), (entry_meta, None));
+        assert_eq!(drive.get(&small).await.unwrap(), ("{}".to_string(), Some(vec![1; 10])));
+        assert_eq!(drive.get(&big).await.unwrap(), ("{}".to_string(), Some(vec![2; 3000])));
+        // The biggest blob fits in the padding
+        let biggest = drive.put("chunk", "{}", Some(vec![3; drive.max_blob_size()])).await.unwrap();
+        assert_eq!(drive.get(&biggest).await.unwrap().1.unwrap().len(), drive.max_blob_size());
+    }
+}
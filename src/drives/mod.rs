@@ -1 +1,162 @@
-pub mod discord;
\ No newline at end of file
+use std::fmt::Debug;
+use chrono::{DateTime, Utc};
+use futures::FutureExt;
+use futures::future::BoxFuture;
+use crate::error::Result;
+
+pub mod discord;
+pub mod encrypted;
+pub mod pool;
+
+pub type DriveFuture<'a, T> = BoxFuture<'a, Result<T>>;
+/// Whether a copy of an object is intact, from its metadata and its blob
+pub type Check<'a> = &'a (dyn Fn(&str, Option<&[u8]>) -> bool + Sync);
+
+/// Separator of the locators of the copies of a replicated object, in its locator
+pub const REPLICA_SEPARATOR: char = '|';
+
+/// Locators of the copies of an object, only its own locator unless the drive replicates it
+pub fn replicas(locator: &str) -> impl Iterator<Item = &str> {
+    locator.split(REPLICA_SEPARATOR)
+}
+
+/// An object found by `Drive::list`
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    pub locator: String,
+    pub meta: String,
+    pub version: String,
+    /// When the object was first stored
+    pub created: DateTime<Utc>
+}
+
+/// A remote storage backend.
+///
+/// A drive stores objects made of a metadata string and an optional opaque blob.
+/// Each object is identified by a locator whose format is specific to the drive (a message id for Discord).
+/// Objects also have a version which changes every time they are updated, it is empty until the first update.
+pub trait Drive: Debug + Send + Sync {
+    /// Maximum size of a blob, bigger files are split in chunks
+    fn max_blob_size(&self) -> usize;
+    /// Number of requests worth sending at the same time, more only wait for the rate limits
+    fn concurrency(&self) -> usize {
+        1
+    }
+    /// Store a new object and return its locator
+    fn put<'a>(&'a self, name: &'a str, meta: &'a str, blob: Option<Vec<u8>>) -> DriveFuture<'a, String>;
+    /// Get the metadata and the blob of an object
+    fn get<'a>(&'a self, locator: &'a str) -> DriveFuture<'a, (String, Option<Vec<u8>>)>;
+    /// Get only the metadata and the version of an object
+    fn get_meta<'a>(&'a self, locator: &'a str) -> DriveFuture<'a, (String, String)>;
+    /// Replace the metadata and the blob of an existing object and return its new version
+    fn update<'a>(&'a self, locator: &'a str, name: &'a str, meta: &'a str, blob: Option<Vec<u8>>) -> DriveFuture<'a, String>;
+    /// List the objects of the drive a page at a time, from the page at `cursor` or the first one.
+    /// Return the objects and the cursor of the next page, `None` after the last one.
+    fn list<'a>(&'a self, cursor: Option<String>) -> DriveFuture<'a, (Vec<ObjectInfo>, Option<String>)>;
+    /// Pin an object so that it can be found without listing the whole drive
+    fn pin<'a>(&'a self, locator: &'a str) -> DriveFuture<'a, ()>;
+    /// List the pinned objects
+    fn pinned<'a>(&'a self) -> DriveFuture<'a, Vec<ObjectInfo>>;
+    /// Delete an object
+    fn delete<'a>(&'a self, locator: &'a str) -> DriveFuture<'a, ()>;
+    /// Delete several objects, drives that support bulk deletion should override it
+    fn delete_many<'a>(&'a self, locators: &'a [String]) -> DriveFuture<'a, ()> {
+        async move {
+            for locator in locators {
+                self.delete(locator).await?;
+            }
+            Ok(())
+        }.boxed()
+    }
+    /// Store the object again where copies are missing or fail `check`, so that it has as many as the drive keeps, from one of the intact copies.
+    /// `name` is the name of the object. Return the new locator of the object if it changed.
+    /// Drives that don't replicate the objects have nothing to do.
+    fn replicate<'a>(&'a self, _locator: &'a str, _name: &'a str, _check: Check<'a>) -> DriveFuture<'a, Option<String>> {
+        async move {
+            Ok(None)
+        }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::drives::discord::{mock, DiscordClient};
+
+    /// Every object of the drive, following the pages
+    async fn list_all(drive: &dyn Drive) -> Vec<ObjectInfo> {
+        let mut objects = Vec::new();
+        let mut cursor = None;
+        loop {
+            let (page, next) = drive.list(cursor).await.unwrap();
+            objects.extend(page);
+            match next {
+                Some(next) => cursor = Some(next),
+                None => return objects
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn contract() {
+        let url = mock::start().await.unwrap();
+        let client = DiscordClient::new("token".to_string(), "channel".to_string(), url);
+        let drive: &dyn Drive = &client;
+
+        let with_blob = drive.put("chunk", "first", Some(vec![1, 2, 3])).await.unwrap();
+        let without_blob = drive.put("entry", "second", None).await.unwrap();
+        assert_eq!(drive.get(&with_blob).await.unwrap(), ("first".to_string(), Some(vec![1, 2, 3])));
+        assert_eq!(drive.get(&without_blob).await.unwrap(), ("second".to_string(), None));
+
+        // The version is empty until the first update and changes with every update
+        let (meta, version) = drive.get_meta(&without_blob).await.unwrap();
+        assert_eq!((meta.as_str(), version.as_str()), ("second", ""));
+        let updated = drive.update(&without_blob, "entry", "third", Some(vec![4])).await.unwrap();
+        assert_eq!(drive.get_meta(&without_blob).await.unwrap(), ("third".to_string(), updated.clone()));
+        assert_eq!(drive.get(&without_blob).await.unwrap().1, Some(vec![4]));
+        let updated_again = drive.update(&without_blob, "entry", "fourth", None).await.unwrap();
+        assert_ne!(updated, updated_again);
+
+        let listed = list_all(drive).await;
+        let mut locators: Vec<&str> = listed.iter().map(|o| o.locator.as_str()).collect();
+        locators.sort();
+        let mut expected = vec![with_blob.as_str(), without_blob.as_str()];
+        expected.sort();
+        assert_eq!(locators, expected);
+        assert_eq!(listed.iter().find(|o| o.locator == without_blob).unwrap().meta, "fourth");
+
+        assert!(drive.pinned().await.unwrap().is_empty());
+        drive.pin(&with_blob).await.unwrap();
+        let pinned = drive.pinned().await.unwrap();
+        assert_eq!(pinned.len(), 1);
+        assert_eq!((pinned[0].locator.as_str(), pinned[0].meta.as_str()), (with_blob.as_str(), "first"));
+
+        drive.delete(&with_blob).await.unwrap();
+        assert!(matches!(drive.get(&with_blob).await, Err(Error::NotFound)));
+        drive.delete_many(std::slice::from_ref(&without_blob)).await.unwrap();
+        assert!(list_all(drive).await.is_empty());
+        // Nothing to replicate with a single copy
+        assert_eq!(drive.replicate(&without_blob, "entry", &|_, _| true).await.unwrap(), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn list_pages() {
+        let url = mock::start().await.unwrap();
+        let drive = DiscordClient::new("token".to_string(), "channel".to_string(), url);
+        let mut locators = Vec::new();
+        for i in 0..150 {
+            locators.push(drive.put("entry", &i.to_string(), None).await.unwrap());
+        }
+        let mut listed: Vec<String> = list_all(&drive).await.into_iter().map(|o| o.locator).collect();
+        listed.sort();
+        locators.sort();
+        assert_eq!(listed, locators);
+    }
+
+    #[test]
+    fn replicas_of_a_locator() {
+        assert_eq!(replicas("a").collect::<Vec<_>>(), vec!["a"]);
+        assert_eq!(replicas("a|b:c").collect::<Vec<_>>(), vec!["a", "b:c"]);
+    }
+}
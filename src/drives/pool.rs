@@ -0,0 +1,248 @@
+//! Objects spread over several drives, to share the load between their rate limits
+//!
+//! The locators of the pool are `<key>:<locator>`, where `<key>` names the member holding the object (the id of its channel for Discord).
+//! The locators of the first member have no key, so that the ones stored before the pool was set up stay the same.
+//!
+//! The objects with a blob can be replicated in several members, their locator is then the list of the locators of their copies
+//! separated by `REPLICA_SEPARATOR`, and they are read from the first copy that can be.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use futures::FutureExt;
+use futures::future::{join_all, try_join_all};
+use crate::drives::{replicas, Check, Drive, DriveFuture, ObjectInfo, REPLICA_SEPARATOR};
+use crate::error::{Result, Error};
+
+#[derive(Debug)]
+pub struct PoolDrive {
+    /// Key and drive of each member
+    members: Vec<(String, Arc<dyn Drive>)>,
+    /// Number of members storing each blob
+    replicas: usize,
+    /// Member receiving the next object
+    next: AtomicUsize
+}
+impl PoolDrive {
+    /// `members` must not be empty, and the first one must stay first since it holds the objects stored before the pool.
+    /// Each blob is stored in `replicas` different members, at most all of them
+    pub fn new(members: Vec<(String, Arc<dyn Drive>)>, replicas: usize) -> Result<Self> {
+        if replicas == 0 || replicas > members.len() {
+            return Err(Error::TooManyReplicas(replicas))
+        }
+        Ok(Self {
+            members,
+            replicas,
+            next: AtomicUsize::new(0)
+        })
+    }
+    /// Locator of an object of a member in the pool
+    fn locator(&self, index: usize, locator: &str) -> String {
+        match index {
+            0 => locator.to_string(),
+            _ => format!("{}:{}", self.members[index].0, locator)
+        }
+    }
+    /// Index of the member holding a copy of an object, and the locator of the copy in this member
+    fn member<'a>(&self, locator: &'a str) -> Result<(usize, &'a str)> {
+        let Some((key, locator)) = locator.split_once(':') else {
+            return Ok((0, locator))
+        };
+        match self.members.iter().position(|(k, _)| k == key) {
+            Some(index) => Ok((index, locator)),
+            None => Err(Error::UnknownLocator(format!("{}:{}", key, locator)))
+        }
+    }
+    /// Drive and locator of each copy of an object
+    fn copies<'a>(&self, locator: &'a str) -> Result<Vec<(&dyn Drive, &'a str)>> {
+        replicas(locator).map(|replica| {
+            let (index, locator) = self.member(replica)?;
+            Ok((self.members[index].1.as_ref(), locator))
+        }).collect()
+    }
+    /// Objects of a member with their locators in the pool
+    fn in_pool(&self, index: usize, objects: Vec<ObjectInfo>) -> Vec<ObjectInfo> {
+        objects.into_iter().map(|mut o| {
+            o.locator = self.locator(index, &o.locator);
+            o
+        }).collect()
+    }
+    /// Store copies of an object in the members in turn, skipping `excluded`, until there are `count` of them.
+    /// Return the locators of the copies, or the last error if none could be stored
+    async fn put_copies(&self, name: &str, meta: &str, blob: Option<Vec<u8>>, count: usize, excluded: &[usize]) -> Result<Vec<String>> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut candidates = (0..self.members.len())
+            .map(|i| (start + i) % self.members.len())
+            .filter(|i| !excluded.contains(i));
+        let mut copies = Vec::new();
+        let mut error = None;
+        // The members that fail are replaced by the next ones
+        while copies.len() < count {
+            let batch: Vec<usize> = candidates.by_ref().take(count - copies.len()).collect();
+            if batch.is_empty() {
+                break
+            }
+            let results = join_all(batch.iter().map(|&i| self.members[i].1.put(name, meta, blob.clone()))).await;
+            for (i, result) in batch.into_iter().zip(results) {
+                match result {
+                    Ok(locator) => copies.push(self.locator(i, &locator)),
+                    Err(e) => error = Some(e)
+                }
+            }
+        }
+        match (copies.is_empty(), error) {
+            (true, Some(e)) => Err(e),
+            _ => Ok(copies)
+        }
+    }
+}
+
+impl Drive for PoolDrive {
+    fn max_blob_size(&self) -> usize {
+        self.members.iter().map(|(_, d)| d.max_blob_size()).min().unwrap_or_default()
+    }
+    fn concurrency(&self) -> usize {
+        self.members.iter().map(|(_, d)| d.concurrency()).sum::<usize>() / self.replicas
+    }
+    /// The objects are given to the members in turn. The ones with a blob are stored in `replicas` members,
+    /// fewer if some of them fail, until `replicate` completes them
+    fn put<'a>(&'a self, name: &'a str, meta: &'a str, blob: Option<Vec<u8>>) -> DriveFuture<'a, String> {
+        async move {
+            let count = match blob {
+                Some(_) => self.replicas,
+                None => 1
+            };
+            let copies = self.put_copies(name, meta, blob, count, &[]).await?;
+            if copies.len() < count {
+                eprintln!("Stored {} of the {} copies of {}", copies.len(), count, name);
+            }
+            Ok(copies.join(&REPLICA_SEPARATOR.to_string()))
+        }.boxed()
+    }
+    fn get<'a>(&'a self, locator: &'a str) -> DriveFuture<'a, (String, Option<Vec<u8>>)> {
+        async move {
+            let mut error = Error::NotFound;
+            for (drive, locator) in self.copies(locator)? {
+                match drive.get(locator).await {
+                    Ok(object) => return Ok(object),
+                    Err(e) => error = e
+                }
+            }
+            Err(error)
+        }.boxed()
+    }
+    fn get_meta<'a>(&'a self, locator: &'a str) -> DriveFuture<'a, (String, String)> {
+        async move {
+            let mut error = Error::NotFound;
+            for (drive, locator) in self.copies(locator)? {
+                match drive.get_meta(locator).await {
+                    Ok(meta) => return Ok(meta),
+                    Err(e) => error = e
+                }
+            }
+            Err(error)
+        }.boxed()
+    }
+    /// Every copy is updated, the version is the one of the first
+    fn update<'a>(&'a self, locator: &'a str, name: &'a str, meta: &'a str, blob: Option<Vec<u8>>) -> DriveFuture<'a, String> {
+        async move {
+            let copies = self.copies(locator)?;
+            let versions = try_join_all(copies.iter().map(|(drive, locator)| drive.update(locator, name, meta, blob.clone()))).await?;
+            Ok(versions.into_iter().next().unwrap_or_default())
+        }.boxed()
+    }
+    /// The members are listed one after the other, the cursor is `<index of the member>:<cursor in the member>`.
+    /// Each copy of a replicated object is listed on its own
+    fn list<'a>(&'a self, cursor: Option<String>) -> DriveFuture<'a, (Vec<ObjectInfo>, Option<String>)> {
+        async move {
+            let (index, cursor) = match &cursor {
+                Some(cursor) => {
+                    let (index, cursor) = cursor.split_once(':').ok_or(Error::BadContent)?;
+                    (index.parse::<usize>()?, Some(cursor.to_string()).filter(|c| !c.is_empty()))
+                },
+                None => (0, None)
+            };
+            let (_, drive) = self.members.get(index).ok_or(Error::BadContent)?;
+            let (objects, next) = drive.list(cursor).await?;
+            let next = match next {
+                Some(next) => Some(format!("{}:{}", index, next)),
+                None if index + 1 < self.members.len() => Some(format!("{}:", index + 1)),
+                None => None
+            };
+            Ok((self.in_pool(index, objects), next))
+        }.boxed()
+    }
+    fn pin<'a>(&'a self, locator: &'a str) -> DriveFuture<'a, ()> {
+        async move {
+            for (drive, locator) in self.copies(locator)? {
+                drive.pin(locator).await?;
+            }
+            Ok(())
+        }.boxed()
+    }
+    fn pinned<'a>(&'a self) -> DriveFuture<'a, Vec<ObjectInfo>> {
+        async move {
+            let pinned = try_join_all(self.members.iter().map(|(_, d)| d.pinned())).await?;
+            Ok(pinned.into_iter().enumerate().flat_map(|(index, objects)| self.in_pool(index, objects)).collect())
+        }.boxed()
+    }
+    fn delete<'a>(&'a self, locator: &'a str) -> DriveFuture<'a, ()> {
+        async move {
+            for (drive, locator) in self.copies(locator)? {
+                drive.delete(locator).await?;
+            }
+            Ok(())
+        }.boxed()
+    }
+    /// The objects of each member are deleted in bulk, all the members at the same time
+    fn delete_many<'a>(&'a self, locators: &'a [String]) -> DriveFuture<'a, ()> {
+        async move {
+            let mut batches = vec![Vec::new(); self.members.len()];
+            for replica in locators.iter().flat_map(|l| replicas(l)) {
+                let (index, locator) = self.member(replica)?;
+                batches[index].push(locator.to_string());
+            }
+            let results = join_all(self.members.iter().zip(&batches)
+                .filter(|(_, batch)| !batch.is_empty())
+                .map(|((_, drive), batch)| drive.delete_many(batch))).await;
+            results.into_iter().collect()
+        }.boxed()
+    }
+    /// The copies that can't be read or that fail `check` are dropped from the locator,
+    /// and new ones are stored in the members without a copy, from the first intact copy
+    fn replicate<'a>(&'a self, locator: &'a str, name: &'a str, check: Check<'a>) -> DriveFuture<'a, Option<String>> {
+        async move {
+            let copies: Vec<&str> = replicas(locator).collect();
+            // Nothing to copy a lost object from
+            if copies.len() == 1 && self.replicas == 1 {
+                return Ok(None)
+            }
+            let mut kept = Vec::new();
+            let mut holders = Vec::new();
+            let mut intact = None;
+            let mut error = Error::NotFound;
+            for copy in &copies {
+                let (index, inner) = self.member(copy)?;
+                if holders.contains(&index) {
+                    continue
+                }
+                match self.members[index].1.get(inner).await {
+                    Ok((meta, blob)) if check(&meta, blob.as_deref()) => {
+                        kept.push(copy.to_string());
+                        holders.push(index);
+                        intact.get_or_insert((meta, blob));
+                    },
+                    Ok(_) => error = Error::Corrupted(format!("copy {}", copy)),
+                    Err(e) => error = e
+                }
+            }
+            let Some((meta, blob)) = intact else {
+                return Err(error)
+            };
+            if kept.len() < self.replicas {
+                kept.extend(self.put_copies(name, &meta, blob, self.replicas - kept.len(), &holders).await?);
+            }
+            let replicated = kept.join(&REPLICA_SEPARATOR.to_string());
+            Ok(Some(replicated).filter(|r| r != locator))
+        }.boxed()
+    }
+}
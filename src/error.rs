@@ -11,9 +11,22 @@ pub enum Error {
     NotFound,
     DiscordAttachmentNotFound,
     FileContentIsNone,
-    DiscordMessageIdIsNone,
+    LocatorIsNone,
+    BlobNotFound,
     BadContent,
-    DiscordError
+    DiscordError,
+    UnknownCommand(String),
+    Crypto,
+    UnknownKey(u32),
+    MissingPassphrase,
+    Chunking(fastcdc::v2020::Error),
+    Corrupted(String),
+    InvalidSnapshotName(String),
+    SnapshotExists(String),
+    UnknownSnapshot(String),
+    PathExists(String),
+    UnknownLocator(String),
+    TooManyReplicas(usize)
 }
 
 impl Display for Error {
@@ -28,9 +41,22 @@ impl Display for Error {
             Self::NotFound => write!(f, "Not found"),
             Self::DiscordAttachmentNotFound => write!(f, "Discord attachment not found"),
             Self::FileContentIsNone => write!(f, "File content is none"),
-            Self::DiscordMessageIdIsNone => write!(f, "Discord message id is none"),
+            Self::LocatorIsNone => write!(f, "Locator is none"),
+            Self::BlobNotFound => write!(f, "Blob not found"),
             Self::BadContent => write!(f, "Bad content"),
-            Self::DiscordError => write!(f, "Discord error")
+            Self::DiscordError => write!(f, "Discord error"),
+            Self::UnknownCommand(c) => write!(f, "Unknown command: {}", c),
+            Self::Crypto => write!(f, "Encryption error"),
+            Self::UnknownKey(id) => write!(f, "Unknown encryption key: {}", id),
+            Self::MissingPassphrase => write!(f, "Obfuscation requires an encryption passphrase"),
+            Self::Chunking(e) => write!(f, "Chunking error: {}", e),
+            Self::Corrupted(what) => write!(f, "Corrupted content: {}", what),
+            Self::InvalidSnapshotName(name) => write!(f, "Invalid snapshot name: {}", name),
+            Self::SnapshotExists(name) => write!(f, "Snapshot already exists: {}", name),
+            Self::UnknownSnapshot(name) => write!(f, "Unknown snapshot: {}", name),
+            Self::PathExists(path) => write!(f, "Path already exists: {}", path),
+            Self::UnknownLocator(locator) => write!(f, "Unknown locator: {}", locator),
+            Self::TooManyReplicas(replicas) => write!(f, "Not enough channels to keep {} replicas", replicas)
         }
     }
 }
@@ -77,10 +103,21 @@ impl From<std::num::ParseIntError> for Error {
     }
 }
 
+impl From<fastcdc::v2020::Error> for Error {
+    fn from(value: fastcdc::v2020::Error) -> Self {
+        Self::Chunking(value)
+    }
+}
+
 impl From<Error> for webdav_handler::fs::FsError {
     fn from(value: Error) -> Self {
-        dbg!(value);
-        webdav_handler::fs::FsError::GeneralFailure
+        match value {
+            Error::NotFound => webdav_handler::fs::FsError::NotFound,
+            value => {
+                dbg!(value);
+                webdav_handler::fs::FsError::GeneralFailure
+            }
+        }
     }
 }
 
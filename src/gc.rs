@@ -0,0 +1,153 @@
+//! Deletion of the objects of the drive that nothing references anymore
+
+use std::collections::HashSet;
+use std::time::Duration;
+use chrono::Utc;
+use crate::db::DB;
+use crate::drives::{replicas, Drive, ObjectInfo};
+use crate::error::Result;
+use crate::types::{BackupChunkMeta, BackupMeta, BlobMeta, ChunkMeta, EntryMeta};
+
+/// What `collect` found in the drive
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Unreferenced objects, deleted unless it was a dry run
+    pub garbage: Vec<String>,
+    /// Unreferenced objects that were kept, and why
+    pub kept: Vec<String>
+}
+
+/// Delete the objects of the drive that are referenced neither by the database nor by a backup, left by failed uploads or crashes.
+///
+/// Some unreferenced objects are kept:
+/// the ones created during the last `grace_period`, which can belong to an upload that is not recorded yet,
+/// the entries whose path is not in the database and their chunks, which can come from another server sharing the drive,
+/// and the objects that can't be read, which can be encrypted with a key that is not configured.
+pub async fn collect(db: &DB, drive: &dyn Drive, grace_period: Duration, dry_run: bool) -> Result<Report> {
+    let mut objects: Vec<ObjectInfo> = Vec::new();
+    let mut cursor = None;
+    loop {
+        let (page, next) = drive.list(cursor).await?;
+        objects.extend(page);
+        match next {
+            Some(next) => cursor = Some(next),
+            None => break
+        }
+    }
+
+    // Read after listing, so that the objects recorded in the meantime are referenced
+    let mut referenced = db.get_referenced_objects().await?;
+    let paths: HashSet<String> = db.get_dir_entries().await?.into_iter().map(|e| e.path).collect();
+    for object in drive.pinned().await? {
+        if let Ok(backup) = serde_json::from_str::<BackupMeta>(&object.meta) {
+            referenced.extend(backup.chunks.into_iter().map(|c| c.locator));
+        }
+        referenced.insert(object.locator);
+    }
+
+    let mut report = Report::default();
+    let mut foreign = Vec::new();
+    for object in &objects {
+        if let Ok(EntryMeta { path: Some(path), chunks, .. }) = serde_json::from_str(&object.meta) {
+            if !referenced.contains(&object.locator) && !paths.contains(&path) {
+                referenced.extend(chunks.unwrap_or_default().into_iter().map(|c| c.locator));
+                foreign.push(object.locator.clone());
+                report.kept.push(format!("{}: entry {} is not in the database, rebuild-index can restore it", object.locator, path));
+            }
+        }
+    }
+    referenced.extend(foreign);
+    // The objects are listed copy by copy
+    let referenced: HashSet<&str> = referenced.iter().flat_map(|l| replicas(l)).collect();
+
+    for object in objects {
+        if referenced.contains(object.locator.as_str()) {
+            continue
+        }
+        // Negative if the clocks differ
+        let age = Utc::now().signed_duration_since(object.created).to_std().unwrap_or_default();
+        if age < grace_period {
+            report.kept.push(format!("{}: created during the grace period", object.locator));
+            continue
+        }
+        let known = serde_json::from_str::<EntryMeta>(&object.meta).is_ok()
+            || serde_json::from_str::<BlobMeta>(&object.meta).is_ok()
+            || serde_json::from_str::<ChunkMeta>(&object.meta).is_ok()
+            || serde_json::from_str::<BackupMeta>(&object.meta).is_ok()
+            || serde_json::from_str::<BackupChunkMeta>(&object.meta).is_ok();
+        if !known {
+            report.kept.push(format!("{}: unknown object", object.locator));
+            continue
+        }
+        report.garbage.push(object.locator);
+    }
+
+    if !dry_run {
+        drive.delete_many(&report.garbage).await?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dav::{Options, BLOB_NAME};
+    use crate::tests::{content, Server};
+    use crate::types::{Chunk, Metadata};
+
+    async fn put_chunk(drive: &dyn Drive, hash: &str) -> String {
+        let meta = serde_json::to_string(&BlobMeta { hash: hash.to_string() }).unwrap();
+        drive.put(BLOB_NAME, &meta, Some(b"data".to_vec())).await.unwrap()
+    }
+
+    async fn put_entry(drive: &dyn Drive, path: &str, chunks: Vec<Chunk>) -> String {
+        let meta = EntryMeta {
+            metadata: Metadata { len: 4, modified: Some(Utc::now()), is_dir: false },
+            path: Some(path.to_string()),
+            chunks: Some(chunks),
+            checksum: None,
+            trashed: None
+        };
+        drive.put("entry", &serde_json::to_string(&meta).unwrap(), None).await.unwrap()
+    }
+
+    fn sorted(mut locators: Vec<String>) -> Vec<String> {
+        locators.sort();
+        locators
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn collect_garbage() {
+        let server = Server::start(Options::default()).await;
+        let data = content(20, 10_000);
+        server.write("/file", &data).await;
+        let drive = server.drive.as_ref();
+        let orphan = put_chunk(drive, "orphan").await;
+        // An object of an entry of the database that was replaced
+        let stale = put_entry(drive, "/file", Vec::new()).await;
+        let foreign_chunk = put_chunk(drive, "foreign").await;
+        let foreign = put_entry(drive, "/foreign", vec![Chunk { locator: foreign_chunk.clone(), size: 4, compressed: false, hash: None }]).await;
+        let unknown = drive.put("other", "not json", None).await.unwrap();
+
+        let report = collect(&server.db, drive, Duration::from_secs(3600), false).await.unwrap();
+        assert!(report.garbage.is_empty());
+        assert_eq!(report.kept.iter().filter(|k| k.ends_with("created during the grace period")).count(), 3);
+
+        let report = collect(&server.db, drive, Duration::ZERO, true).await.unwrap();
+        assert_eq!(sorted(report.garbage), sorted(vec![orphan.clone(), stale.clone()]));
+        assert!(drive.get(&orphan).await.is_ok());
+
+        let report = collect(&server.db, drive, Duration::ZERO, false).await.unwrap();
+        assert_eq!(sorted(report.garbage), sorted(vec![orphan.clone(), stale.clone()]));
+        assert!(report.kept.contains(&format!("{}: entry /foreign is not in the database, rebuild-index can restore it", foreign)));
+        assert!(report.kept.contains(&format!("{}: unknown object", unknown)));
+        for deleted in [&orphan, &stale] {
+            assert!(drive.get(deleted).await.is_err());
+        }
+        for kept in [&foreign, &foreign_chunk, &unknown] {
+            assert!(drive.get(kept).await.is_ok());
+        }
+        server.clear_cache();
+        assert!(server.read("/file").await == data);
+    }
+}
@@ -0,0 +1,253 @@
+//! Reconstruction of the database from the objects of the drive
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use crate::db::DB;
+use crate::drives::{replicas, Drive};
+use crate::error::Result;
+use crate::types::{BackupChunkMeta, BackupMeta, BlobMeta, ChunkMeta, EntryMeta, IndexedEntry, Metadata};
+
+/// What `rebuild` found in the drive
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Number of entries in the new index
+    pub entries: usize,
+    /// Number of entries put back in the trash
+    pub trashed: usize,
+    /// Entries that could not be restored as they were
+    pub conflicts: Vec<String>,
+    /// Objects that are not part of any restored entry
+    pub orphans: Vec<String>
+}
+
+/// Replace the entries of the database by the ones stored in the drive.
+///
+/// When several objects claim the same path, the most recently modified one is kept.
+/// The entries marked as moved to the trash are put back in the trash, they never claim a path of the tree.
+/// Nothing is deleted from the drive, the conflicting and orphaned objects are only reported.
+pub async fn rebuild(db: &DB, drive: &dyn Drive) -> Result<Report> {
+    let mut report = Report::default();
+    let mut found: HashMap<String, IndexedEntry> = HashMap::new();
+    let mut trashed = Vec::new();
+    let mut chunk_objects = HashSet::new();
+
+    let mut cursor = None;
+    loop {
+        let (objects, next) = drive.list(cursor).await?;
+        for object in objects {
+            if serde_json::from_str::<BlobMeta>(&object.meta).is_ok() || serde_json::from_str::<ChunkMeta>(&object.meta).is_ok() {
+                chunk_objects.insert(object.locator);
+                continue
+            }
+            // Backups of the database are not part of the tree
+            if serde_json::from_str::<BackupMeta>(&object.meta).is_ok() || serde_json::from_str::<BackupChunkMeta>(&object.meta).is_ok() {
+                continue
+            }
+            let meta = match serde_json::from_str::<EntryMeta>(&object.meta) {
+                Ok(EntryMeta { path: Some(path), metadata, chunks, checksum, trashed: deleted }) => IndexedEntry {
+                    path,
+                    metadata,
+                    locator: Some(object.locator),
+                    version: Some(object.version),
+                    chunks: chunks.unwrap_or_default(),
+                    checksum,
+                    trashed: deleted
+                },
+                Ok(_) => {
+                    report.orphans.push(format!("{}: sent by an older version without its path", object.locator));
+                    continue
+                },
+                Err(_) => {
+                    report.orphans.push(format!("{}: unknown object", object.locator));
+                    continue
+                }
+            };
+            if meta.trashed.is_some() {
+                trashed.push(meta);
+                continue
+            }
+            match found.get(&meta.path) {
+                Some(other) if other.metadata.modified >= meta.metadata.modified => {
+                    report.conflicts.push(format!("{}: {} ignored, {} is more recent", meta.path, meta.locator.unwrap_or_default(), other.locator.clone().unwrap_or_default()));
+                },
+                Some(other) => {
+                    report.conflicts.push(format!("{}: {} ignored, {} is more recent", meta.path, other.locator.clone().unwrap_or_default(), meta.locator.clone().unwrap_or_default()));
+                    found.insert(meta.path.clone(), meta);
+                },
+                None => {
+                    found.insert(meta.path.clone(), meta);
+                }
+            }
+        }
+        match next {
+            Some(next) => cursor = Some(next),
+            None => break
+        }
+    }
+
+    // Parents first
+    let mut found: Vec<IndexedEntry> = found.into_values().filter(|e| e.path != "/").collect();
+    found.sort_by_key(|e| (e.path.matches('/').count(), e.path.clone()));
+
+    // Whether each placed path is a directory
+    let mut placed = HashMap::from([("/".to_string(), true)]);
+    let mut entries = Vec::new();
+    let mut used_chunks = HashSet::new();
+    for entry in found {
+        let mut missing = Vec::new();
+        let mut parent = Path::new(&entry.path).parent();
+        let mut parent_is_dir = true;
+        while let Some(path) = parent.and_then(|p| p.to_str()) {
+            match placed.get(path) {
+                Some(is_dir) => {
+                    parent_is_dir = *is_dir;
+                    break
+                },
+                None => missing.push(path.to_string())
+            }
+            parent = Path::new(path).parent();
+        }
+        if !parent_is_dir {
+            report.conflicts.push(format!("{}: {} ignored, its parent is a file", entry.path, entry.locator.unwrap_or_default()));
+            continue
+        }
+        // Directories that were not stored in the drive
+        for path in missing.into_iter().rev() {
+            placed.insert(path.clone(), true);
+            entries.push(IndexedEntry {
+                path,
+                metadata: Metadata { len: 0, modified: None, is_dir: true },
+                locator: None,
+                version: None,
+                chunks: Vec::new(),
+                checksum: None,
+                trashed: None
+            });
+        }
+        for chunk in &entry.chunks {
+            // The objects are listed copy by copy
+            if !replicas(&chunk.locator).any(|r| chunk_objects.contains(r)) {
+                report.conflicts.push(format!("{}: chunk {} is missing", entry.path, chunk.locator));
+            }
+            used_chunks.extend(replicas(&chunk.locator).map(String::from));
+        }
+        placed.insert(entry.path.clone(), entry.metadata.is_dir);
+        entries.push(entry);
+    }
+
+    report.entries = entries.len();
+    report.trashed = trashed.len();
+    for entry in trashed {
+        for chunk in &entry.chunks {
+            if !replicas(&chunk.locator).any(|r| chunk_objects.contains(r)) {
+                report.conflicts.push(format!("{} in the trash: chunk {} is missing", entry.path, chunk.locator));
+            }
+            used_chunks.extend(replicas(&chunk.locator).map(String::from));
+        }
+        entries.push(entry);
+    }
+
+    for locator in chunk_objects.difference(&used_chunks) {
+        report.orphans.push(format!("{}: chunk of no entry", locator));
+    }
+
+    db.replace_dir_entries(entries).await?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use crate::drives::discord::{mock, DiscordClient};
+    use crate::types::Chunk;
+
+    async fn drive() -> DiscordClient {
+        let url = mock::start().await.unwrap();
+        DiscordClient::new("token".to_string(), "channel".to_string(), url)
+    }
+
+    /// A database with only the root
+    async fn db() -> DB {
+        let db = DB::new(None).await;
+        db.insert_dir_entry(None, "/".to_string(), Metadata { len: 0, modified: None, is_dir: true }).await.unwrap();
+        db
+    }
+
+    /// Store the object of an entry modified at `modified` seconds
+    async fn put_entry(drive: &dyn Drive, path: &str, is_dir: bool, modified: i64, chunks: Vec<Chunk>) -> String {
+        let meta = EntryMeta {
+            metadata: Metadata { len: chunks.iter().map(|c| c.size).sum(), modified: Some(Utc.timestamp_opt(modified, 0).unwrap()), is_dir },
+            path: Some(path.to_string()),
+            chunks: Some(chunks),
+            checksum: None,
+            trashed: None
+        };
+        drive.put("entry", &serde_json::to_string(&meta).unwrap(), None).await.unwrap()
+    }
+
+    async fn put_chunk(drive: &dyn Drive, hash: &str) -> Chunk {
+        let meta = serde_json::to_string(&BlobMeta { hash: hash.to_string() }).unwrap();
+        let locator = drive.put("chunk", &meta, Some(b"data".to_vec())).await.unwrap();
+        Chunk { locator, size: 4, compressed: false, hash: Some(hash.to_string()) }
+    }
+
+    async fn locator_of(db: &DB, path: &str) -> Option<String> {
+        db.get_dir_entry_by_path(path.to_string()).await.unwrap().unwrap().locator
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn most_recent_wins() {
+        let drive = drive().await;
+        let db = db().await;
+        put_entry(&drive, "/a", false, 10, Vec::new()).await;
+        let newest = put_entry(&drive, "/a", false, 30, Vec::new()).await;
+        put_entry(&drive, "/a", false, 20, Vec::new()).await;
+        let report = rebuild(&db, &drive).await.unwrap();
+        assert_eq!(report.entries, 1);
+        assert_eq!(report.conflicts.len(), 2);
+        assert!(report.conflicts.iter().all(|c| c.contains(&format!("{} is more recent", newest))));
+        assert_eq!(locator_of(&db, "/a").await, Some(newest));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn parents() {
+        let drive = drive().await;
+        let db = db().await;
+        let file = put_entry(&drive, "/dir/sub/file", false, 10, Vec::new()).await;
+        put_entry(&drive, "/other", false, 10, Vec::new()).await;
+        let child = put_entry(&drive, "/other/child", false, 10, Vec::new()).await;
+        let report = rebuild(&db, &drive).await.unwrap();
+        // The missing directories are created
+        assert_eq!(report.entries, 4);
+        for dir in ["/dir", "/dir/sub"] {
+            let entry = db.get_dir_entry_by_path(dir.to_string()).await.unwrap().unwrap();
+            assert!(entry.metadata.is_dir && entry.locator.is_none());
+        }
+        assert_eq!(locator_of(&db, "/dir/sub/file").await, Some(file));
+        // The children of a file are ignored
+        assert!(db.get_dir_entry_by_path("/other/child".to_string()).await.unwrap().is_none());
+        assert_eq!(report.conflicts, vec![format!("/other/child: {} ignored, its parent is a file", child)]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn chunks_and_orphans() {
+        let drive = drive().await;
+        let db = db().await;
+        let used = put_chunk(&drive, "used").await;
+        let orphan = put_chunk(&drive, "orphan").await;
+        let missing = Chunk { locator: "404".to_string(), size: 4, compressed: false, hash: None };
+        put_entry(&drive, "/file", false, 10, vec![used.clone(), missing]).await;
+        let unknown = drive.put("entry", "not json", None).await.unwrap();
+        let report = rebuild(&db, &drive).await.unwrap();
+        assert_eq!(report.entries, 1);
+        assert_eq!(report.conflicts, vec!["/file: chunk 404 is missing".to_string()]);
+        let mut orphans = report.orphans.clone();
+        orphans.sort();
+        let mut expected = vec![format!("{}: chunk of no entry", orphan.locator), format!("{}: unknown object", unknown)];
+        expected.sort();
+        assert_eq!(orphans, expected);
+        let id = db.get_dir_entry_by_path("/file".to_string()).await.unwrap().unwrap().id;
+        assert_eq!(db.get_chunks_by_entry_id(id).await.unwrap()[0].locator, used.locator);
+    }
+}
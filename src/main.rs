@@ -1,12 +1,26 @@
+mod backup;
+mod cache;
+mod compression;
+mod config;
+mod dav;
 mod drives;
 mod db;
 mod error;
+mod gc;
+mod index;
+mod replication;
+mod scrub;
 mod types;
+#[cfg(test)]
+mod tests;
 
 use types::Metadata;
-use error::Result;
-use std::{env, fs};
+use drives::Drive;
+use error::{Error, Result};
+use std::fs;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use actix_web::{web, App, HttpServer};
 use webdav_handler::actix::*;
 use webdav_handler::{fakels::FakeLs, DavConfig, DavHandler};
@@ -22,27 +36,230 @@ pub async fn dav_handler(req: DavRequest, davhandler: web::Data<DavHandler>) ->
     }
 }
 
+/// Rebuild the database from the objects of the drive, after it was lost.
+/// The versions, the snapshots and the trash are lost, unless `force` is set it is refused when there are some
+async fn rebuild_index(db: &db::DB, drive: &dyn Drive, cache: &cache::Cache, force: bool) -> Result<()> {
+    if db.get_cache_entries().await?.iter().any(|e| e.dirty) {
+        eprintln!("Some files were not sent to the drive yet, start the server to send them before rebuilding the index");
+        return Ok(())
+    }
+    if !force && db.has_history().await? {
+        eprintln!("The versions, the snapshots and the trash would be lost, use --force to rebuild the index anyway");
+        return Ok(())
+    }
+    let report = index::rebuild(db, drive).await?;
+    // The ids of the entries changed
+    cache.clear().await?;
+    for conflict in &report.conflicts {
+        println!("Conflict: {}", conflict);
+    }
+    for orphan in &report.orphans {
+        println!("Orphan: {}", orphan);
+    }
+    println!("Rebuilt the index with {} entries and {} in the trash", report.entries, report.trashed);
+    Ok(())
+}
+
+/// Replace the database by the newest backup stored in the drive
+async fn restore(db: &db::DB, drive: &dyn Drive, cache: &cache::Cache) -> Result<()> {
+    let created = backup::restore(db, drive).await?;
+    // The backup can come from an older version
+    db.create_tables().await;
+    // The ids of the entries can be different
+    cache.clear().await?;
+    println!("Restored the backup of {}, the files sent after it can be found with rebuild-index", created);
+    Ok(())
+}
+
+/// Check the content stored in the drive, and repair what can be if `repair` is set
+async fn scrub(fs: &dav::DriveFs, repair: bool) -> Result<()> {
+    let report = scrub::scrub(fs, repair).await?;
+    for problem in &report.problems {
+        println!("Problem: {}", problem);
+    }
+    for repaired in &report.repaired {
+        println!("Repaired: {}", repaired);
+    }
+    println!("Checked {} entries, found {} problems and repaired {}", report.entries, report.problems.len(), report.repaired.len());
+    Ok(())
+}
+
+/// Delete the objects of the drive that nothing references, or only list them if `dry_run` is set
+async fn gc(db: &db::DB, drive: &dyn Drive, grace_period: Duration, dry_run: bool) -> Result<()> {
+    let report = gc::collect(db, drive, grace_period, dry_run).await?;
+    for kept in &report.kept {
+        println!("Kept: {}", kept);
+    }
+    for garbage in &report.garbage {
+        println!("Garbage: {}", garbage);
+    }
+    match dry_run {
+        true => println!("Found {} unreferenced objects", report.garbage.len()),
+        false => println!("Deleted {} unreferenced objects", report.garbage.len())
+    }
+    Ok(())
+}
+
+/// Store the missing copies of the blobs
+async fn replicate(fs: &dav::DriveFs) -> Result<()> {
+    let report = replication::replicate(fs).await?;
+    for replicated in &report.replicated {
+        println!("Replicated: {}", replicated);
+    }
+    for failed in &report.failed {
+        println!("Failed: {}", failed);
+    }
+    println!("Checked {} blobs, replicated {} and failed to replicate {}", report.blobs, report.replicated.len(), report.failed.len());
+    Ok(())
+}
+
+/// Create, list or remove the snapshots of the tree, which are read under `/.snapshots/<name>`
+async fn snapshot(db: &db::DB, args: &[String]) -> Result<()> {
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["create", name] => {
+            if name.is_empty() || name.contains('/') {
+                return Err(Error::InvalidSnapshotName(name.to_string()))
+            }
+            if db.get_cache_entries().await?.iter().any(|e| e.dirty) {
+                eprintln!("Some files were not sent to the drive yet, the snapshot holds their last sent content");
+            }
+            if !db.create_snapshot(name.to_string()).await? {
+                return Err(Error::SnapshotExists(name.to_string()))
+            }
+            println!("Created the snapshot {}", name);
+        },
+        ["list"] => {
+            for snapshot in db.get_snapshots().await? {
+                println!("{}\t{}", snapshot.name, snapshot.created);
+            }
+        },
+        ["remove", name] => {
+            if !db.remove_snapshot(name.to_string()).await? {
+                return Err(Error::UnknownSnapshot(name.to_string()))
+            }
+            // The objects are deleted by the next purge of the server
+            println!("Removed the snapshot {}", name);
+        },
+        _ => return Err(Error::UnknownCommand(format!("snapshot {}", args.join(" "))))
+    }
+    Ok(())
+}
+
+/// List, put back or delete for good the entries removed while the trash is enabled, which are read under `/.trash`
+async fn trash(db: &db::DB, args: &[String]) -> Result<()> {
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["list"] => {
+            for entry in db.get_trash_entries().await? {
+                println!("{}\t{}", entry.path, entry.deleted);
+            }
+        },
+        ["restore", path] => {
+            if !db.has_trash_entries(path.to_string()).await? {
+                return Err(Error::NotFound)
+            }
+            if db.get_dir_entry_by_path(path.to_string()).await?.is_some() {
+                return Err(Error::PathExists(path.to_string()))
+            }
+            // Put back at its path, which its objects still hold
+            db.restore_trash(path.to_string(), path.to_string()).await?;
+            println!("Restored {}", path);
+        },
+        ["empty"] => {
+            let count = db.empty_trash(None).await?;
+            // The objects are deleted by the next purge of the server
+            println!("Deleted {} entries from the trash", count);
+        },
+        _ => return Err(Error::UnknownCommand(format!("trash {}", args.join(" "))))
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
-    dotenv::from_filename("config.env").unwrap();
+    dotenv::from_filename("config.env").ok();
+    let config = config::Config::from_env();
 
     let addr = "127.0.0.1:4918";
-    let cache = "./cache";
+    let cache_dir = config.get("CACHE_DIR").unwrap_or("./cache".to_string());
+    // In MiB
+    let cache_size: u64 = match config.get("CACHE_SIZE") {
+        Some(size) => size.parse()?,
+        None => 1024
+    };
 
-    if fs::metadata(cache).is_err() {
-        fs::create_dir(cache)?;
+    if fs::metadata(&cache_dir).is_err() {
+        fs::create_dir(&cache_dir)?;
     }
 
     let db = Arc::new(db::DB::new(Some("test.db")).await);
     db.create_tables().await;
-    db.insert_dir_entry(None, "/".to_string(), Metadata {
-        len: 0,
-        modified: None,
-        is_dir: true
-    }).await?;
+    if db.get_dir_entry_by_path("/".to_string()).await?.is_none() {
+        db.insert_dir_entry(None, "/".to_string(), Metadata {
+            len: 0,
+            modified: None,
+            is_dir: true
+        }).await?;
+    }
+
+    let api_url = if config.flag("DISCORD_MOCK") {
+        drives::discord::mock::start().await?
+    } else {
+        config.get("DISCORD_API_URL").unwrap_or(drives::discord::DEFAULT_API_URL.to_string())
+    };
+    // Number of channels storing each blob
+    let replicas: usize = match config.get("REPLICAS") {
+        Some(replicas) => replicas.parse()?,
+        None => 1
+    };
+    let client = drives::discord::DiscordClient::pool(&config.list("DISCORD_TOKEN")?, &config.list("DISCORD_CHANNEL")?, api_url, replicas)?;
+    let drive = drives::encrypted::EncryptedDrive::from_config(client, &config).await?;
+    let cache = cache::Cache::new(PathBuf::from(cache_dir), cache_size * 1024 * 1024, db.clone());
+    cache.scan().await?;
 
-    let d_fs = drives::discord::DiscordFs::new(db, env::var("DISCORD_TOKEN")?, env::var("DISCORD_CHANNEL")?);
+    match config.args.first().map(String::as_str) {
+        None | Some("serve") => {},
+        Some("rebuild-index") => return rebuild_index(&db, drive.as_ref(), &cache, config.flag("FORCE")).await,
+        Some("backup") => return backup::backup(&db, drive.as_ref()).await,
+        Some("restore") => return restore(&db, drive.as_ref(), &cache).await,
+        Some("gc") => {
+            // In seconds
+            let grace_period = match config.get("GC_GRACE_PERIOD") {
+                Some(period) => period.parse()?,
+                None => 60 * 60
+            };
+            return gc(&db, drive.as_ref(), Duration::from_secs(grace_period), config.flag("DRY_RUN")).await
+        },
+        Some("snapshot") => return snapshot(&db, &config.args[1..]).await,
+        Some("trash") => return trash(&db, &config.args[1..]).await,
+        Some("replicate") => return replicate(&dav::DriveFs::new(db, drive, cache, dav::Options::from_config(&config)?)).await,
+        Some("scrub") => return scrub(&dav::DriveFs::new(db, drive, cache, dav::Options::from_config(&config)?), config.flag("REPAIR")).await,
+        Some(command) => return Err(Error::UnknownCommand(command.to_string()))
+    }
+
+    // In seconds, 0 to disable the backups
+    let backup_interval: u64 = match config.get("BACKUP_INTERVAL") {
+        Some(interval) => interval.parse()?,
+        None => 60 * 60
+    };
+    if backup_interval > 0 {
+        backup::spawn(db.clone(), drive.clone(), Duration::from_secs(backup_interval));
+    }
+
+    // In seconds, 0 to disable the replication of the blobs missing copies
+    let replicate_interval: u64 = match config.get("REPLICATE_INTERVAL") {
+        Some(interval) => interval.parse()?,
+        None => 24 * 60 * 60
+    };
+    let d_fs = dav::DriveFs::new(db, drive, cache, dav::Options::from_config(&config)?);
+    if replicas > 1 && replicate_interval > 0 {
+        replication::spawn(d_fs.clone(), Duration::from_secs(replicate_interval));
+    }
+    // Finish the uploads and deletions interrupted by the last shutdown
+    d_fs.spawn_send_dirty();
+    d_fs.spawn_send_outdated();
+    d_fs.spawn_purge();
+    d_fs.spawn_expire_trash();
 
     let dav_server = DavHandler::builder()
         .filesystem(Box::new(d_fs))
@@ -0,0 +1,53 @@
+//! Completion of the copies of the blobs, when the drive keeps several of each
+
+use std::time::Duration;
+use crate::dav::{DriveFs, BLOB_NAME};
+use crate::error::Result;
+
+/// What `replicate` did
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Number of blobs checked
+    pub blobs: usize,
+    /// Blobs whose copies changed
+    pub replicated: Vec<String>,
+    /// Blobs that could not be replicated, and why
+    pub failed: Vec<String>
+}
+
+/// Check the copies of every blob of the database against its hash and store new ones where some are missing or corrupted.
+///
+/// The blobs whose copies changed get their new locator, and the objects of the entries using them are sent again.
+/// The copies that are dropped are deleted with the purge of the server.
+pub async fn replicate(fs: &DriveFs) -> Result<Report> {
+    let mut report = Report::default();
+    for blob in fs.db().get_blobs().await? {
+        report.blobs += 1;
+        let hash = blob.hash.clone().unwrap_or_default();
+        match fs.drive().replicate(&blob.locator, BLOB_NAME, &|meta, content| blob.is_intact(meta, content)).await {
+            Ok(Some(locator)) => {
+                fs.db().replace_chunk_object(blob.locator.clone(), locator.clone(), blob.compressed).await?;
+                report.replicated.push(format!("{}: {} replaced by {}", hash, blob.locator, locator));
+            },
+            Ok(None) => {},
+            Err(e) => report.failed.push(format!("{}: {}: {}", hash, blob.locator, e))
+        }
+    }
+    fs.send_outdated().await?;
+    Ok(report)
+}
+
+/// Replicate the blobs every `interval`
+pub fn spawn(fs: DriveFs, interval: Duration) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            match replicate(&fs).await {
+                Ok(report) => for failed in report.failed {
+                    eprintln!("Failed to replicate {}", failed);
+                },
+                Err(e) => eprintln!("Failed to replicate the blobs: {}", e)
+            }
+        }
+    });
+}
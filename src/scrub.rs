@@ -0,0 +1,166 @@
+//! Verification of the content stored in the drive
+
+use crate::dav::{DriveFile, DriveFs};
+use crate::drives::replicas;
+use crate::error::{Error, Result};
+use crate::types::File;
+
+/// What `scrub` found in the drive
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Number of entries checked
+    pub entries: usize,
+    /// Objects that could not be read or whose content does not match its checksum
+    pub problems: Vec<String>,
+    /// Problems that were repaired
+    pub repaired: Vec<String>
+}
+
+/// Download the objects of every entry and check them against the checksums of the database.
+///
+/// Every copy of the replicated chunks is checked.
+/// With `repair`, the lost objects of the entries are sent again from the database,
+/// and the missing or corrupted copies of the chunks from an intact copy, or from the cached copy when it is intact.
+pub async fn scrub(fs: &DriveFs, repair: bool) -> Result<Report> {
+    let mut report = Report::default();
+    for entry in fs.db().get_dir_entries().await? {
+        report.entries += 1;
+        let mut file = DriveFile::new(File {
+            dir_entry: entry,
+            cached: None,
+            cursor_pos: 0
+        }, fs.clone());
+        let path = file.inner.dir_entry.path.clone();
+
+        if let Some(locator) = file.inner.locator().cloned() {
+            if let Err(e) = file.drive().get_meta(&locator).await {
+                report.problems.push(format!("{}: object {}: {}", path, locator, e));
+                if repair {
+                    match file.resend_meta().await {
+                        Ok(()) => report.repaired.push(format!("{}: object sent again", path)),
+                        Err(e) => eprintln!("Failed to send the object of {} again: {}", path, e)
+                    }
+                }
+            }
+        }
+        if file.inner.metadata().is_dir {
+            continue
+        }
+
+        // `None` once a chunk can't be read, the checksum of the file can't be computed
+        let mut hasher = Some(blake3::Hasher::new());
+        let mut offset = 0;
+        for (index, chunk) in file.chunks().await?.iter().enumerate() {
+            let mut intact = None;
+            let mut corrupted = Vec::new();
+            let mut lost = Vec::new();
+            for copy in replicas(&chunk.locator) {
+                match file.download_copy(chunk, copy).await {
+                    Ok(content) => intact = intact.or(Some(content)),
+                    Err(e) => {
+                        report.problems.push(format!("{}: chunk {} copy {}: {}", path, index, copy, e));
+                        match e {
+                            Error::NotFound => lost.push(copy),
+                            _ => corrupted.push(copy)
+                        }
+                    }
+                }
+            }
+            match &intact {
+                Some(content) => if let Some(hasher) = &mut hasher {
+                    hasher.update(content);
+                },
+                None => hasher = None
+            }
+            if repair && !(corrupted.is_empty() && lost.is_empty()) {
+                let source = match intact {
+                    Some(_) => "an intact copy",
+                    None => "the cache"
+                };
+                match file.repair_copies(index, offset, chunk, &corrupted, &lost, intact).await {
+                    Ok(true) => {
+                        for copy in corrupted.iter().chain(&lost) {
+                            report.repaired.push(format!("{}: chunk {} copy {} sent again from {}", path, index, copy, source));
+                        }
+                    },
+                    Ok(false) => {},
+                    Err(e) => eprintln!("Failed to send chunk {} of {} again: {}", index, path, e)
+                }
+            }
+            offset += chunk.size;
+        }
+        if let (Some(hasher), Some(checksum)) = (hasher, &file.inner.dir_entry.checksum) {
+            if hasher.finalize().to_hex().as_str() != checksum {
+                report.problems.push(format!("{}: the content does not match its checksum", path));
+            }
+        }
+    }
+    if repair {
+        // Send the objects of the entries whose chunks were sent again, and delete the objects replaced by the repairs
+        fs.send_outdated().await?;
+        fs.purge().await?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dav::{Options, BLOB_NAME};
+    use crate::drives::Drive;
+    use crate::drives::discord::{mock, DiscordClient};
+    use crate::tests::{content, Server};
+
+    /// The member of the pool storing a copy and the locator of the copy in it
+    fn member(server: &Server, copy: &str) -> (DiscordClient, String) {
+        // The locators of the first channel have no key
+        let (channel, locator) = copy.split_once(':').unwrap_or(("first", copy));
+        (DiscordClient::new("token".to_string(), channel.to_string(), server.url.clone()), locator.to_string())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn every_copy() {
+        let url = mock::start().await.unwrap();
+        let drive = DiscordClient::pool(&["token".to_string()], &["first".to_string(), "second".to_string()], url.clone(), 2).unwrap();
+        let server = Server::with_drive(url, drive, Options::default()).await;
+        let (a, b) = (content(1, 10_000), content(2, 10_000));
+        server.write("/a", &a).await;
+        server.write("/b", &b).await;
+        let corrupted_chunk = server.objects("/a").await[1].clone();
+        let lost_chunk = server.objects("/b").await[1].clone();
+        let corrupted = replicas(&corrupted_chunk).next().unwrap().to_string();
+        let mut copies = replicas(&lost_chunk);
+        let kept = copies.next().unwrap().to_string();
+        let lost = copies.next().unwrap().to_string();
+        let (member_a, locator) = member(&server, &corrupted);
+        let (meta, _) = member_a.get(&locator).await.unwrap();
+        member_a.update(&locator, BLOB_NAME, &meta, Some(vec![0; 1000])).await.unwrap();
+        let (member_b, locator) = member(&server, &lost);
+        member_b.delete(&locator).await.unwrap();
+        // The files are still readable from the other copies
+        server.clear_cache();
+
+        let report = scrub(&server.fs, false).await.unwrap();
+        assert_eq!(report.problems.len(), 2);
+        assert!(report.problems.iter().any(|p| p.starts_with(&format!("/a: chunk 0 copy {}: ", corrupted))));
+        assert!(report.problems.iter().any(|p| p.starts_with(&format!("/b: chunk 0 copy {}: ", lost))));
+        assert!(report.repaired.is_empty());
+
+        let report = scrub(&server.fs, true).await.unwrap();
+        assert_eq!(report.repaired, vec![
+            format!("/a: chunk 0 copy {} sent again from an intact copy", corrupted),
+            format!("/b: chunk 0 copy {} sent again from an intact copy", lost)
+        ]);
+        // Only the lost copy is replaced
+        assert_eq!(server.objects("/a").await[1], corrupted_chunk);
+        let repaired = server.objects("/b").await[1].clone();
+        assert_ne!(repaired, lost_chunk);
+        assert!(replicas(&repaired).any(|c| c == kept));
+        assert!(!replicas(&repaired).any(|c| c == lost));
+
+        let report = scrub(&server.fs, false).await.unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        assert!(server.read("/a").await == a);
+        assert!(server.read("/b").await == b);
+    }
+}
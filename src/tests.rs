@@ -0,0 +1,312 @@
+//! End to end tests of the WebDAV layer, against the fake Discord API of `drives::discord::mock`
+
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use futures::StreamExt;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::{DavFileSystem, FsError, OpenOptions, ReadDirMeta};
+use crate::cache::Cache;
+use crate::dav::{DriveFs, Options, BLOB_NAME};
+use crate::db::DB;
+use crate::drives::{replicas, Drive, REPLICA_SEPARATOR};
+use crate::drives::discord::{mock, DiscordClient};
+use crate::gc;
+use crate::replication;
+use crate::types::{EntryMeta, Metadata};
+
+/// Number of the next cache directory, each test has its own
+static NEXT_CACHE: AtomicUsize = AtomicUsize::new(0);
+
+pub fn path(path: &str) -> DavPath {
+    DavPath::new(path).unwrap()
+}
+
+/// Content that compresses badly, the same for the same seed
+pub fn content(mut seed: u64, len: usize) -> Vec<u8> {
+    (0..len).map(|_| {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (seed >> 33) as u8
+    }).collect()
+}
+
+/// A server with an empty tree
+pub struct Server {
+    /// Base url of the fake API
+    pub url: String,
+    pub db: Arc<DB>,
+    pub drive: Arc<dyn Drive>,
+    pub fs: DriveFs,
+    pub cache: PathBuf
+}
+impl Server {
+    /// Start a fake API and a server storing its objects in one channel of it
+    pub async fn start(options: Options) -> Self {
+        let url = mock::start().await.unwrap();
+        let drive = Arc::new(DiscordClient::new("token".to_string(), "channel".to_string(), url.clone()));
+        Self::with_drive(url, drive, options).await
+    }
+    pub async fn with_drive(url: String, drive: Arc<dyn Drive>, options: Options) -> Self {
+        let db = Arc::new(DB::new(None).await);
+        db.insert_dir_entry(None, "/".to_string(), Metadata { len: 0, modified: None, is_dir: true }).await.unwrap();
+        let cache = std::env::temp_dir().join(format!("multi-drive-test-{}-{}", std::process::id(), NEXT_CACHE.fetch_add(1, Ordering::Relaxed)));
+        std::fs::create_dir_all(&cache).unwrap();
+        let fs = DriveFs::new(db.clone(), drive.clone(), Cache::new(cache.clone(), 1 << 30, db.clone()), options);
+        Self { url, db, drive, fs, cache }
+    }
+    /// The same server after its drive was replaced
+    pub fn with_new_drive(self, drive: Arc<dyn Drive>, options: Options) -> Self {
+        let fs = DriveFs::new(self.db.clone(), drive.clone(), Cache::new(self.cache.clone(), 1 << 30, self.db.clone()), options);
+        Self { drive, fs, ..self }
+    }
+    /// Remove the cached files, so that the next reads download them
+    pub fn clear_cache(&self) {
+        for entry in std::fs::read_dir(&self.cache).unwrap() {
+            std::fs::remove_file(entry.unwrap().path()).unwrap();
+        }
+    }
+    pub async fn write(&self, to: &str, data: &[u8]) {
+        let mut file = self.fs.open(&path(to), OpenOptions { write: true, create: true, truncate: true, ..Default::default() }).await.unwrap();
+        file.write_bytes(bytes::Bytes::copy_from_slice(data)).await.unwrap();
+        file.flush().await.unwrap();
+    }
+    /// Read `len` bytes of a file from `offset`, fewer at its end
+    pub async fn read_range(&self, from: &str, offset: u64, len: usize) -> Vec<u8> {
+        let mut file = self.fs.open(&path(from), OpenOptions { read: true, ..Default::default() }).await.unwrap();
+        file.seek(SeekFrom::Start(offset)).await.unwrap();
+        let mut content = Vec::new();
+        while content.len() < len {
+            let read = file.read_bytes(len - content.len()).await.unwrap();
+            if read.is_empty() {
+                break
+            }
+            content.extend_from_slice(&read);
+        }
+        content
+    }
+    pub async fn read(&self, from: &str) -> Vec<u8> {
+        let len = self.fs.metadata(&path(from)).await.unwrap().len();
+        self.read_range(from, 0, len as usize).await
+    }
+    /// Names of the entries of a directory, sorted
+    pub async fn names(&self, dir: &str) -> Vec<String> {
+        let mut names: Vec<String> = self.fs.read_dir(&path(dir), ReadDirMeta::None).await.unwrap()
+            .map(|e| String::from_utf8(e.name()).unwrap())
+            .collect().await;
+        names.sort();
+        names
+    }
+    /// Locator of the object of an entry and of its chunks
+    pub async fn objects(&self, of: &str) -> Vec<String> {
+        let entry = self.db.get_dir_entry_by_path(of.to_string()).await.unwrap().unwrap();
+        let chunks = self.db.get_chunks_by_entry_id(entry.id).await.unwrap();
+        entry.locator.into_iter().chain(chunks.into_iter().map(|c| c.locator)).collect()
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn put_and_read() {
+    let server = Server::start(Options::default()).await;
+    let data = content(1, 3_000_000);
+    server.write("/file", &data).await;
+    // Bigger than a chunk
+    assert!(server.objects("/file").await.len() > 2);
+    server.clear_cache();
+    assert!(server.read("/file").await == data);
+    assert_eq!(server.fs.metadata(&path("/file")).await.unwrap().len(), 3_000_000);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn range_read() {
+    let server = Server::start(Options::default()).await;
+    let data = content(2, 3_000_000);
+    server.write("/file", &data).await;
+    server.clear_cache();
+    assert!(server.read_range("/file", 2_000_000, 1000).await == data[2_000_000..2_001_000]);
+    assert!(server.read_range("/file", 2_999_900, 1000).await == data[2_999_900..]);
+    // Only the chunks covering the ranges were downloaded, not the whole file
+    let id = server.db.get_dir_entry_by_path("/file".to_string()).await.unwrap().unwrap().id;
+    assert!(!server.cache.join(id.to_string()).exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rename() {
+    let server = Server::start(Options::default()).await;
+    server.fs.create_dir(&path("/dir")).await.unwrap();
+    server.fs.create_dir(&path("/dir/sub")).await.unwrap();
+    server.write("/dir/sub/file", b"hello").await;
+    let objects = server.objects("/dir/sub/file").await;
+    server.fs.create_dir(&path("/dest")).await.unwrap();
+    server.fs.rename(&path("/dir"), &path("/dest/moved")).await.unwrap();
+    server.fs.send_outdated().await.unwrap();
+    // Into itself
+    assert!(server.fs.rename(&path("/dest"), &path("/dest/moved/in")).await.is_err());
+
+    assert!(server.fs.metadata(&path("/dir")).await.is_err());
+    assert_eq!(server.names("/dest/moved/sub").await, vec!["file"]);
+    // The content was not sent again
+    assert_eq!(server.objects("/dest/moved/sub/file").await, objects);
+    // The object holds the new path
+    let (meta, _) = server.drive.get_meta(&objects[0]).await.unwrap();
+    assert!(meta.contains("/dest/moved/sub/file"));
+    server.clear_cache();
+    assert_eq!(server.read("/dest/moved/sub/file").await, b"hello");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn remove() {
+    let server = Server::start(Options::default()).await;
+    server.fs.create_dir(&path("/dir")).await.unwrap();
+    server.write("/dir/file", &content(3, 1_000_000)).await;
+    let objects = server.objects("/dir/file").await;
+    assert!(server.fs.remove_dir(&path("/dir")).await.is_err());
+    server.fs.remove_file(&path("/dir/file")).await.unwrap();
+    server.fs.remove_dir(&path("/dir")).await.unwrap();
+    assert!(server.fs.metadata(&path("/dir")).await.is_err());
+
+    server.fs.purge().await.unwrap();
+    assert!(server.db.get_pending_deletions(10).await.unwrap().is_empty());
+    for locator in objects {
+        assert!(server.drive.get(&locator).await.is_err());
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn copy() {
+    let server = Server::start(Options::default()).await;
+    let data = content(4, 2_000_000);
+    server.fs.create_dir(&path("/dir")).await.unwrap();
+    server.write("/dir/file", &data).await;
+    server.fs.copy(&path("/dir/file"), &path("/copy")).await.unwrap();
+    server.fs.send_outdated().await.unwrap();
+    // The chunks are shared
+    assert_eq!(server.objects("/copy").await[1..], server.objects("/dir/file").await[1..]);
+
+    // Copy on write
+    let mut file = server.fs.open(&path("/copy"), OpenOptions { write: true, ..Default::default() }).await.unwrap();
+    file.write_bytes(bytes::Bytes::from_static(b"changed")).await.unwrap();
+    file.flush().await.unwrap();
+    drop(file);
+    server.clear_cache();
+    assert!(server.read("/dir/file").await == data);
+    let copy = server.read("/copy").await;
+    assert_eq!(&copy[..7], b"changed");
+    assert!(copy[7..] == data[7..]);
+
+    // The copy of a tree outlives the original
+    server.fs.copy(&path("/dir"), &path("/dir2")).await.unwrap();
+    server.fs.send_outdated().await.unwrap();
+    server.fs.remove_file(&path("/dir/file")).await.unwrap();
+    server.fs.purge().await.unwrap();
+    server.clear_cache();
+    assert!(server.read("/dir2/file").await == data);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn removed_by_another_server() {
+    let server = Server::start(Options::default()).await;
+    server.fs.create_dir(&path("/dir")).await.unwrap();
+    server.write("/dir/file", b"hello").await;
+    let objects = server.objects("/dir/file").await;
+    // The other server deletes the object of the entry, its chunks are then released there
+    server.drive.delete(&objects[0]).await.unwrap();
+
+    let opened = server.fs.open(&path("/dir/file"), OpenOptions { read: true, ..Default::default() }).await;
+    assert!(matches!(opened, Err(FsError::NotFound)));
+    assert!(server.fs.metadata(&path("/dir/file")).await.is_err());
+    assert!(server.db.get_pending_deletions(10).await.unwrap().is_empty());
+    assert!(server.names("/dir").await.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn replicate() {
+    let url = mock::start().await.unwrap();
+    let drive = DiscordClient::pool(&["token".to_string()], &["first".to_string(), "second".to_string()], url.clone(), 2).unwrap();
+    let server = Server::with_drive(url, drive, Options::default()).await;
+    let data = content(5, 100_000);
+    server.write("/file", &data).await;
+    // One of the copies of the chunk is lost
+    let lost = replicas(&server.objects("/file").await[1]).next().unwrap().to_string();
+    server.drive.delete(&lost).await.unwrap();
+
+    let report = replication::replicate(&server.fs).await.unwrap();
+    assert_eq!(report.replicated.len(), 1);
+    let objects = server.objects("/file").await;
+    assert!(replicas(&objects[1]).all(|r| r != lost));
+    // The object of the entry lists the new copies
+    let (meta, _) = server.drive.get_meta(&objects[0]).await.unwrap();
+    let meta: EntryMeta = serde_json::from_str(&meta).unwrap();
+    assert_eq!(meta.chunks.unwrap()[0].locator, objects[1]);
+    server.clear_cache();
+    assert!(server.read("/file").await == data);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn replicate_corrupted_copy() {
+    let url = mock::start().await.unwrap();
+    let drive = DiscordClient::pool(&["token".to_string()], &["first".to_string(), "second".to_string()], url.clone(), 2).unwrap();
+    let server = Server::with_drive(url, drive, Options::default()).await;
+    let data = content(9, 100_000);
+    server.write("/file", &data).await;
+    // The first copy of the chunk is overwritten, the locators of the first channel have no key
+    let chunk = server.objects("/file").await[1].clone();
+    let (corrupted, intact) = chunk.split_once(REPLICA_SEPARATOR).unwrap();
+    let (channel, locator) = corrupted.split_once(':').unwrap_or(("first", corrupted));
+    let member = DiscordClient::new("token".to_string(), channel.to_string(), server.url.clone());
+    let (meta, _) = member.get(locator).await.unwrap();
+    member.update(locator, BLOB_NAME, &meta, Some(vec![0; 1000])).await.unwrap();
+
+    let report = replication::replicate(&server.fs).await.unwrap();
+    assert_eq!(report.replicated.len(), 1, "{:?}", report.failed);
+    let objects = server.objects("/file").await;
+    assert_eq!(replicas(&objects[1]).next().unwrap(), intact);
+    assert!(replicas(&objects[1]).all(|r| r != corrupted));
+    // The new copy is made from the intact one
+    server.drive.delete(intact).await.unwrap();
+    server.clear_cache();
+    assert!(server.read("/file").await == data);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn upgrade_to_a_pool() {
+    let server = Server::start(Options::default()).await;
+    let old = content(6, 100_000);
+    server.write("/old", &old).await;
+    let pool = DiscordClient::pool(&["token".to_string()], &["channel".to_string(), "other".to_string()], server.url.clone(), 1).unwrap();
+    let server = server.with_new_drive(pool, Options::default());
+    // Spread over both channels
+    let new = content(7, 100_000);
+    for i in 0..4 {
+        server.write(&format!("/new{}", i), &new).await;
+    }
+    assert!(server.objects("/new0").await.iter().chain(server.objects("/new1").await.iter()).any(|l| l.starts_with("other:")));
+
+    let report = gc::collect(&server.db, server.drive.as_ref(), Duration::ZERO, false).await.unwrap();
+    assert!(report.garbage.is_empty(), "{:?}", report.garbage);
+    server.clear_cache();
+    assert!(server.read("/old").await == old);
+    for i in 0..4 {
+        assert!(server.read(&format!("/new{}", i)).await == new);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn corrupted_copy() {
+    let url = mock::start().await.unwrap();
+    let drive = DiscordClient::pool(&["token".to_string()], &["first".to_string(), "second".to_string()], url.clone(), 2).unwrap();
+    let server = Server::with_drive(url, drive, Options::default()).await;
+    let data = content(8, 100_000);
+    server.write("/file", &data).await;
+    // The first copy of the chunk is overwritten, the locators of the first channel have no key
+    let chunk = server.objects("/file").await[1].clone();
+    let copy = replicas(&chunk).next().unwrap();
+    let (channel, locator) = copy.split_once(':').unwrap_or(("first", copy));
+    let member = DiscordClient::new("token".to_string(), channel.to_string(), server.url.clone());
+    let (meta, _) = member.get(locator).await.unwrap();
+    member.update(locator, BLOB_NAME, &meta, Some(vec![0; 1000])).await.unwrap();
+
+    server.clear_cache();
+    assert!(server.read("/file").await == data);
+}
@@ -32,6 +32,154 @@ impl DavMetaData for Metadata {
     }
 }
 
+/// Content of the object of an entry
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct EntryMeta {
+    #[serde(flatten)]
+    pub metadata: Metadata,
+    /// Path of the entry, to rebuild the index from the drive. `None` in the objects sent before it was stored
+    #[serde(default)]
+    pub path: Option<String>,
+    /// `None` in the objects sent before the chunks were listed
+    #[serde(default)]
+    pub chunks: Option<Vec<Chunk>>,
+    /// BLAKE3 hash of the content of the file, `None` for the directories and in the objects sent before it was computed
+    #[serde(default)]
+    pub checksum: Option<String>,
+    /// When the entry was moved to the trash, `None` while it is in the tree
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trashed: Option<DateTime<Utc>>
+}
+
+/// An entry read from the drive to rebuild the index
+#[derive(Debug, Clone)]
+pub struct IndexedEntry {
+    pub path: String,
+    pub metadata: Metadata,
+    /// `None` for the directories that were not stored in the drive but contain entries that were
+    pub locator: Option<String>,
+    pub version: Option<String>,
+    pub chunks: Vec<Chunk>,
+    pub checksum: Option<String>,
+    /// When the entry was moved to the trash, it is put back there rather than in the tree
+    pub trashed: Option<DateTime<Utc>>
+}
+
+/// A part of the content of a file stored in its own object
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Chunk {
+    pub locator: String,
+    /// Size of the content, once decompressed
+    pub size: u64,
+    /// The blob is compressed with zstd
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub compressed: bool,
+    /// Hash of the content, identical chunks share their object. `None` in the chunks sent before deduplication
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>
+}
+impl Chunk {
+    /// Decompress the blob of the chunk if needed, `None` if the content doesn't match the hash of the chunk
+    pub fn content(&self, blob: Vec<u8>) -> crate::error::Result<Option<Vec<u8>>> {
+        let content = match self.compressed {
+            true => crate::compression::decompress(&blob, self.size as usize)?,
+            false => blob
+        };
+        match &self.hash {
+            Some(hash) if blake3::hash(&content).to_hex().as_str() != hash => Ok(None),
+            _ => Ok(Some(content))
+        }
+    }
+    /// Whether a copy of the chunk is intact, the check given to `Drive::replicate`
+    pub fn is_intact(&self, _meta: &str, blob: Option<&[u8]>) -> bool {
+        blob.is_some_and(|blob| matches!(self.content(blob.to_vec()), Ok(Some(_))))
+    }
+}
+
+/// A saved content of a file, kept when versioning
+#[derive(Debug, Clone)]
+pub struct FileVersion {
+    pub id: usize,
+    /// `None` once the entry is removed
+    pub entry_id: Option<usize>,
+    /// Path of the entry, followed when it is moved
+    pub path: String,
+    pub metadata: Metadata,
+    pub checksum: Option<String>
+}
+
+/// A frozen copy of the tree
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub id: usize,
+    pub name: String,
+    pub created: DateTime<Utc>
+}
+
+/// An entry removed while the trash is enabled
+#[derive(Debug, Clone)]
+pub struct TrashEntry {
+    pub id: usize,
+    /// Id of the entry before it was removed
+    pub entry_id: usize,
+    /// Path of the entry before it was removed
+    pub path: String,
+    pub metadata: Metadata,
+    pub checksum: Option<String>,
+    pub deleted: DateTime<Utc>
+}
+
+/// A file of the local cache
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    /// Name of the file in the cache directory
+    pub name: String,
+    /// Id of the entry whose content is cached
+    pub entry_id: usize,
+    pub size: u64,
+    /// Milliseconds since the Unix epoch
+    pub last_access: i64,
+    /// Written but not sent to the drive yet
+    pub dirty: bool
+}
+
+/// Metadata of the pinned object of a backup of the database, its content is in chunks
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BackupMeta {
+    /// When the backup was made
+    pub backup: DateTime<Utc>,
+    pub chunks: Vec<Chunk>
+}
+
+/// Metadata of the pinned object holding the salts of the encryption keys by key id, in base64.
+/// The keys without salt were used before the salts were stored, theirs is derived from their id
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct SaltsMeta {
+    pub salts: std::collections::HashMap<u32, Option<String>>
+}
+
+/// Metadata of the objects holding the chunks of a backup
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BackupChunkMeta {
+    pub backup: DateTime<Utc>,
+    pub index: usize
+}
+
+/// Metadata of the objects holding a chunk of content, shared by all the files containing it
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BlobMeta {
+    /// BLAKE3 hash of the content
+    pub hash: String
+}
+
+/// Metadata of the objects holding the chunks of a file, sent before deduplication
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ChunkMeta {
+    /// Id of the file
+    pub id: usize,
+    pub index: usize
+}
+
 #[derive(Debug)]
 pub struct File {
     pub dir_entry: DirEntry,
@@ -50,14 +198,23 @@ impl File {
     pub fn id(&self) -> &usize {
         &self.dir_entry.id
     }
+    pub fn locator(&self) -> Option<&String> {
+        self.dir_entry.locator.as_ref()
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DirEntry {
     pub path: String,
     pub id: usize,
     pub parent_id: Option<usize>,
-    pub metadata: Metadata
+    pub metadata: Metadata,
+    /// Where the entry is stored in the drive, `None` if it was never sent
+    pub locator: Option<String>,
+    /// Version of the object the entry was last read from or sent to
+    pub version: Option<String>,
+    /// BLAKE3 hash of the content of the file, `None` if it was not computed yet
+    pub checksum: Option<String>
 }
 
 impl DavDirEntry for DirEntry {